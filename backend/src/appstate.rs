@@ -1,105 +1,220 @@
 use actix::Addr;
-use surrealdb::Surreal;
 use surrealdb::sql::Uuid;
-use surrealdb::engine::remote::ws::Client;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::sync::{Arc, Mutex};
+use std::time::Instant;
 use validator::Validate;
-use crate::structs::{Room, UserData, LoginForm};
-use crate::message_structs::*;
-use crate::websocket::{WsActor, WsMessage};
+use crate::structs::{AccountState, Room, UserData, UserKind, LoginForm};
+use black_signal_protocol::*;
+use crate::audit::AuditLog;
+use crate::cluster::{Cluster, ClusterEvent, PresenceLock};
+use crate::kv::KvStore;
+use crate::one_time_token::{self, TokenPurpose};
+use crate::login_guard::LoginGuard;
+use crate::mailer::Mailer;
+use crate::metrics::Metrics;
+use crate::rate_limit::RateLimiter;
+use crate::password_policy::PasswordPolicy;
+use crate::sessions::SessionTracker;
+use crate::shutdown::{Drain, ShutdownConfig};
+use crate::storage::Storage;
+use crate::tokens::TokenService;
+use crate::two_factor::{TwoFactor, TwoFactorConfig};
+use crate::websocket::{WebSocketConfig, WsActor};
 
-pub type WsActorMap = HashMap<Uuid, Addr<WsActor>>;
+// A live socket and the login session it was opened from
+pub struct ConnectedActor {
+    pub addr: Addr<WsActor>,
+    pub session_id: Option<Uuid>,
+    pub presence: PresenceLock,
+}
+
+pub type WsActorMap = HashMap<Uuid, ConnectedActor>;
+// The sockets held by this instance, by user
+pub type ActorRegistry = Arc<Mutex<HashMap<Uuid, WsActorMap>>>;
 pub struct AppState {
-    pub db: Arc<Surreal<Client>>,
+    pub storage: Arc<dyn Storage>,
     pub channels: Arc<Mutex<HashMap<Uuid, Room>>>,
-    pub actor_registry: Arc<Mutex<HashMap<Uuid, WsActorMap>>>,
+    pub actor_registry: ActorRegistry,
     pub main_room_id: Uuid,
+    pub rate_limiter: RateLimiter,
+    pub login_guard: LoginGuard,
+    pub password_policy: PasswordPolicy,
+    pub kv: KvStore,
+    pub mailer: Arc<dyn Mailer>,
+    // Where the frontend is served, used for links in emails
+    pub public_url: String,
+    pub two_factor: TwoFactorConfig,
+    pub tokens: TokenService,
+    pub sessions: SessionTracker,
+    // Logins given admin rights by the config, on top of stored ones
+    pub admin_logins: HashSet<String>,
+    pub cluster: Cluster,
+    pub websocket: WebSocketConfig,
+    pub shutdown: ShutdownConfig,
+    pub drain: Drain,
+    pub metrics: Metrics,
+    pub audit: AuditLog,
 }
 
 impl AppState {
+    // Humans have to turn on two-factor authentication when the config asks
+    // for it, bots authenticate with a scoped API token instead
+    pub fn lacks_required_two_factor(&self, user: &UserData) -> bool {
+        self.two_factor.required && user.kind == UserKind::Human && !user.two_factor_enabled()
+    }
+
+    pub fn is_admin(&self, user: &UserData) -> bool {
+        user.is_admin || (user.kind == UserKind::Human && self.admin_logins.contains(&user.login))
+    }
+
     pub async fn broadcast_message(&self, message: String, room_id: &Uuid, user_id: &Uuid) {
-        let query = "SELECT * FROM rooms WHERE room_id = $room_id;";
-        let mut response = match self.db.query(query)
-            .bind(("room_id", room_id))
-            .await {
-                Ok(retrieved) => retrieved,
-                Err(e) => {log::error!("Failed to get users in requested room: fn broadcast_message, error: {:?}", e);
-                return}
-            };
-        let rooms: Vec<Room> = match response.take(0) {
-            Ok(user) => user,
-            Err(e) => {log::error!("Failed to get user data: fn broadcast_message, error: {:?}", e);
+        let started = Instant::now();
+        let room = match self.storage.get_room(room_id).await {
+            Ok(room) => room,
+            Err(e) => {log::error!("Failed to get users in requested room: fn broadcast_message, error: {:?}", e);
             return}
         };
-        let actor_registry = self.actor_registry.lock().unwrap();
-
-        for room in rooms {
-            if room.users.get(user_id).is_some() {
-                for user in &room.users {
-                    if let Some(client) = actor_registry.get(user) {
-                        for instance in client.values() {
-                            instance.do_send(WsMessage(message.clone()));
-                        }
-                    }
-                }
-            } else {
-                return;
-            }
+
+        if let Some(room) = room.filter(|room| room.users.contains(user_id)) {
+            let user_ids = room.users.into_iter().collect();
+            self.cluster.publish_to_room(room_id, ClusterEvent::Frame { user_ids, message }).await;
+            self.metrics.message_broadcast(started);
         }
     }
 
+    // Stores a chat message and delivers it to everyone in its room
+    pub async fn post_message(&self, message: &BasicMessage) -> Result<(), ErrorCode> {
+        if let Err(e) = self.storage.create_message(message).await {
+            log::error!("Failed to create message in db: fn post_message, error: {:?}", e);
+            return Err(ErrorCode::DatabaseError);
+        }
+        let room_id = Uuid::from(message.room_id);
+        self.metrics.message_sent();
+        let serialized_msg = match serde_json::to_string(&UserMessage::Basic(message.clone())) {
+            Ok(serialized) => serialized,
+            Err(e) => {log::error!("Failed to serialize message: fn post_message, error: {:?}", e);
+            return Err(ErrorCode::Internal)}
+        };
+        self.broadcast_message(serialized_msg, &room_id, &message.sender_id.into()).await;
+        Ok(())
+    }
+
     pub async fn catch_up(&self, room_id: &Uuid) -> Option<Vec<UserMessage>> {
-        let query = "SELECT * FROM messages WHERE room_id = $room_id ORDER BY timestamp ASC;";
-        let mut response = match self.db.query(query).bind(("room_id", room_id))
-            .await {
-                Ok(queried) => queried,
-                Err(e) => {log::error!("Failed to query messages: fn catch_up, error: {:?}", e);
-                return None}
-            };
-        let basic_messages: Vec<BasicMessage> = match response.take(0)
-            {
-                Ok(retrieved) => retrieved,
-                Err(e) => {log::error!("Failed to get messages from query: fn catch_up, error: {:?}", e);
-                return None}
-            };
+        let basic_messages = match self.storage.room_messages(room_id).await {
+            Ok(retrieved) => retrieved,
+            Err(e) => {log::error!("Failed to query messages: fn catch_up, error: {:?}", e);
+            return None}
+        };
         let user_messages: Vec<UserMessage> = basic_messages.into_iter().map(UserMessage::Basic).collect();
         Some(user_messages)
     }
 
-    pub async fn authenticate_user(&self, login_data: &LoginForm) -> Option<Uuid> {
-        let query = "SELECT * FROM users WHERE login_username = $login_username;";
-        let mut response = match self.db
-            .query(query)
-            .bind(("login_username", login_data.username.clone()))
-            .await {
-                Ok(queried) => queried,
-                Err(e) => {log::error!("Failed to query for user: fn authenticate_user, error: {:?}", e);
-                return None}
-            };
-        let result: Option<UserData> = match response.take(0)
-            {
-                Ok(user) => user,
-                Err(e) => {log::error!("Failed to get user data: fn authenticate_user, error: {:?}", e);
-                return None}
-            };
+    // On failure, the account the login belongs to if there is one, so the
+    // attempt can be audited without looking it up again
+    pub async fn authenticate_user(&self, login_data: &LoginForm) -> Result<UserData, Option<Uuid>> {
+        let result = self.get_user_by_login(&login_data.username).await;
 
         match result {
-            Some(user_data) => {
-                if bcrypt::verify(login_data.password.clone(), &user_data.hashed_password).unwrap_or(false) {
-                    Some(user_data.user_id)
-                } else {
-                    None
-                }
+            Some(user_data) if bcrypt::verify(login_data.password.clone(), &user_data.hashed_password).unwrap_or(false) => {
+                Ok(user_data)
             },
-            None => {
-                None
+            user_data => {
+                Err(user_data.map(|user_data| user_data.user_id))
             }
         }
     }
 
+    pub async fn get_user(&self, user_id: &Uuid) -> Option<UserData> {
+        match self.storage.get_user(user_id).await {
+            Ok(user) => user,
+            Err(e) => {log::error!("Failed to query for user: fn get_user, error: {:?}", e);
+            None}
+        }
+    }
+
+    pub async fn get_user_by_login(&self, login: &str) -> Option<UserData> {
+        match self.storage.get_user_by_login(login).await {
+            Ok(user) => user,
+            Err(e) => {log::error!("Failed to query for user: fn get_user_by_login, error: {:?}", e);
+            None}
+        }
+    }
+
+    // Stores a new password hash and ends every other session of the user,
+    // returning the epoch the surviving session must carry
+    pub async fn update_password(&self, user: &UserData, hashed_password: String, keep_session: Option<Uuid>) -> Option<u64> {
+        let session_epoch = user.session_epoch + 1;
+        if let Err(e) = self.storage.set_password(&user.user_id, &hashed_password, session_epoch).await {
+            log::error!("Failed to update password: fn update_password, error: {:?}", e);
+            return None
+        }
+        // Reset links mailed before the change must not undo it
+        if let Err(e) = one_time_token::revoke_all(&self.kv, TokenPurpose::PasswordReset, &user.user_id).await {
+            log::error!("Failed to revoke password reset tokens: fn update_password, error: {:?}", e);
+        }
+        self.sessions.revoke_all(&user.user_id, keep_session).await;
+        self.disconnect_user(&user.user_id, keep_session, "Password changed".to_string()).await;
+        Some(session_epoch)
+    }
+
+    pub async fn activate_user(&self, user_id: &Uuid) -> bool {
+        if let Err(e) = self.storage.set_account_state(user_id, AccountState::Active).await {
+            log::error!("Failed to activate user: fn activate_user, error: {:?}", e);
+            return false;
+        }
+        self.cluster.publish_to_user(user_id, ClusterEvent::Activated { user_id: *user_id }).await;
+        true
+    }
+
+    pub async fn set_two_factor(&self, user_id: &Uuid, two_factor: Option<TwoFactor>) -> bool {
+        if let Err(e) = self.storage.set_two_factor(user_id, two_factor).await {
+            log::error!("Failed to update two factor settings: fn set_two_factor, error: {:?}", e);
+            return false
+        }
+        true
+    }
+
+    // Checks an authenticator or recovery code and spends it, storage spends
+    // them atomically so each one only works once even with concurrent logins
+    pub async fn verify_second_factor(&self, user: &UserData, code: Option<&str>, recovery_code: Option<&str>) -> bool {
+        let two_factor = match &user.two_factor {
+            Some(two_factor) if two_factor.enabled => two_factor,
+            _ => return false,
+        };
+        let spent = if let Some(code) = code {
+            let now = chrono::Utc::now().timestamp().max(0) as u64;
+            match two_factor.verify_code(&self.two_factor.issuer, &user.login, code, now) {
+                Some(step) => self.storage.spend_totp_step(&user.user_id, step).await,
+                None => return false,
+            }
+        } else if let Some(recovery_code) = recovery_code {
+            match two_factor.find_recovery_code(recovery_code) {
+                Some(hashed) => self.storage.spend_recovery_code(&user.user_id, &hashed).await,
+                None => return false,
+            }
+        } else {
+            return false;
+        };
+        match spent {
+            Ok(spent) => spent,
+            Err(e) => {log::error!("Failed to spend second factor: fn verify_second_factor, error: {:?}", e);
+            false}
+        }
+    }
+
+    pub async fn disconnect_user(&self, user_id: &Uuid, keep_session: Option<Uuid>, reason: String) {
+        let event = ClusterEvent::Disconnect { user_id: *user_id, keep_session, only_session: None, reason };
+        self.cluster.publish_to_user(user_id, event).await;
+    }
+
+    pub async fn disconnect_session(&self, user_id: &Uuid, session_id: Uuid, reason: String) {
+        let event = ClusterEvent::Disconnect { user_id: *user_id, keep_session: None, only_session: Some(session_id), reason };
+        self.cluster.publish_to_user(user_id, event).await;
+    }
+
     pub async fn valid_user_credentials(&self, signup_data: &LoginForm) -> bool {
-        let result: Option<UserData> = match self.db.select(("logins", &signup_data.username)).await {
+        let result = match self.storage.get_user_by_login(&signup_data.username).await {
             Ok(retrieved) => retrieved,
             Err(e) => {log::error!("Failed to get user : fn valid_user_credentials, error: {:?}", e);
             return false}
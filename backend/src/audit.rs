@@ -0,0 +1,284 @@
+use crate::appstate::AppState;
+use crate::auth::authenticate;
+use crate::storage::{connection_error, unavailable, Storage};
+use actix_session::Session;
+use actix_web::{get, web, HttpRequest, HttpResponse, Responder};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::{BTreeMap, VecDeque};
+use std::env;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::IpAddr;
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use surrealdb::sql::Uuid;
+
+const DEFAULT_QUERY_LIMIT: usize = 100;
+const MAX_QUERY_LIMIT: usize = 1000;
+// Events kept while storage is unavailable, the oldest are dropped past this
+const MAX_PENDING_EVENTS: usize = 10_000;
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct AuditConfig {
+    // Every event is also appended to this file as a line of JSON
+    pub file: Option<String>,
+}
+
+impl AuditConfig {
+    // Overrides settings with AUDIT_* environment variables
+    pub fn apply_env(&mut self) {
+        if let Ok(file) = env::var("AUDIT_LOG_FILE") {
+            self.file = Some(file).filter(|file| !file.is_empty());
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuditKind {
+    Login,
+    LoginFailed,
+    Signup,
+    UsernameChanged,
+    MessageDeleted,
+    RoomCreated,
+    RoomMemberAdded,
+    RoomMemberRemoved,
+    AdminAction,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct AuditEvent {
+    pub event_id: Uuid,
+    pub kind: AuditKind,
+    // Unix seconds
+    pub timestamp: u64,
+    // Who acted, unknown for failed logins
+    pub actor_id: Option<Uuid>,
+    // The account acted on when it is not the actor's own, or the account a
+    // failed login was aimed at
+    pub target_id: Option<Uuid>,
+    pub room_id: Option<Uuid>,
+    pub ip: Option<String>,
+    // Depends on the kind, e.g. the login that was tried or the new username
+    #[serde(default)]
+    pub details: BTreeMap<String, String>,
+}
+
+impl AuditEvent {
+    pub fn new(kind: AuditKind) -> Self {
+        AuditEvent {
+            event_id: Uuid::new_v4(),
+            kind,
+            timestamp: Utc::now().timestamp().max(0) as u64,
+            actor_id: None,
+            target_id: None,
+            room_id: None,
+            ip: None,
+            details: BTreeMap::new(),
+        }
+    }
+
+    pub fn actor(mut self, actor_id: Uuid) -> Self {
+        self.actor_id = Some(actor_id);
+        self
+    }
+
+    pub fn target(mut self, target_id: Uuid) -> Self {
+        self.target_id = Some(target_id);
+        self
+    }
+
+    pub fn room(mut self, room_id: Uuid) -> Self {
+        self.room_id = Some(room_id);
+        self
+    }
+
+    pub fn ip(mut self, ip: Option<IpAddr>) -> Self {
+        self.ip = ip.map(|ip| ip.to_string());
+        self
+    }
+
+    pub fn detail(mut self, key: &str, value: impl Into<String>) -> Self {
+        self.details.insert(key.to_string(), value.into());
+        self
+    }
+}
+
+// Filters for reading the audit log back, newest events first
+#[derive(Clone, Debug, Default)]
+pub struct AuditQuery {
+    // Events the user did or had done to them
+    pub user_id: Option<Uuid>,
+    pub room_id: Option<Uuid>,
+    pub kind: Option<AuditKind>,
+    // Unix seconds, both inclusive
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+    pub limit: usize,
+}
+
+impl AuditQuery {
+    pub fn matches(&self, event: &AuditEvent) -> bool {
+        self.user_id.is_none_or(|user_id| event.actor_id == Some(user_id) || event.target_id == Some(user_id))
+            && self.room_id.is_none_or(|room_id| event.room_id == Some(room_id))
+            && self.kind.is_none_or(|kind| event.kind == kind)
+            && self.since.is_none_or(|since| event.timestamp >= since)
+            && self.until.is_none_or(|until| event.timestamp <= until)
+    }
+}
+
+// Writes audit events to storage and, when configured, to a JSON lines
+// file. A failed write is logged and never fails the action being audited.
+// Events that arrive while storage is unavailable are held back and stored
+// once it is reachable again.
+pub struct AuditLog {
+    storage: Arc<dyn Storage>,
+    file: Option<Sender<String>>,
+    pending: Mutex<VecDeque<AuditEvent>>,
+}
+
+impl AuditLog {
+    pub fn open(storage: Arc<dyn Storage>, config: &AuditConfig) -> anyhow::Result<Self> {
+        let file = match &config.file {
+            Some(path) => Some(spawn_writer(OpenOptions::new().create(true).append(true).open(path)?)?),
+            None => None,
+        };
+        Ok(AuditLog { storage, file, pending: Mutex::new(VecDeque::new()) })
+    }
+
+    pub async fn record(&self, event: AuditEvent) {
+        if let Some(file) = &self.file {
+            let sent = serde_json::to_string(&event)
+                .map_err(anyhow::Error::from)
+                .and_then(|line| file.send(line).map_err(|_| anyhow::anyhow!("audit file writer stopped")));
+            if let Err(e) = sent {
+                log::error!("Failed to append audit event to file: fn record, error: {:?}", e);
+            }
+        }
+        if !self.storage.is_available() {
+            self.hold_back(vec![event]);
+            return;
+        }
+        self.flush().await;
+        self.store(event).await;
+    }
+
+    pub async fn query(&self, query: &AuditQuery) -> anyhow::Result<Vec<AuditEvent>> {
+        self.flush().await;
+        self.storage.audit_events(query).await
+    }
+
+    // Stores the events held back during an outage, in the order they happened
+    async fn flush(&self) {
+        let pending = std::mem::take(&mut *self.pending.lock().unwrap());
+        let mut pending = pending.into_iter();
+        while let Some(event) = pending.next() {
+            if !self.store(event).await {
+                self.hold_back(pending.collect());
+                return;
+            }
+        }
+    }
+
+    // Whether the event was dealt with, false when it was held back
+    async fn store(&self, event: AuditEvent) -> bool {
+        match self.storage.record_audit_event(&event).await {
+            Ok(()) => true,
+            Err(e) if unavailable(&e) || connection_error(&e) => {
+                self.hold_back(vec![event]);
+                false
+            }
+            Err(e) => {
+                log::error!("Failed to store audit event: fn record, error: {:?}, event: {:?}", e, event);
+                true
+            }
+        }
+    }
+
+    fn hold_back(&self, events: Vec<AuditEvent>) {
+        let mut pending = self.pending.lock().unwrap();
+        pending.extend(events);
+        let excess = pending.len().saturating_sub(MAX_PENDING_EVENTS);
+        if excess > 0 {
+            log::error!("Failed to keep audit events while storage is unavailable: fn hold_back, dropped: {}", excess);
+            pending.drain(..excess);
+        }
+    }
+}
+
+// Appends lines on a thread of its own so handlers never wait on the disk
+fn spawn_writer(mut file: File) -> anyhow::Result<Sender<String>> {
+    let (sender, receiver) = channel::<String>();
+    thread::Builder::new().name("audit-file".to_string()).spawn(move || {
+        for line in receiver {
+            if let Err(e) = writeln!(file, "{}", line) {
+                log::error!("Failed to append audit event to file: fn spawn_writer, error: {:?}", e);
+            }
+        }
+    })?;
+    Ok(sender)
+}
+
+#[derive(Deserialize)]
+pub struct AuditFilter {
+    pub user_id: Option<String>,
+    pub room_id: Option<String>,
+    pub kind: Option<AuditKind>,
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+    pub limit: Option<usize>,
+}
+
+impl AuditFilter {
+    fn into_query(self) -> Result<AuditQuery, String> {
+        let parse = |name: &str, value: Option<String>| match value {
+            Some(value) => Uuid::try_from(value.as_str()).map(Some).map_err(|_| format!("{} is not a valid uuid", name)),
+            None => Ok(None),
+        };
+        Ok(AuditQuery {
+            user_id: parse("user_id", self.user_id)?,
+            room_id: parse("room_id", self.room_id)?,
+            kind: self.kind,
+            since: self.since,
+            until: self.until,
+            limit: self.limit.unwrap_or(DEFAULT_QUERY_LIMIT).min(MAX_QUERY_LIMIT),
+        })
+    }
+}
+
+// Audit events for admins, filtered by user, room, kind and time
+#[get("/admin/audit")]
+pub async fn audit_events(
+    req: HttpRequest,
+    filter: web::Query<AuditFilter>,
+    session: Session,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let user = match authenticate(&req, &session, &state).await {
+        Some(auth) => auth.user,
+        None => return HttpResponse::Unauthorized().json(json!({"error": "Not logged in"})),
+    };
+    if !state.is_admin(&user) {
+        return HttpResponse::Forbidden().json(json!({"error": "Admin access required"}));
+    }
+    let query = match filter.into_inner().into_query() {
+        Ok(query) => query,
+        Err(error) => return HttpResponse::BadRequest().json(json!({"error": error})),
+    };
+    let ip = req.peer_addr().map(|addr| addr.ip());
+    state
+        .audit
+        .record(AuditEvent::new(AuditKind::AdminAction).actor(user.user_id).ip(ip).detail("action", "view_audit_log"))
+        .await;
+    match state.audit.query(&query).await {
+        Ok(events) => HttpResponse::Ok().json(events),
+        Err(e) => {
+            log::error!("Failed to query audit events: fn audit_events, error: {:?}", e);
+            HttpResponse::InternalServerError().json(json!({"error": "Internal DB Error"}))
+        }
+    }
+}
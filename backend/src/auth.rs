@@ -0,0 +1,176 @@
+use crate::appstate::AppState;
+use crate::bots::{api_token_user, is_api_token, ApiScope};
+use crate::sessions::{ClientInfo, SessionKind};
+use crate::structs::UserData;
+use actix_session::{Session, SessionInsertError};
+use actix_web::http::header;
+use actix_web::HttpRequest;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use surrealdb::sql::Uuid;
+
+pub const USER_KEY: &str = "key";
+// Sessions whose epoch trails the user's were ended by a password change
+pub const EPOCH_KEY: &str = "epoch";
+pub const SESSION_ID_KEY: &str = "session_id";
+// Set once the password is accepted while the second factor is outstanding
+pub const PENDING_LOGIN_KEY: &str = "pending_login";
+const PENDING_LOGIN_TTL: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Serialize, Deserialize)]
+struct PendingLogin {
+    user_id: Uuid,
+    session_epoch: u64,
+    started_at: i64,
+}
+
+pub fn start_session(session: &Session, user: &UserData) -> Result<Uuid, SessionInsertError> {
+    let session_id = Uuid::new_v4();
+    session.renew();
+    session.remove(PENDING_LOGIN_KEY);
+    session.insert(USER_KEY, user.user_id)?;
+    session.insert(EPOCH_KEY, user.session_epoch)?;
+    session.insert(SESSION_ID_KEY, session_id)?;
+    Ok(session_id)
+}
+
+// Starts the cookie session and lists it among the user's sessions
+pub async fn log_in(
+    req: &HttpRequest,
+    session: &Session,
+    state: &AppState,
+    user: &UserData,
+) -> Result<Uuid, SessionInsertError> {
+    let session_id = start_session(session, user)?;
+    state.sessions.record(&user.user_id, session_id, SessionKind::Cookie, ClientInfo::from_request(req)).await;
+    Ok(session_id)
+}
+
+pub fn session_id(session: &Session) -> Option<Uuid> {
+    session.get::<Uuid>(SESSION_ID_KEY).ok().flatten()
+}
+
+// Loads the logged in user, purging sessions that have since been invalidated
+pub async fn session_user(session: &Session, state: &AppState) -> Option<UserData> {
+    let user_id = session.get::<Uuid>(USER_KEY).ok().flatten()?;
+    let user = match state.get_user(&user_id).await {
+        Some(user) => user,
+        None => {
+            session.purge();
+            return None;
+        }
+    };
+    // Sessions created before epochs existed carry none and count as epoch 0
+    let epoch = session.get::<u64>(EPOCH_KEY).ok().flatten().unwrap_or(0);
+    if epoch != user.session_epoch {
+        session.purge();
+        return None;
+    }
+    if let Some(session_id) = session_id(session) {
+        if state.sessions.is_revoked(&session_id).await {
+            session.purge();
+            return None;
+        }
+    }
+    Some(user)
+}
+
+pub struct Authenticated {
+    pub user: UserData,
+    // The cookie session or token session the request belongs to
+    pub session_id: Option<Uuid>,
+    pub bearer: bool,
+    // Set for API tokens, which only allow what they were scoped to
+    pub scopes: Option<Vec<ApiScope>>,
+}
+
+impl Authenticated {
+    pub fn allows(&self, scope: ApiScope) -> bool {
+        self.scopes.as_ref().is_none_or(|scopes| scopes.contains(&scope))
+    }
+
+    // Keeps the session list's last activity current, API tokens are listed
+    // with their bot instead
+    pub async fn touch(&self, req: &HttpRequest, state: &AppState) {
+        let session_id = match self.session_id {
+            Some(session_id) if self.scopes.is_none() => session_id,
+            _ => return,
+        };
+        let kind = if self.bearer { SessionKind::Token } else { SessionKind::Cookie };
+        state.sessions.touch(&self.user.user_id, session_id, kind, ClientInfo::from_request(req)).await;
+    }
+}
+
+// The access token from an `Authorization: Bearer` header
+pub fn bearer_token(req: &HttpRequest) -> Option<String> {
+    let value = req.headers().get(header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = value.split_once(' ')?;
+    scheme.eq_ignore_ascii_case("bearer").then(|| token.trim().to_string())
+}
+
+// Browsers cannot set headers on websocket requests, so clients may instead
+// offer the subprotocols "bearer, <token>"
+pub fn subprotocol_token(req: &HttpRequest) -> Option<String> {
+    let value = req.headers().get(header::SEC_WEBSOCKET_PROTOCOL)?.to_str().ok()?;
+    let mut protocols = value.split(',').map(str::trim);
+    protocols.find(|protocol| *protocol == "bearer")?;
+    protocols.next().map(String::from)
+}
+
+pub async fn token_user(token: &str, state: &AppState) -> Option<Authenticated> {
+    let claims = state.tokens.verify_access(token)?;
+    let user = state.get_user(&claims.sub).await?;
+    if claims.epoch != user.session_epoch || state.sessions.is_revoked(&claims.sid).await {
+        return None;
+    }
+    Some(Authenticated { user, session_id: Some(claims.sid), bearer: true, scopes: None })
+}
+
+// Bearer tokens accepted where bots may act, on top of access tokens
+pub async fn room_token_user(token: &str, state: &AppState) -> Option<Authenticated> {
+    if is_api_token(token) {
+        api_token_user(token, state).await
+    } else {
+        token_user(token, state).await
+    }
+}
+
+// Accepts either an access token or the cookie session, the token winning
+// when both are sent. Bot API tokens are not accepted for account routes.
+pub async fn authenticate(req: &HttpRequest, session: &Session, state: &AppState) -> Option<Authenticated> {
+    let auth = match bearer_token(req) {
+        Some(token) => token_user(&token, state).await?,
+        None => {
+            let user = session_user(session, state).await?;
+            Authenticated { user, session_id: session_id(session), bearer: false, scopes: None }
+        }
+    };
+    auth.touch(req, state).await;
+    Some(auth)
+}
+
+// Remembers who passed the password check without logging them in
+pub fn start_pending_login(session: &Session, user: &UserData) -> Result<(), SessionInsertError> {
+    session.clear();
+    session.renew();
+    session.insert(
+        PENDING_LOGIN_KEY,
+        PendingLogin { user_id: user.user_id, session_epoch: user.session_epoch, started_at: Utc::now().timestamp() },
+    )
+}
+
+// The user still owing a second factor, dropping logins that took too long or
+// whose password has changed since
+pub async fn pending_login_user(session: &Session, state: &AppState) -> Option<UserData> {
+    let pending = session.get::<PendingLogin>(PENDING_LOGIN_KEY).ok().flatten()?;
+    let expired = Utc::now().timestamp() - pending.started_at > PENDING_LOGIN_TTL.as_secs() as i64;
+    let user = match state.get_user(&pending.user_id).await {
+        Some(user) if !expired && user.session_epoch == pending.session_epoch => user,
+        _ => {
+            session.remove(PENDING_LOGIN_KEY);
+            return None;
+        }
+    };
+    Some(user)
+}
@@ -0,0 +1,379 @@
+use crate::appstate::AppState;
+use crate::audit::{AuditEvent, AuditKind};
+use crate::auth::{authenticate, bearer_token, room_token_user, Authenticated};
+use crate::one_time_token::{generate_token, hash_token};
+use crate::rate_limit::{too_many_requests, RateAction};
+use crate::storage::unique_violation;
+use crate::structs::{
+    AccountState, ConnectionState, CreateApiTokenForm, CreateBotForm, PostMessageForm, UserData, UserKind,
+};
+use actix_session::Session;
+use actix_web::{delete, get, post, web, HttpRequest, HttpResponse, Responder};
+use black_signal_protocol::*;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use surrealdb::sql::Uuid;
+
+const API_TOKEN_PREFIX: &str = "bsk_";
+const MAX_BOTS_PER_OWNER: usize = 10;
+const MAX_TOKENS_PER_BOT: usize = 20;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum ApiScope {
+    // Connect to the websocket and receive room traffic
+    Read,
+    // Send messages, over the websocket or the HTTP endpoint
+    Post,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ApiToken {
+    pub token_id: Uuid,
+    pub name: String,
+    pub hashed_secret: String,
+    pub scopes: Vec<ApiScope>,
+    pub created_at: DateTime<Utc>,
+}
+
+// What owners get to see of a token, never the hash
+#[derive(Serialize)]
+pub struct ApiTokenInfo {
+    pub token_id: Uuid,
+    pub name: String,
+    pub scopes: Vec<ApiScope>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+pub struct BotInfo {
+    pub user_id: Uuid,
+    pub username: String,
+    pub owner_id: Option<Uuid>,
+    pub tokens: Vec<ApiTokenInfo>,
+}
+
+impl From<UserData> for BotInfo {
+    fn from(bot: UserData) -> Self {
+        BotInfo {
+            user_id: bot.user_id,
+            username: bot.username,
+            owner_id: bot.owner_id,
+            tokens: bot
+                .api_tokens
+                .into_iter()
+                .map(|token| ApiTokenInfo {
+                    token_id: token.token_id,
+                    name: token.name,
+                    scopes: token.scopes,
+                    created_at: token.created_at,
+                })
+                .collect(),
+        }
+    }
+}
+
+pub fn is_api_token(token: &str) -> bool {
+    token.starts_with(API_TOKEN_PREFIX)
+}
+
+// Tokens read "bsk_<bot id>_<secret>" so the bot can be found without a scan
+fn format_api_token(bot_id: &Uuid, secret: &str) -> String {
+    format!("{}{}_{}", API_TOKEN_PREFIX, bot_id.0, secret)
+}
+
+fn parse_api_token(token: &str) -> Option<(Uuid, &str)> {
+    let (bot_id, secret) = token.strip_prefix(API_TOKEN_PREFIX)?.split_once('_')?;
+    Some((Uuid::try_from(bot_id).ok()?, secret))
+}
+
+pub async fn api_token_user(token: &str, state: &AppState) -> Option<Authenticated> {
+    let (bot_id, secret) = parse_api_token(token)?;
+    let bot = state.get_user(&bot_id).await?;
+    if bot.kind != UserKind::Bot {
+        return None;
+    }
+    let hashed_secret = hash_token(secret);
+    let api_token = bot.api_tokens.iter().find(|api_token| api_token.hashed_secret == hashed_secret)?;
+    let (token_id, scopes) = (api_token.token_id, api_token.scopes.clone());
+    Some(Authenticated { user: bot, session_id: Some(token_id), bearer: true, scopes: Some(scopes) })
+}
+
+// Only verified humans create and manage bots
+async fn bot_owner(req: &HttpRequest, session: &Session, state: &AppState) -> Result<UserData, HttpResponse> {
+    let user = match authenticate(req, session, state).await {
+        Some(auth) => auth.user,
+        None => return Err(HttpResponse::Unauthorized().json(json!({"error": "Not logged in"}))),
+    };
+    if user.kind != UserKind::Human || user.account_state != AccountState::Active {
+        return Err(HttpResponse::Forbidden().json(json!({"error": "Verify your email address first"})));
+    }
+    // Otherwise bot tokens would let the owner post without a second factor
+    if state.lacks_required_two_factor(&user) {
+        return Err(HttpResponse::Forbidden().json(json!({"error": "Enable two-factor authentication to use chat"})));
+    }
+    let ip = req.peer_addr().map(|addr| addr.ip());
+    if let Err(retry_after) = state.rate_limiter.check(Some(user.user_id), ip, RateAction::ManageBots) {
+        return Err(too_many_requests(retry_after));
+    }
+    Ok(user)
+}
+
+// The bot, if the owner (or an admin) may manage it
+async fn managed_bot(state: &AppState, owner: &UserData, bot_id: &str) -> Result<UserData, HttpResponse> {
+    let not_found = || HttpResponse::NotFound().json(json!({"error": "Bot not found"}));
+    let bot_id = Uuid::try_from(bot_id).map_err(|_| not_found())?;
+    match state.get_user(&bot_id).await {
+        Some(bot) if bot.kind == UserKind::Bot && (bot.owner_id == Some(owner.user_id) || state.is_admin(owner)) => Ok(bot),
+        _ => Err(not_found()),
+    }
+}
+
+// Admins may manage any bot, doing so to someone else's is audited
+async fn audit_admin_override(state: &AppState, req: &HttpRequest, owner: &UserData, bot: &UserData, action: &str) {
+    if bot.owner_id == Some(owner.user_id) {
+        return;
+    }
+    let ip = req.peer_addr().map(|addr| addr.ip());
+    let event = AuditEvent::new(AuditKind::AdminAction).actor(owner.user_id).target(bot.user_id).ip(ip).detail("action", action);
+    state.audit.record(event).await;
+}
+
+async fn owned_bots(state: &AppState, owner_id: &Uuid) -> Option<Vec<UserData>> {
+    match state.storage.bots_owned_by(owner_id).await {
+        Ok(bots) => Some(bots),
+        Err(e) => {
+            log::error!("Failed to query bots: fn owned_bots, error: {:?}", e);
+            None
+        }
+    }
+}
+
+async fn set_api_tokens(state: &AppState, bot_id: &Uuid, api_tokens: Vec<ApiToken>) -> bool {
+    if let Err(e) = state.storage.set_api_tokens(bot_id, api_tokens).await {
+        log::error!("Failed to update api tokens: fn set_api_tokens, error: {:?}", e);
+        return false;
+    }
+    true
+}
+
+#[post("/bots")]
+pub async fn create_bot(
+    req: HttpRequest,
+    form: web::Json<CreateBotForm>,
+    session: Session,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let owner = match bot_owner(&req, &session, &state).await {
+        Ok(owner) => owner,
+        Err(response) => return response,
+    };
+    let username = form.into_inner().username.trim().to_string();
+    if username.is_empty() {
+        return HttpResponse::BadRequest().json(json!({"error": "Bot name must not be empty"}));
+    }
+    match owned_bots(&state, &owner.user_id).await {
+        Some(bots) if bots.len() >= MAX_BOTS_PER_OWNER => {
+            return HttpResponse::BadRequest().json(json!({"error": format!("At most {} bots per owner", MAX_BOTS_PER_OWNER)}))
+        }
+        Some(_) => {}
+        None => return HttpResponse::InternalServerError().json(json!({"error": "Internal DB Error"})),
+    }
+    let bot_id = Uuid::new_v4();
+    let bot = UserData {
+        user_id: bot_id,
+        // Bots never log in with a password, the login only has to be unique
+        login: format!("bot:{}", bot_id.0),
+        username,
+        hashed_password: String::new(),
+        status: ConnectionState::Offline,
+        rooms: vec![state.main_room_id],
+        is_admin: false,
+        session_epoch: 0,
+        account_state: AccountState::Active,
+        two_factor: None,
+        kind: UserKind::Bot,
+        owner_id: Some(owner.user_id),
+        api_tokens: Vec::new(),
+    };
+    if let Err(e) = state.storage.create_user(&bot).await {
+        if unique_violation(&e).is_some() {
+            return HttpResponse::BadRequest().json(json!({"error": "Username Already In Use"}));
+        }
+        log::error!("Failed to create bot: fn create_bot, error: {:?}", e);
+        return HttpResponse::InternalServerError().json(json!({"error": "Internal DB Error"}));
+    }
+    if let Err(e) = state.storage.add_room_member(&state.main_room_id, &bot_id).await {
+        log::error!("Failed to add bot to room: fn create_bot, error: {:?}", e);
+        return HttpResponse::InternalServerError().json(json!({"error": "Internal DB Error"}));
+    }
+    let message = UserMessage::NewUser(NewUserMessage::bot(bot_id.into(), bot.username.clone()));
+    if let Ok(serialized) = serde_json::to_string(&message) {
+        state.broadcast_message(serialized, &state.main_room_id, &bot_id).await;
+    }
+    HttpResponse::Ok().json(BotInfo::from(bot))
+}
+
+#[get("/bots")]
+pub async fn list_bots(req: HttpRequest, session: Session, state: web::Data<AppState>) -> impl Responder {
+    let owner = match bot_owner(&req, &session, &state).await {
+        Ok(owner) => owner,
+        Err(response) => return response,
+    };
+    match owned_bots(&state, &owner.user_id).await {
+        Some(bots) => HttpResponse::Ok().json(bots.into_iter().map(BotInfo::from).collect::<Vec<_>>()),
+        None => HttpResponse::InternalServerError().json(json!({"error": "Internal DB Error"})),
+    }
+}
+
+// Revokes a bot outright, its messages stay behind
+#[delete("/bots/{bot_id}")]
+pub async fn delete_bot(
+    req: HttpRequest,
+    path: web::Path<String>,
+    session: Session,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let owner = match bot_owner(&req, &session, &state).await {
+        Ok(owner) => owner,
+        Err(response) => return response,
+    };
+    let bot = match managed_bot(&state, &owner, &path).await {
+        Ok(bot) => bot,
+        Err(response) => return response,
+    };
+    if let Err(e) = state.storage.delete_user(&bot.user_id).await {
+        log::error!("Failed to delete bot: fn delete_bot, error: {:?}", e);
+        return HttpResponse::InternalServerError().json(json!({"error": "Internal DB Error"}));
+    }
+    state.disconnect_user(&bot.user_id, None, "Bot deleted".to_string()).await;
+    audit_admin_override(&state, &req, &owner, &bot, "delete_bot").await;
+    HttpResponse::Ok().json(json!({"message": "Bot deleted"}))
+}
+
+#[post("/bots/{bot_id}/tokens")]
+pub async fn create_api_token(
+    req: HttpRequest,
+    path: web::Path<String>,
+    form: web::Json<CreateApiTokenForm>,
+    session: Session,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let owner = match bot_owner(&req, &session, &state).await {
+        Ok(owner) => owner,
+        Err(response) => return response,
+    };
+    let bot = match managed_bot(&state, &owner, &path).await {
+        Ok(bot) => bot,
+        Err(response) => return response,
+    };
+    let form = form.into_inner();
+    if form.scopes.is_empty() {
+        return HttpResponse::BadRequest().json(json!({"error": "A token needs at least one scope"}));
+    }
+    if bot.api_tokens.len() >= MAX_TOKENS_PER_BOT {
+        return HttpResponse::BadRequest().json(json!({"error": format!("At most {} tokens per bot", MAX_TOKENS_PER_BOT)}));
+    }
+    let secret = generate_token();
+    let api_token = ApiToken {
+        token_id: Uuid::new_v4(),
+        name: form.name,
+        hashed_secret: hash_token(&secret),
+        scopes: form.scopes,
+        created_at: Utc::now(),
+    };
+    let mut api_tokens = bot.api_tokens.clone();
+    api_tokens.push(api_token.clone());
+    if !set_api_tokens(&state, &bot.user_id, api_tokens).await {
+        return HttpResponse::InternalServerError().json(json!({"error": "Internal DB Error"}));
+    }
+    audit_admin_override(&state, &req, &owner, &bot, "create_api_token").await;
+    // The only time the token itself is ever shown
+    HttpResponse::Ok().json(json!({
+        "token": format_api_token(&bot.user_id, &secret),
+        "token_id": api_token.token_id,
+        "scopes": api_token.scopes,
+    }))
+}
+
+#[delete("/bots/{bot_id}/tokens/{token_id}")]
+pub async fn revoke_api_token(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    session: Session,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let owner = match bot_owner(&req, &session, &state).await {
+        Ok(owner) => owner,
+        Err(response) => return response,
+    };
+    let (bot_id, token_id) = path.into_inner();
+    let bot = match managed_bot(&state, &owner, &bot_id).await {
+        Ok(bot) => bot,
+        Err(response) => return response,
+    };
+    let token_id = match Uuid::try_from(token_id.as_str()) {
+        Ok(token_id) if bot.api_tokens.iter().any(|api_token| api_token.token_id == token_id) => token_id,
+        _ => return HttpResponse::NotFound().json(json!({"error": "Token not found"})),
+    };
+    let api_tokens = bot.api_tokens.iter().filter(|api_token| api_token.token_id != token_id).cloned().collect();
+    if !set_api_tokens(&state, &bot.user_id, api_tokens).await {
+        return HttpResponse::InternalServerError().json(json!({"error": "Internal DB Error"}));
+    }
+    audit_admin_override(&state, &req, &owner, &bot, "revoke_api_token").await;
+    state.disconnect_session(&bot.user_id, token_id, "API token revoked".to_string()).await;
+    HttpResponse::Ok().json(json!({"message": "Token revoked"}))
+}
+
+// Lets scripts post without holding a websocket open
+#[post("/rooms/{room_id}/messages")]
+pub async fn post_to_room(
+    req: HttpRequest,
+    path: web::Path<String>,
+    form: web::Json<PostMessageForm>,
+    session: Session,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let auth = match bearer_token(&req) {
+        Some(token) => room_token_user(&token, &state).await,
+        None => authenticate(&req, &session, &state).await,
+    };
+    let auth = match auth {
+        Some(auth) => auth,
+        None => return HttpResponse::Unauthorized().json(json!({"error": "Not logged in"})),
+    };
+    if !auth.allows(ApiScope::Post) {
+        return HttpResponse::Forbidden().json(json!({"error": "Token is not allowed to post"}));
+    }
+    if auth.user.account_state != AccountState::Active {
+        return HttpResponse::Forbidden().json(json!({"error": "Verify your email address first"}));
+    }
+    if state.lacks_required_two_factor(&auth.user) {
+        return HttpResponse::Forbidden().json(json!({"error": "Enable two-factor authentication to use chat"}));
+    }
+    let ip = req.peer_addr().map(|addr| addr.ip());
+    if let Err(retry_after) = state.rate_limiter.check(Some(auth.user.user_id), ip, RateAction::Message) {
+        return too_many_requests(retry_after);
+    }
+    let room_id = match Uuid::try_from(path.as_str()) {
+        Ok(room_id) if auth.user.rooms.contains(&room_id) => room_id,
+        _ => return HttpResponse::Forbidden().json(json!({"error": "Not a member of the requested room"})),
+    };
+    let content = form.into_inner().content;
+    if content.trim().is_empty() {
+        return HttpResponse::BadRequest().json(json!({"error": "Message must not be empty"}));
+    }
+    let message = BasicMessage {
+        content,
+        sender_id: auth.user.user_id.into(),
+        timestamp: Utc::now().timestamp() as u64,
+        message_id: Uuid::new_v4().into(),
+        room_id: room_id.into(),
+        // There is no socket, so the token session stands in for one
+        ws_id: auth.session_id.unwrap_or_else(Uuid::new_v4).into(),
+    };
+    match state.post_message(&message).await {
+        Ok(()) => HttpResponse::Ok().json(json!({"message_id": message.message_id})),
+        Err(_) => HttpResponse::InternalServerError().json(json!({"error": "Failed to save message"})),
+    }
+}
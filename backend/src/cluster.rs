@@ -0,0 +1,257 @@
+use crate::appstate::ActorRegistry;
+use crate::kv::KvStore;
+use crate::storage::Storage;
+use crate::websocket::{change_to_offline, AccountActivated, Disconnect, WsMessage};
+use chrono::Utc;
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use surrealdb::sql::Uuid;
+
+const CHANNEL_PREFIX: &str = "black_signal:";
+const PRESENCE_PREFIX: &str = "presence";
+// Users that have or recently had a socket anywhere, scored by when their
+// latest entry expires, so the reaper knows whose entries to check
+const PRESENCE_USERS_KEY: &str = "presence_users";
+// Each socket's entry is scored by when it expires. Open sockets push that
+// back well before it passes, the reaper prunes the entries of sockets on an
+// instance that crashed and marks users left without any offline.
+const PRESENCE_TTL: Duration = Duration::from_secs(2 * 60);
+pub const PRESENCE_REFRESH: Duration = Duration::from_secs(60);
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(1);
+
+// Something every backend instance applies to the sockets it holds
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum ClusterEvent {
+    // A serialized frame for every socket of the users
+    Frame { user_ids: Vec<Uuid>, message: String },
+    Activated { user_id: Uuid },
+    Disconnect {
+        user_id: Uuid,
+        keep_session: Option<Uuid>,
+        only_session: Option<Uuid>,
+        reason: String,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+struct Envelope {
+    origin: Uuid,
+    event: ClusterEvent,
+}
+
+// An open socket on any instance
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SocketPresence {
+    pub ws_id: Uuid,
+    pub session_id: Option<Uuid>,
+    pub node_id: Uuid,
+}
+
+fn presence_key(user_id: &Uuid) -> String {
+    format!("{}:{}", PRESENCE_PREFIX, user_id.0)
+}
+
+fn now_ms() -> u64 {
+    Utc::now().timestamp_millis().max(0) as u64
+}
+
+// Orders the presence writes of one socket. The open runs in a spawned task
+// and can lose the race with a socket that closes right away; once the close
+// has gone through, later opens and refreshes are skipped instead of leaving
+// an entry behind.
+#[derive(Clone, Default)]
+pub struct PresenceLock(Arc<tokio::sync::Mutex<bool>>);
+
+// Fans events out to the sockets of every instance through kv pub/sub. Each
+// instance delivers its own events directly and ignores their echo.
+#[derive(Clone)]
+pub struct Cluster {
+    pub node_id: Uuid,
+    kv: KvStore,
+    registry: ActorRegistry,
+}
+
+impl Cluster {
+    pub fn new(kv: KvStore, registry: ActorRegistry) -> Self {
+        Cluster { node_id: Uuid::new_v4(), kv, registry }
+    }
+
+    // Forwards events published by other instances to local sockets for as
+    // long as the process runs
+    pub fn listen(&self) {
+        let cluster = self.clone();
+        actix_web::rt::spawn(async move {
+            loop {
+                match cluster.kv.subscribe(CHANNEL_PREFIX).await {
+                    Ok(mut published) => {
+                        while let Some((channel, payload)) = published.next().await {
+                            cluster.receive(&channel, &payload);
+                        }
+                        log::warn!("Lost the cluster event subscription, resubscribing");
+                    }
+                    Err(e) => log::error!("Failed to subscribe to cluster events: fn listen, error: {:?}", e),
+                }
+                actix_web::rt::time::sleep(RESUBSCRIBE_DELAY).await;
+            }
+        });
+    }
+
+    fn receive(&self, channel: &str, payload: &str) {
+        match serde_json::from_str::<Envelope>(payload) {
+            Ok(envelope) if envelope.origin != self.node_id => self.deliver(&envelope.event),
+            Ok(_) => {}
+            Err(e) => log::error!("Dropped malformed event on {}: fn receive, error: {:?}", channel, e),
+        }
+    }
+
+    pub async fn publish_to_room(&self, room_id: &Uuid, event: ClusterEvent) {
+        self.publish(format!("{}room:{}", CHANNEL_PREFIX, room_id.0), event).await;
+    }
+
+    pub async fn publish_to_user(&self, user_id: &Uuid, event: ClusterEvent) {
+        self.publish(format!("{}user:{}", CHANNEL_PREFIX, user_id.0), event).await;
+    }
+
+    async fn publish(&self, channel: String, event: ClusterEvent) {
+        self.deliver(&event);
+        let envelope = Envelope { origin: self.node_id, event };
+        let serialized = match serde_json::to_string(&envelope) {
+            Ok(serialized) => serialized,
+            Err(e) => {
+                log::error!("Failed to serialize cluster event: fn publish, error: {:?}", e);
+                return;
+            }
+        };
+        if let Err(e) = self.kv.publish(&channel, &serialized).await {
+            log::error!("Failed to publish cluster event: fn publish, error: {:?}", e);
+        }
+    }
+
+    fn deliver(&self, event: &ClusterEvent) {
+        let registry = self.registry.lock().unwrap();
+        match event {
+            ClusterEvent::Frame { user_ids, message } => {
+                for sockets in user_ids.iter().filter_map(|user_id| registry.get(user_id)) {
+                    for socket in sockets.values() {
+                        socket.addr.do_send(WsMessage(message.clone()));
+                    }
+                }
+            }
+            ClusterEvent::Activated { user_id } => {
+                for socket in registry.get(user_id).into_iter().flat_map(|sockets| sockets.values()) {
+                    socket.addr.do_send(AccountActivated);
+                }
+            }
+            ClusterEvent::Disconnect { user_id, keep_session, only_session, reason } => {
+                for socket in registry.get(user_id).into_iter().flat_map(|sockets| sockets.values()) {
+                    socket.addr.do_send(Disconnect {
+                        keep_session: *keep_session,
+                        only_session: *only_session,
+                        reason: reason.clone(),
+                    });
+                }
+            }
+        }
+    }
+
+    fn presence(&self, ws_id: Uuid, session_id: Option<Uuid>) -> String {
+        let presence = SocketPresence { ws_id, session_id, node_id: self.node_id };
+        serde_json::to_string(&presence).unwrap()
+    }
+
+    // Also called periodically by open sockets to keep their entry alive.
+    // The then callback, e.g. marking the user online, runs while the lock is
+    // held, so it cannot overtake the close either.
+    pub async fn socket_opened(
+        &self,
+        lock: &PresenceLock,
+        user_id: &Uuid,
+        ws_id: Uuid,
+        session_id: Option<Uuid>,
+        then: impl Future<Output = ()>,
+    ) {
+        let closed = lock.0.lock().await;
+        if *closed {
+            return;
+        }
+        let expires_at = now_ms() + PRESENCE_TTL.as_millis() as u64;
+        let member = self.presence(ws_id, session_id);
+        let recorded = async {
+            self.kv.scored_add(&presence_key(user_id), &member, expires_at).await?;
+            self.kv.scored_add(PRESENCE_USERS_KEY, &user_id.0.to_string(), expires_at).await
+        };
+        if let Err(e) = recorded.await {
+            log::error!("Failed to record socket presence: fn socket_opened, error: {:?}", e);
+        }
+        then.await;
+    }
+
+    // Whether the user has no sockets left on any instance. Further opens
+    // and refreshes through the lock are ignored afterwards.
+    pub async fn socket_closed(&self, lock: &PresenceLock, user_id: &Uuid, ws_id: Uuid, session_id: Option<Uuid>) -> bool {
+        let mut closed = lock.0.lock().await;
+        if *closed {
+            return false;
+        }
+        *closed = true;
+        let member = self.presence(ws_id, session_id);
+        if let Err(e) = self.kv.scored_remove(&presence_key(user_id), &member).await {
+            log::error!("Failed to remove socket presence: fn socket_closed, error: {:?}", e);
+        }
+        self.sockets(user_id).await.is_none_or(|sockets| sockets.is_empty())
+    }
+
+    // Periodically drops the entries of sockets that stopped refreshing them,
+    // whose instance went away without closing them, and marks users left
+    // without a socket offline. Every instance runs it, the work is idempotent.
+    pub fn reap_presence(&self, storage: Arc<dyn Storage>) {
+        let cluster = self.clone();
+        actix_web::rt::spawn(async move {
+            let mut interval = actix_web::rt::time::interval(PRESENCE_REFRESH);
+            loop {
+                interval.tick().await;
+                cluster.reap(storage.clone()).await;
+            }
+        });
+    }
+
+    pub async fn reap(&self, storage: Arc<dyn Storage>) {
+        let now = now_ms();
+        let user_ids = match self.kv.scored_members(PRESENCE_USERS_KEY, 0).await {
+            Ok(user_ids) => user_ids,
+            Err(e) => {
+                log::error!("Failed to list users with presence: fn reap, error: {:?}", e);
+                return;
+            }
+        };
+        for user_id in user_ids.iter().filter_map(|user_id| Uuid::try_from(user_id.as_str()).ok()) {
+            match self.kv.scored_prune(&presence_key(&user_id), now).await {
+                // Users whose last socket closed normally were already marked
+                Ok((removed, 0)) if removed > 0 => {
+                    log::info!("Reaped stale socket presence: fn reap, user_id: {}, count: {}", user_id.0, removed);
+                    change_to_offline(storage.clone(), user_id).await;
+                }
+                Ok(_) => {}
+                Err(e) => log::error!("Failed to prune socket presence: fn reap, error: {:?}", e),
+            }
+        }
+        // Anyone with a live socket has refreshed their score since
+        if let Err(e) = self.kv.scored_prune(PRESENCE_USERS_KEY, now).await {
+            log::error!("Failed to prune users with presence: fn reap, error: {:?}", e);
+        }
+    }
+
+    // Sockets whose entry has not expired
+    pub async fn sockets(&self, user_id: &Uuid) -> Option<Vec<SocketPresence>> {
+        match self.kv.scored_members(&presence_key(user_id), now_ms()).await {
+            Ok(members) => Some(members.iter().filter_map(|member| serde_json::from_str(member).ok()).collect()),
+            Err(e) => {
+                log::error!("Failed to list socket presence: fn sockets, error: {:?}", e);
+                None
+            }
+        }
+    }
+}
@@ -0,0 +1,403 @@
+use crate::audit::AuditConfig;
+use crate::kv::KvBackend;
+use crate::login_guard::LoginGuardConfig;
+use crate::mailer::MailerConfig;
+use crate::metrics::MetricsConfig;
+use crate::password_policy::PasswordPolicyConfig;
+use crate::rate_limit::RateLimitConfig;
+use crate::session_key::SessionKeyConfig;
+use crate::session_store::SessionStoreKind;
+use crate::shutdown::ShutdownConfig;
+use crate::storage::StorageBackend;
+use crate::tokens::TokenConfig;
+use crate::two_factor::TwoFactorConfig;
+use crate::websocket::WebSocketConfig;
+use anyhow::Context;
+use clap::{Parser, ValueEnum};
+use redis::IntoConnectionInfo;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const DEFAULT_CONFIG_FILE: &str = "blacksignal.toml";
+const REDACTED: &str = "<redacted>";
+
+#[derive(Parser, Debug, Default)]
+#[command(version, about = "BlackSignal chat server")]
+pub struct Cli {
+    // Defaults to BLACKSIGNAL_CONFIG, then blacksignal.toml if it exists
+    #[arg(long, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+    #[arg(long, value_name = "ADDRESS")]
+    pub bind: Option<String>,
+    #[arg(long)]
+    pub port: Option<u16>,
+    #[arg(long, value_name = "ORIGIN")]
+    pub cors_origin: Option<String>,
+    #[arg(long, value_enum)]
+    pub db_backend: Option<StorageBackend>,
+    #[arg(long, value_name = "HOST:PORT")]
+    pub db_address: Option<String>,
+    #[arg(long)]
+    pub namespace: Option<String>,
+    #[arg(long)]
+    pub database: Option<String>,
+    #[arg(long, value_name = "URL")]
+    pub redis_url: Option<String>,
+    #[arg(long, value_enum)]
+    pub kv_backend: Option<KvBackend>,
+    #[arg(long, value_enum)]
+    pub session_store: Option<SessionStoreKind>,
+    // Prints the effective configuration with secrets redacted and exits
+    #[arg(long)]
+    pub print_config: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct ServerConfig {
+    pub bind_address: String,
+    pub port: u16,
+    // The frontend allowed to make credentialed requests
+    pub cors_origin: String,
+    // Base of the links sent in emails
+    pub public_url: String,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            bind_address: "0.0.0.0".to_string(),
+            port: 8080,
+            cors_origin: "http://127.0.0.1:3000".to_string(),
+            public_url: "http://127.0.0.1:3000".to_string(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct DatabaseConfig {
+    pub backend: StorageBackend,
+    // Tries at the first connection before giving up, 0 keeps trying
+    pub connect_attempts: u32,
+    // Wait between reconnection tries, doubling up to the maximum
+    pub retry_initial_ms: u64,
+    pub retry_max_ms: u64,
+    // A query that takes longer counts as a lost connection
+    pub query_timeout_secs: u64,
+    // How often the connection is checked while nothing seems wrong
+    pub health_check_interval_secs: u64,
+    // The rest only applies to the surreal backend
+    pub address: String,
+    pub username: String,
+    pub password: String,
+    pub namespace: String,
+    pub database: String,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        DatabaseConfig {
+            backend: StorageBackend::Surreal,
+            connect_attempts: 10,
+            retry_initial_ms: 500,
+            retry_max_ms: 30_000,
+            query_timeout_secs: 10,
+            health_check_interval_secs: 5,
+            address: "localhost:8000".to_string(),
+            username: "root".to_string(),
+            password: "root".to_string(),
+            namespace: "general".to_string(),
+            database: "all".to_string(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct RedisConfig {
+    pub url: String,
+}
+
+impl Default for RedisConfig {
+    fn default() -> Self {
+        RedisConfig { url: "redis://127.0.0.1:6379".to_string() }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct KvConfig {
+    // Where rate limits, lockouts, tokens and the session index are kept
+    pub backend: KvBackend,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct SessionConfig {
+    pub store: SessionStoreKind,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct AccountsConfig {
+    // Creates test@gmail.com with the password "password" in the main room,
+    // for development only
+    pub seed_test_user: bool,
+    // Accounts with admin rights, by login
+    pub admin_logins: Vec<String>,
+}
+
+impl AccountsConfig {
+    // Overrides settings with SEED_TEST_USER and ADMIN_LOGINS, the latter
+    // separated by commas
+    pub fn apply_env(&mut self) {
+        if let Ok(seed) = env::var("SEED_TEST_USER") {
+            match seed.parse() {
+                Ok(seed) => self.seed_test_user = seed,
+                Err(e) => log::warn!("Ignoring invalid SEED_TEST_USER: {}", e),
+            }
+        }
+        if let Ok(logins) = env::var("ADMIN_LOGINS") {
+            self.admin_logins = logins.split(',').map(str::trim).filter(|login| !login.is_empty()).map(String::from).collect();
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub accounts: AccountsConfig,
+    pub database: DatabaseConfig,
+    pub redis: RedisConfig,
+    pub kv: KvConfig,
+    pub session: SessionConfig,
+    pub mail: MailerConfig,
+    pub password_policy: PasswordPolicyConfig,
+    pub rate_limit: RateLimitConfig,
+    pub login_guard: LoginGuardConfig,
+    pub two_factor: TwoFactorConfig,
+    pub websocket: WebSocketConfig,
+    pub shutdown: ShutdownConfig,
+    pub tokens: TokenConfig,
+    pub session_key: SessionKeyConfig,
+    pub audit: AuditConfig,
+    pub metrics: MetricsConfig,
+}
+
+impl Config {
+    // Defaults, then the config file, then environment variables, then flags
+    pub fn load(cli: &Cli) -> anyhow::Result<Self> {
+        let mut config = match config_path(cli) {
+            Some(path) => Config::from_file(&path)?,
+            None => Config::default(),
+        };
+        config.apply_env();
+        config.apply_cli(cli);
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let contents = fs::read_to_string(path).with_context(|| format!("failed to read config file {}", path.display()))?;
+        toml::from_str(&contents).with_context(|| format!("invalid config file {}", path.display()))
+    }
+
+    fn apply_env(&mut self) {
+        let overrides: [(&str, &mut String); 9] = [
+            ("BIND_ADDRESS", &mut self.server.bind_address),
+            ("CORS_ORIGIN", &mut self.server.cors_origin),
+            ("PUBLIC_URL", &mut self.server.public_url),
+            ("SURREAL_ADDRESS", &mut self.database.address),
+            ("SURREAL_USERNAME", &mut self.database.username),
+            ("SURREAL_PASSWORD", &mut self.database.password),
+            ("SURREAL_NAMESPACE", &mut self.database.namespace),
+            ("SURREAL_DATABASE", &mut self.database.database),
+            ("REDIS_URL", &mut self.redis.url),
+        ];
+        for (name, field) in overrides {
+            if let Ok(value) = env::var(name) {
+                *field = value;
+            }
+        }
+        if let Some(attempts) = env::var("DATABASE_CONNECT_ATTEMPTS").ok().and_then(|value| value.parse().ok()) {
+            self.database.connect_attempts = attempts;
+        }
+        let database_overrides: [(&str, &mut u64); 4] = [
+            ("DATABASE_RETRY_INITIAL_MS", &mut self.database.retry_initial_ms),
+            ("DATABASE_RETRY_MAX_MS", &mut self.database.retry_max_ms),
+            ("DATABASE_QUERY_TIMEOUT_SECS", &mut self.database.query_timeout_secs),
+            ("DATABASE_HEALTH_CHECK_INTERVAL_SECS", &mut self.database.health_check_interval_secs),
+        ];
+        for (name, field) in database_overrides {
+            if let Some(value) = env::var(name).ok().and_then(|value| value.parse().ok()) {
+                *field = value;
+            }
+        }
+        if let Ok(backend) = env::var("DATABASE_BACKEND") {
+            match StorageBackend::from_str(&backend, true) {
+                Ok(backend) => self.database.backend = backend,
+                Err(e) => log::warn!("Ignoring invalid DATABASE_BACKEND: {}", e),
+            }
+        }
+        if let Ok(backend) = env::var("KV_BACKEND") {
+            match KvBackend::from_str(&backend, true) {
+                Ok(backend) => self.kv.backend = backend,
+                Err(e) => log::warn!("Ignoring invalid KV_BACKEND: {}", e),
+            }
+        }
+        if let Ok(store) = env::var("SESSION_STORE") {
+            match SessionStoreKind::from_str(&store, true) {
+                Ok(store) => self.session.store = store,
+                Err(e) => log::warn!("Ignoring invalid SESSION_STORE: {}", e),
+            }
+        }
+        if let Ok(port) = env::var("PORT") {
+            match port.parse() {
+                Ok(port) => self.server.port = port,
+                Err(e) => log::warn!("Ignoring invalid PORT: {}", e),
+            }
+        }
+        self.accounts.apply_env();
+        self.mail.apply_env();
+        self.password_policy.apply_env();
+        self.rate_limit.apply_env();
+        self.login_guard.apply_env();
+        self.two_factor.apply_env();
+        self.websocket.apply_env();
+        self.shutdown.apply_env();
+        self.tokens.apply_env();
+        self.session_key.apply_env();
+        self.audit.apply_env();
+        self.metrics.apply_env();
+    }
+
+    fn apply_cli(&mut self, cli: &Cli) {
+        let overrides = [
+            (&cli.bind, &mut self.server.bind_address),
+            (&cli.cors_origin, &mut self.server.cors_origin),
+            (&cli.db_address, &mut self.database.address),
+            (&cli.namespace, &mut self.database.namespace),
+            (&cli.database, &mut self.database.database),
+            (&cli.redis_url, &mut self.redis.url),
+        ];
+        for (flag, field) in overrides {
+            if let Some(value) = flag {
+                *field = value.clone();
+            }
+        }
+        if let Some(backend) = cli.db_backend {
+            self.database.backend = backend;
+        }
+        if let Some(backend) = cli.kv_backend {
+            self.kv.backend = backend;
+        }
+        if let Some(store) = cli.session_store {
+            self.session.store = store;
+        }
+        if let Some(port) = cli.port {
+            self.server.port = port;
+        }
+    }
+
+    // Every problem is reported at once rather than one per restart
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let mut errors = Vec::new();
+        if self.server.bind_address.trim().is_empty() {
+            errors.push("server.bind_address must not be empty".to_string());
+        }
+        if self.server.port == 0 {
+            errors.push("server.port must not be 0".to_string());
+        }
+        for (name, url) in [("server.cors_origin", &self.server.cors_origin), ("server.public_url", &self.server.public_url)] {
+            if !(url.starts_with("http://") || url.starts_with("https://")) {
+                errors.push(format!("{} must be an http:// or https:// URL", name));
+            }
+        }
+        let database_fields = [
+            ("database.address", &self.database.address),
+            ("database.username", &self.database.username),
+            ("database.namespace", &self.database.namespace),
+            ("database.database", &self.database.database),
+        ];
+        for (name, value) in database_fields {
+            if self.database.backend == StorageBackend::Surreal && value.trim().is_empty() {
+                errors.push(format!("{} must not be empty", name));
+            }
+        }
+        if self.database.retry_initial_ms == 0 || self.database.retry_initial_ms > self.database.retry_max_ms {
+            errors.push("database.retry_initial_ms must be positive and not exceed retry_max_ms".to_string());
+        }
+        if self.database.query_timeout_secs == 0 || self.database.health_check_interval_secs == 0 {
+            errors.push("database.query_timeout_secs and health_check_interval_secs must be positive".to_string());
+        }
+        let uses_redis = self.kv.backend == KvBackend::Redis || self.session.store == SessionStoreKind::Redis;
+        if uses_redis {
+            if let Err(e) = self.redis.url.as_str().into_connection_info() {
+                errors.push(format!("redis.url is invalid: {}", e));
+            }
+            // Instances sharing Redis accept each other's tokens, a random
+            // secret per process would not
+            if self.tokens.secret.as_deref().is_none_or(|secret| secret.trim().is_empty()) {
+                errors.push("tokens.secret (JWT_SECRET) must be set when Redis is used for kv.backend or session.store".to_string());
+            }
+        }
+        if self.password_policy.min_length == 0 || self.password_policy.min_length > self.password_policy.max_length {
+            errors.push("password_policy.min_length must be between 1 and max_length".to_string());
+        }
+        errors.extend(self.rate_limit.problems());
+        if self.login_guard.base_backoff_secs > self.login_guard.max_backoff_secs {
+            errors.push("login_guard.base_backoff_secs must not exceed max_backoff_secs".to_string());
+        }
+        if self.tokens.access_ttl_secs == 0 || self.tokens.access_ttl_secs >= self.tokens.refresh_ttl_secs {
+            errors.push("tokens.access_ttl_secs must be positive and shorter than refresh_ttl_secs".to_string());
+        }
+        if self.websocket.heartbeat_interval_secs == 0 || self.websocket.heartbeat_interval_secs >= self.websocket.client_timeout_secs {
+            errors.push("websocket.heartbeat_interval_secs must be positive and shorter than client_timeout_secs".to_string());
+        }
+        if let MailerConfig::Smtp { from, .. } = &self.mail {
+            if from.parse::<lettre::message::Mailbox>().is_err() {
+                errors.push("mail.from is not a valid mailbox".to_string());
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!("invalid configuration:\n  {}", errors.join("\n  ")))
+        }
+    }
+
+    // The effective configuration as TOML, safe to paste into a bug report
+    pub fn to_redacted_toml(&self) -> anyhow::Result<String> {
+        let mut config = self.clone();
+        config.database.password = REDACTED.to_string();
+        if let MailerConfig::Smtp { password, .. } = &mut config.mail {
+            *password = REDACTED.to_string();
+        }
+        let secrets = [
+            &mut config.tokens.secret,
+            &mut config.session_key.key,
+            &mut config.session_key.previous_key,
+            &mut config.metrics.token,
+        ];
+        for secret in secrets {
+            if secret.is_some() {
+                *secret = Some(REDACTED.to_string());
+            }
+        }
+        Ok(toml::to_string_pretty(&config)?)
+    }
+}
+
+fn config_path(cli: &Cli) -> Option<PathBuf> {
+    if let Some(path) = &cli.config {
+        return Some(path.clone());
+    }
+    if let Ok(path) = env::var("BLACKSIGNAL_CONFIG") {
+        return Some(PathBuf::from(path));
+    }
+    Path::new(DEFAULT_CONFIG_FILE).exists().then(|| PathBuf::from(DEFAULT_CONFIG_FILE))
+}
@@ -0,0 +1,88 @@
+use crate::appstate::AppState;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::{get, web, Error, HttpResponse, Responder};
+use actix_web_lab::middleware::Next;
+use serde::Serialize;
+use std::future::Future;
+use std::time::Duration;
+
+// A dependency that does not answer within this long counts as down
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+// Suggested to clients turned away while storage is reconnecting
+const DEGRADED_RETRY_AFTER_SECS: u64 = 5;
+// Still served while storage is down, so probes and scrapes see the outage
+const ALWAYS_SERVED: &[&str] = &["/healthz", "/readyz", "/metrics"];
+
+#[derive(Serialize, Debug)]
+pub struct DependencyStatus {
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Readiness {
+    pub ready: bool,
+    // Refusing new sockets because the instance is shutting down
+    pub draining: bool,
+    // Reachable with every migration applied
+    pub storage: DependencyStatus,
+    pub kv: DependencyStatus,
+}
+
+async fn check(dependency: impl Future<Output = anyhow::Result<()>>) -> DependencyStatus {
+    let error = match actix_web::rt::time::timeout(CHECK_TIMEOUT, dependency).await {
+        Ok(Ok(())) => None,
+        Ok(Err(e)) => Some(format!("{:#}", e)),
+        Err(_) => Some(format!("no answer within {} seconds", CHECK_TIMEOUT.as_secs())),
+    };
+    DependencyStatus { ok: error.is_none(), error }
+}
+
+pub async fn readiness(state: &AppState) -> Readiness {
+    let storage = check(state.storage.check_health()).await;
+    let kv = check(state.kv.ping()).await;
+    let draining = state.drain.is_draining();
+    Readiness { ready: storage.ok && kv.ok && !draining, draining, storage, kv }
+}
+
+// The process is up and serving requests
+#[get("/healthz")]
+pub async fn healthz() -> impl Responder {
+    HttpResponse::Ok().json(serde_json::json!({"status": "ok"}))
+}
+
+// Whether this instance should be sent traffic
+#[get("/readyz")]
+pub async fn readyz(state: web::Data<AppState>) -> impl Responder {
+    let readiness = readiness(&state).await;
+    for (name, dependency) in [("storage", &readiness.storage), ("kv", &readiness.kv)] {
+        if let Some(error) = &dependency.error {
+            log::warn!("Readiness check failed: fn readyz, dependency: {}, error: {}", name, error);
+        }
+    }
+    if readiness.ready {
+        HttpResponse::Ok().json(readiness)
+    } else {
+        HttpResponse::ServiceUnavailable().json(readiness)
+    }
+}
+
+// Turns requests away with 503 while storage is reconnecting instead of
+// letting each one fail on its own
+pub async fn reject_while_degraded(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let degraded = req
+        .app_data::<web::Data<AppState>>()
+        .is_some_and(|state| !state.storage.is_available());
+    if degraded && !ALWAYS_SERVED.contains(&req.path()) {
+        let response = HttpResponse::ServiceUnavailable()
+            .append_header(("Retry-After", DEGRADED_RETRY_AFTER_SECS.to_string()))
+            .json(serde_json::json!({"error": "Service temporarily unavailable, try again shortly"}));
+        return Ok(req.into_response(response).map_into_right_body());
+    }
+    Ok(next.call(req).await?.map_into_left_body())
+}
@@ -0,0 +1,510 @@
+use clap::ValueEnum;
+use futures_util::stream::{self, BoxStream, StreamExt};
+use redis::aio::ConnectionManager;
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+
+// Expired keys nobody asks for again are swept after this many writes
+const SWEEP_INTERVAL: u64 = 1024;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum KvBackend {
+    #[default]
+    Redis,
+    // Not shared between processes, for development and single node deployments
+    Memory,
+}
+
+// Channel name and payload of a published message
+pub type Published = (String, String);
+
+// Short-lived server state (counters, locks, event feeds) kept in Redis, or
+// in process when there is no Redis server. The client opens the dedicated
+// connections that subscriptions need.
+#[derive(Clone)]
+pub enum KvStore {
+    Redis(ConnectionManager, Box<redis::Client>),
+    Memory(Arc<Mutex<MemoryKv>>),
+}
+
+impl KvStore {
+    pub async fn connect(url: &str) -> anyhow::Result<Self> {
+        let client = redis::Client::open(url)?;
+        let connection = ConnectionManager::new(client.clone()).await?;
+        Ok(KvStore::Redis(connection, Box::new(client)))
+    }
+
+    pub async fn open(backend: KvBackend, redis_url: &str) -> anyhow::Result<Self> {
+        match backend {
+            KvBackend::Redis => KvStore::connect(redis_url).await,
+            KvBackend::Memory => Ok(KvStore::memory()),
+        }
+    }
+
+    pub fn memory() -> Self {
+        KvStore::Memory(Arc::new(Mutex::new(MemoryKv::default())))
+    }
+
+    fn locked(memory: &Mutex<MemoryKv>) -> std::sync::MutexGuard<'_, MemoryKv> {
+        memory.lock().unwrap()
+    }
+
+    // Round trip to Redis, always fine in process
+    pub async fn ping(&self) -> anyhow::Result<()> {
+        match self {
+            KvStore::Redis(connection, _) => {
+                let mut connection = connection.clone();
+                let _: String = redis::cmd("PING").query_async(&mut connection).await?;
+                Ok(())
+            }
+            KvStore::Memory(_) => Ok(()),
+        }
+    }
+
+    // Increments a counter whose window restarts on every increment
+    pub async fn incr(&self, key: &str, ttl: Duration) -> anyhow::Result<u64> {
+        match self {
+            KvStore::Redis(connection, _) => {
+                let mut connection = connection.clone();
+                let (count,): (u64,) = redis::pipe()
+                    .atomic()
+                    .incr(key, 1u64)
+                    .expire(key, ttl.as_secs().max(1) as i64)
+                    .ignore()
+                    .query_async(&mut connection)
+                    .await?;
+                Ok(count)
+            }
+            KvStore::Memory(memory) => Self::locked(memory).incr(key, ttl),
+        }
+    }
+
+    pub async fn set_ex(&self, key: &str, value: &str, ttl: Duration) -> anyhow::Result<()> {
+        match self {
+            KvStore::Redis(connection, _) => {
+                let mut connection = connection.clone();
+                let _: () = connection.set_ex(key, value, ttl.as_secs().max(1)).await?;
+                Ok(())
+            }
+            KvStore::Memory(memory) => {
+                Self::locked(memory).insert(key, Value::String(value.to_string()), Some(ttl));
+                Ok(())
+            }
+        }
+    }
+
+    // Reads and deletes a key in one step
+    pub async fn take(&self, key: &str) -> anyhow::Result<Option<String>> {
+        match self {
+            KvStore::Redis(connection, _) => {
+                let mut connection = connection.clone();
+                let (value,): (Option<String>,) = redis::pipe()
+                    .atomic()
+                    .get(key)
+                    .del(key)
+                    .ignore()
+                    .query_async(&mut connection)
+                    .await?;
+                Ok(value)
+            }
+            KvStore::Memory(memory) => {
+                let mut memory = Self::locked(memory);
+                let value = memory.string(key)?;
+                memory.entries.remove(key);
+                Ok(value)
+            }
+        }
+    }
+
+    // Remaining lifetime of a key, None once it has expired
+    pub async fn ttl(&self, key: &str) -> anyhow::Result<Option<Duration>> {
+        match self {
+            KvStore::Redis(connection, _) => {
+                let mut connection = connection.clone();
+                let millis: i64 = redis::cmd("PTTL").arg(key).query_async(&mut connection).await?;
+                if millis > 0 {
+                    Ok(Some(Duration::from_millis(millis as u64)))
+                } else {
+                    Ok(None)
+                }
+            }
+            KvStore::Memory(memory) => Ok(Self::locked(memory)
+                .live(key)
+                .and_then(|entry| entry.expires_at)
+                .map(|expires_at| expires_at.saturating_duration_since(Instant::now()))),
+        }
+    }
+
+    // Restarts the expiry of a key that exists
+    pub async fn expire(&self, key: &str, ttl: Duration) -> anyhow::Result<()> {
+        match self {
+            KvStore::Redis(connection, _) => {
+                let mut connection = connection.clone();
+                let _: () = connection.expire(key, ttl.as_secs().max(1) as i64).await?;
+                Ok(())
+            }
+            KvStore::Memory(memory) => {
+                if let Some(entry) = Self::locked(memory).live(key) {
+                    entry.expires_at = Some(Instant::now() + ttl);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    pub async fn del(&self, keys: &[&str]) -> anyhow::Result<()> {
+        match self {
+            KvStore::Redis(connection, _) => {
+                let mut connection = connection.clone();
+                let _: () = connection.del(keys).await?;
+                Ok(())
+            }
+            KvStore::Memory(memory) => {
+                let mut memory = Self::locked(memory);
+                for key in keys {
+                    memory.entries.remove(*key);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    // Prepends to a list, keeping only the newest max_len entries
+    pub async fn push_capped(&self, key: &str, value: &str, max_len: usize) -> anyhow::Result<()> {
+        match self {
+            KvStore::Redis(connection, _) => {
+                let mut connection = connection.clone();
+                let _: () = redis::pipe()
+                    .atomic()
+                    .lpush(key, value)
+                    .ignore()
+                    .ltrim(key, 0, max_len as isize - 1)
+                    .ignore()
+                    .query_async(&mut connection)
+                    .await?;
+                Ok(())
+            }
+            KvStore::Memory(memory) => {
+                let mut memory = Self::locked(memory);
+                let list = memory.list_mut(key)?;
+                list.push_front(value.to_string());
+                list.truncate(max_len);
+                Ok(())
+            }
+        }
+    }
+
+    pub async fn list(&self, key: &str, limit: usize) -> anyhow::Result<Vec<String>> {
+        match self {
+            KvStore::Redis(connection, _) => {
+                let mut connection = connection.clone();
+                let values: Vec<String> = connection.lrange(key, 0, limit as isize - 1).await?;
+                Ok(values)
+            }
+            KvStore::Memory(memory) => match Self::locked(memory).live(key).map(|entry| &entry.value) {
+                Some(Value::List(list)) => Ok(list.iter().take(limit).cloned().collect()),
+                Some(_) => Err(wrong_type(key)),
+                None => Ok(Vec::new()),
+            },
+        }
+    }
+
+    pub async fn get(&self, key: &str) -> anyhow::Result<Option<String>> {
+        match self {
+            KvStore::Redis(connection, _) => {
+                let mut connection = connection.clone();
+                let value: Option<String> = connection.get(key).await?;
+                Ok(value)
+            }
+            KvStore::Memory(memory) => Self::locked(memory).string(key),
+        }
+    }
+
+    // Adds to a set and restarts the set's expiry
+    pub async fn set_add(&self, key: &str, member: &str, ttl: Duration) -> anyhow::Result<()> {
+        match self {
+            KvStore::Redis(connection, _) => {
+                let mut connection = connection.clone();
+                let _: () = redis::pipe()
+                    .atomic()
+                    .sadd(key, member)
+                    .ignore()
+                    .expire(key, ttl.as_secs().max(1) as i64)
+                    .ignore()
+                    .query_async(&mut connection)
+                    .await?;
+                Ok(())
+            }
+            KvStore::Memory(memory) => {
+                let mut memory = Self::locked(memory);
+                memory.set_mut(key)?.insert(member.to_string());
+                if let Some(entry) = memory.live(key) {
+                    entry.expires_at = Some(Instant::now() + ttl);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    pub async fn set_remove(&self, key: &str, member: &str) -> anyhow::Result<()> {
+        match self {
+            KvStore::Redis(connection, _) => {
+                let mut connection = connection.clone();
+                let _: () = connection.srem(key, member).await?;
+                Ok(())
+            }
+            KvStore::Memory(memory) => {
+                let mut memory = Self::locked(memory);
+                if memory.live(key).is_some() {
+                    memory.set_mut(key)?.remove(member);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    pub async fn set_members(&self, key: &str) -> anyhow::Result<Vec<String>> {
+        match self {
+            KvStore::Redis(connection, _) => {
+                let mut connection = connection.clone();
+                let members: Vec<String> = connection.smembers(key).await?;
+                Ok(members)
+            }
+            KvStore::Memory(memory) => match Self::locked(memory).live(key).map(|entry| &entry.value) {
+                Some(Value::Set(set)) => Ok(set.iter().cloned().collect()),
+                Some(_) => Err(wrong_type(key)),
+                None => Ok(Vec::new()),
+            },
+        }
+    }
+
+    // Adds to a sorted set or moves a member already in it to a new score
+    pub async fn scored_add(&self, key: &str, member: &str, score: u64) -> anyhow::Result<()> {
+        match self {
+            KvStore::Redis(connection, _) => {
+                let mut connection = connection.clone();
+                let _: () = connection.zadd(key, member, score).await?;
+                Ok(())
+            }
+            KvStore::Memory(memory) => {
+                Self::locked(memory).scored_mut(key)?.insert(member.to_string(), score);
+                Ok(())
+            }
+        }
+    }
+
+    pub async fn scored_remove(&self, key: &str, member: &str) -> anyhow::Result<()> {
+        match self {
+            KvStore::Redis(connection, _) => {
+                let mut connection = connection.clone();
+                let _: () = connection.zrem(key, member).await?;
+                Ok(())
+            }
+            KvStore::Memory(memory) => {
+                let mut memory = Self::locked(memory);
+                if memory.live(key).is_some() {
+                    memory.scored_mut(key)?.remove(member);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    // Members scored above min_score
+    pub async fn scored_members(&self, key: &str, min_score: u64) -> anyhow::Result<Vec<String>> {
+        match self {
+            KvStore::Redis(connection, _) => {
+                let mut connection = connection.clone();
+                let members: Vec<String> = connection.zrangebyscore(key, format!("({}", min_score), "+inf").await?;
+                Ok(members)
+            }
+            KvStore::Memory(memory) => match Self::locked(memory).live(key).map(|entry| &entry.value) {
+                Some(Value::Scored(scored)) => {
+                    Ok(scored.iter().filter(|(_, score)| **score > min_score).map(|(member, _)| member.clone()).collect())
+                }
+                Some(_) => Err(wrong_type(key)),
+                None => Ok(Vec::new()),
+            },
+        }
+    }
+
+    // Removes the members scored at or below max_score in one step,
+    // returning how many were removed and how many are left
+    pub async fn scored_prune(&self, key: &str, max_score: u64) -> anyhow::Result<(usize, usize)> {
+        match self {
+            KvStore::Redis(connection, _) => {
+                let mut connection = connection.clone();
+                let (removed, left): (usize, usize) = redis::pipe()
+                    .atomic()
+                    .zrembyscore(key, "-inf", max_score)
+                    .zcard(key)
+                    .query_async(&mut connection)
+                    .await?;
+                Ok((removed, left))
+            }
+            KvStore::Memory(memory) => {
+                let mut memory = Self::locked(memory);
+                if memory.live(key).is_none() {
+                    return Ok((0, 0));
+                }
+                let scored = memory.scored_mut(key)?;
+                let before = scored.len();
+                scored.retain(|_, score| *score > max_score);
+                let left = scored.len();
+                // Like Redis, an emptied sorted set is gone
+                if left == 0 {
+                    memory.entries.remove(key);
+                }
+                Ok((before - left, left))
+            }
+        }
+    }
+
+    pub async fn publish(&self, channel: &str, payload: &str) -> anyhow::Result<()> {
+        match self {
+            KvStore::Redis(connection, _) => {
+                let mut connection = connection.clone();
+                let _: () = connection.publish(channel, payload).await?;
+                Ok(())
+            }
+            KvStore::Memory(memory) => {
+                Self::locked(memory)
+                    .subscribers
+                    .retain(|(prefix, subscriber)| {
+                        !channel.starts_with(prefix.as_str())
+                            || subscriber.send((channel.to_string(), payload.to_string())).is_ok()
+                    });
+                Ok(())
+            }
+        }
+    }
+
+    // Messages published to any channel starting with the prefix. The stream
+    // ends when the connection is lost, anything published until the caller
+    // subscribes again is missed.
+    pub async fn subscribe(&self, prefix: &str) -> anyhow::Result<BoxStream<'static, Published>> {
+        match self {
+            KvStore::Redis(_, client) => {
+                let mut pubsub = client.get_async_connection().await?.into_pubsub();
+                pubsub.psubscribe(format!("{}*", prefix)).await?;
+                Ok(pubsub
+                    .into_on_message()
+                    .filter_map(|message| async move {
+                        let payload: String = message.get_payload().ok()?;
+                        Some((message.get_channel_name().to_string(), payload))
+                    })
+                    .boxed())
+            }
+            KvStore::Memory(memory) => {
+                let (sender, receiver) = unbounded_channel();
+                Self::locked(memory).subscribers.push((prefix.to_string(), sender));
+                Ok(stream::unfold(receiver, |mut receiver| async move {
+                    receiver.recv().await.map(|published| (published, receiver))
+                })
+                .boxed())
+            }
+        }
+    }
+}
+
+enum Value {
+    String(String),
+    List(VecDeque<String>),
+    Set(HashSet<String>),
+    // Member to score
+    Scored(HashMap<String, u64>),
+}
+
+struct Entry {
+    value: Value,
+    expires_at: Option<Instant>,
+}
+
+// Mirrors the Redis commands KvStore uses, expiring keys lazily
+#[derive(Default)]
+pub struct MemoryKv {
+    entries: HashMap<String, Entry>,
+    writes: u64,
+    // Channel prefix each subscriber asked for
+    subscribers: Vec<(String, UnboundedSender<Published>)>,
+}
+
+fn wrong_type(key: &str) -> anyhow::Error {
+    anyhow::anyhow!("key {} holds the wrong kind of value", key)
+}
+
+impl MemoryKv {
+    fn live(&mut self, key: &str) -> Option<&mut Entry> {
+        let now = Instant::now();
+        let expired = self
+            .entries
+            .get(key)
+            .is_some_and(|entry| entry.expires_at.is_some_and(|expires_at| expires_at <= now));
+        if expired {
+            self.entries.remove(key);
+        }
+        self.entries.get_mut(key)
+    }
+
+    fn insert(&mut self, key: &str, value: Value, ttl: Option<Duration>) {
+        self.writes += 1;
+        if self.writes.is_multiple_of(SWEEP_INTERVAL) {
+            let now = Instant::now();
+            self.entries.retain(|_, entry| entry.expires_at.is_none_or(|expires_at| expires_at > now));
+        }
+        let expires_at = ttl.map(|ttl| Instant::now() + ttl);
+        self.entries.insert(key.to_string(), Entry { value, expires_at });
+    }
+
+    fn string(&mut self, key: &str) -> anyhow::Result<Option<String>> {
+        match self.live(key).map(|entry| &entry.value) {
+            Some(Value::String(value)) => Ok(Some(value.clone())),
+            Some(_) => Err(wrong_type(key)),
+            None => Ok(None),
+        }
+    }
+
+    fn incr(&mut self, key: &str, ttl: Duration) -> anyhow::Result<u64> {
+        let count = match self.string(key)? {
+            Some(value) => value.parse::<u64>()? + 1,
+            None => 1,
+        };
+        self.insert(key, Value::String(count.to_string()), Some(ttl));
+        Ok(count)
+    }
+
+    fn list_mut(&mut self, key: &str) -> anyhow::Result<&mut VecDeque<String>> {
+        if self.live(key).is_none() {
+            self.insert(key, Value::List(VecDeque::new()), None);
+        }
+        match self.entries.get_mut(key).map(|entry| &mut entry.value) {
+            Some(Value::List(list)) => Ok(list),
+            _ => Err(wrong_type(key)),
+        }
+    }
+
+    fn scored_mut(&mut self, key: &str) -> anyhow::Result<&mut HashMap<String, u64>> {
+        if self.live(key).is_none() {
+            self.insert(key, Value::Scored(HashMap::new()), None);
+        }
+        match self.entries.get_mut(key).map(|entry| &mut entry.value) {
+            Some(Value::Scored(scored)) => Ok(scored),
+            _ => Err(wrong_type(key)),
+        }
+    }
+
+    fn set_mut(&mut self, key: &str) -> anyhow::Result<&mut HashSet<String>> {
+        if self.live(key).is_none() {
+            self.insert(key, Value::Set(HashSet::new()), None);
+        }
+        match self.entries.get_mut(key).map(|entry| &mut entry.value) {
+            Some(Value::Set(set)) => Ok(set),
+            _ => Err(wrong_type(key)),
+        }
+    }
+}
@@ -0,0 +1,1027 @@
+use actix_cors::Cors;
+use actix_session::{Session, SessionMiddleware};
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceFactory, ServiceRequest, ServiceResponse};
+use actix_web::{get, http, post, web, App, HttpRequest, HttpResponse, Responder};
+use actix_web_lab::middleware::from_fn;
+use bcrypt::{hash, DEFAULT_COST};
+use names::{Generator, Name};
+use serde_json::json;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use surrealdb::sql::Uuid;
+
+// Local packages
+pub mod appstate;
+pub mod audit;
+pub mod auth;
+pub mod bots;
+pub mod cluster;
+pub mod config;
+pub mod health;
+pub mod kv;
+pub mod login_guard;
+pub mod mailer;
+pub mod metrics;
+pub mod one_time_token;
+pub mod password_policy;
+pub mod rate_limit;
+pub mod session_key;
+pub mod session_store;
+pub mod sessions;
+pub mod shutdown;
+pub mod storage;
+pub mod structs;
+pub mod tokens;
+pub mod two_factor;
+pub mod websocket;
+
+use appstate::AppState;
+use audit::{audit_events, AuditEvent, AuditKind, AuditLog};
+use cluster::Cluster;
+use auth::{authenticate, log_in, pending_login_user, session_id, session_user, start_pending_login, EPOCH_KEY};
+use config::{AccountsConfig, Config};
+use health::{healthz, readyz, reject_while_degraded};
+use kv::KvStore;
+use login_guard::LoginGuard;
+use mailer::Email;
+use metrics::{metrics_endpoint, Metrics};
+use one_time_token::TokenPurpose;
+use password_policy::PasswordPolicy;
+use rate_limit::{too_many_requests, RateAction, RateLimiter};
+use session_key::{reissue_rotated_session_cookie, SessionKeys, SESSION_COOKIE_NAME};
+use session_store::SessionBackend;
+use sessions::{ClientInfo, SessionKind, SessionTracker};
+use shutdown::Drain;
+use storage::{unique_violation, InstrumentedStorage, Storage, LOGIN_INDEX, USERNAME_INDEX};
+use black_signal_protocol::*;
+use structs::{
+    AccountState, ConnectionState, ForgotPasswordForm, LoginForm, PasswordChangeForm, RefreshTokenForm,
+    ResetPasswordForm, Room, TokenRequestForm, TwoFactorCodeForm, TwoFactorDisableForm, TwoFactorLoginForm, UserData, UserKind, VerifyEmailForm,
+};
+use tokens::{Redemption, TokenService};
+use two_factor::{generate_recovery_codes, TwoFactor};
+use websocket::*;
+
+const PASSWORD_RESET_TTL: Duration = Duration::from_secs(60 * 60);
+const EMAIL_VERIFICATION_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+const TEST_LOGIN: &str = "test@gmail.com";
+// Where everyone is added on signup
+const MAIN_ROOM_ID: Uuid = Uuid(uuid::Uuid::from_u128(1));
+// Generated usernames are retried this often before signup gives up
+const MAX_USERNAME_ATTEMPTS: u32 = 3;
+
+async fn send_verification_email(state: &AppState, user: &UserData) -> bool {
+    let token = match one_time_token::issue(&state.kv, TokenPurpose::EmailVerification, user.user_id, EMAIL_VERIFICATION_TTL).await {
+        Ok(token) => token,
+        Err(e) => {
+            log::error!("Failed to issue verification token: fn send_verification_email, error: {:?}", e);
+            return false;
+        }
+    };
+    let email = Email {
+        to: user.login.clone(),
+        subject: "Verify your BlackSignal email address".to_string(),
+        body: format!(
+            "Welcome to BlackSignal! Confirm your email address with the link below, it expires in {} hours.\n\n{}/verify_email?token={}",
+            EMAIL_VERIFICATION_TTL.as_secs() / 3600,
+            state.public_url,
+            token
+        ),
+    };
+    match state.mailer.send(email).await {
+        Ok(()) => true,
+        Err(e) => {
+            log::error!("Failed to send verification email: fn send_verification_email, error: {:?}", e);
+            false
+        }
+    }
+}
+
+#[get("/logout")]
+async fn logout(session: Session, state: web::Data<AppState>) -> impl Responder {
+    if let (Some(user), Some(session_id)) = (session_user(&session, &state).await, session_id(&session)) {
+        state.sessions.revoke(&user.user_id, &session_id).await;
+        state.disconnect_session(&user.user_id, session_id, "Logged out".to_string()).await;
+    }
+    session.purge();
+    HttpResponse::Found()
+        .append_header(("LOCATION", "/login"))
+        .finish()
+}
+
+#[post("/create_login")]
+async fn create_login_action(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    form: web::Json<LoginForm>,
+    session: Session,
+) -> impl Responder {
+    let ip = req.peer_addr().map(|addr| addr.ip());
+    if let Err(retry_after) = state.rate_limiter.check(None, ip, RateAction::CreateLogin) {
+        return too_many_requests(retry_after);
+    }
+    let login = form.into_inner();
+    let violations = state.password_policy.violations(&login.password, &login.username);
+    if !violations.is_empty() {
+        return HttpResponse::Ok().json(json!(LoginErrorMessage::new(violations.join(". "))));
+    }
+    if state.valid_user_credentials(&login).await {
+        let mut generator = Generator::with_naming(Name::Numbered);
+
+        let mut user_data = UserData {
+            user_id: Uuid::new_v4(),
+            hashed_password: hash(login.password.clone(), DEFAULT_COST).unwrap(),
+            login: login.username,
+            username: generator.next().unwrap().replace('-', ""),
+            status: ConnectionState::Online,
+            rooms: vec![state.main_room_id],
+            is_admin: false,
+            session_epoch: 0,
+            account_state: AccountState::PendingVerification,
+            two_factor: None,
+            kind: UserKind::Human,
+            owner_id: None,
+            api_tokens: Vec::new(),
+        };
+        // The unique indexes catch a login taken since the check above, and
+        // the rare generated username that is already in use
+        let mut attempts = 0;
+        loop {
+            let e = match state.storage.create_user(&user_data).await {
+                Ok(()) => break,
+                Err(e) => e,
+            };
+            attempts += 1;
+            match unique_violation(&e) {
+                Some(USERNAME_INDEX) if attempts < MAX_USERNAME_ATTEMPTS => {
+                    user_data.username = generator.next().unwrap().replace('-', "");
+                }
+                Some(LOGIN_INDEX) => {
+                    return HttpResponse::Ok().json(json!(LoginErrorMessage::new(
+                        "Invalid Please enter an email and a password".to_string()
+                    )));
+                }
+                _ => {
+                    log::error!(
+                        "Failed to get user data: fn create_login_action, error: {:?}",
+                        e
+                    );
+                    return HttpResponse::InternalServerError()
+                        .body("Internal server error: Failed to create user data.");
+                }
+            }
+        }
+
+        if let Err(e) = state.storage.add_room_member(&state.main_room_id, &user_data.user_id).await {
+            log::error!("Error adding to room: {:?}", e);
+            return HttpResponse::InternalServerError().body(
+                "Internal server error: Failed to add user to room in db: fn create_login_action",
+            );
+        }
+
+        let message =
+            UserMessage::NewUser(NewUserMessage::new(user_data.user_id.into(), user_data.username.clone()));
+        let serialized_message = serde_json::to_string(&message).unwrap();
+
+        state
+            .broadcast_message(serialized_message, &state.main_room_id, &user_data.user_id)
+            .await;
+        state
+            .audit
+            .record(AuditEvent::new(AuditKind::Signup).actor(user_data.user_id).ip(ip).detail("login", user_data.login.clone()))
+            .await;
+        state
+            .audit
+            .record(AuditEvent::new(AuditKind::RoomMemberAdded).actor(user_data.user_id).room(state.main_room_id).ip(ip))
+            .await;
+        // The account stays usable, the client is sent where it can ask for
+        // another mail through /resend_verification
+        let location = if send_verification_email(&state, &user_data).await {
+            "/"
+        } else {
+            "/?verification_email=failed"
+        };
+        log_in(&req, &session, &state, &user_data).await.unwrap();
+        HttpResponse::Found()
+            .append_header(("LOCATION", location))
+            .finish()
+    } else {
+        HttpResponse::Ok().json(json!(LoginErrorMessage::new(
+            "Invalid Please enter an email and a password".to_string()
+        )))
+    }
+}
+
+// The account a failed login was aimed at is its target, when it exists
+async fn audit_failed_login(state: &AppState, login: &str, user_id: Option<Uuid>, ip: Option<IpAddr>, method: &str) {
+    let mut event = AuditEvent::new(AuditKind::LoginFailed).ip(ip).detail("login", login).detail("method", method);
+    if let Some(user_id) = user_id {
+        event = event.target(user_id);
+    }
+    state.audit.record(event).await;
+}
+
+#[post("/login")]
+async fn login_action(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    form: web::Json<LoginForm>,
+    session: Session,
+) -> impl Responder {
+    let ip = req.peer_addr().map(|addr| addr.ip());
+    if let Err(retry_after) = state.rate_limiter.check(None, ip, RateAction::Login) {
+        return too_many_requests(retry_after);
+    }
+    let login = form.into_inner();
+    if let Err(retry_after) = state.login_guard.check(&login.username, ip).await {
+        return too_many_requests(retry_after);
+    }
+    match state.authenticate_user(&login).await {
+        Ok(user_data) if user_data.two_factor_enabled() => {
+            // The guard is only reset once the second factor is passed as well
+            match start_pending_login(&session, &user_data) {
+                Ok(()) => HttpResponse::Ok().json(json!({"two_factor_required": true})),
+                Err(_) => HttpResponse::Found()
+                    .append_header(("LOCATION", "/login"))
+                    .finish(),
+            }
+        }
+        Ok(user_data) => {
+            state.login_guard.record_success(&login.username).await;
+            state
+                .audit
+                .record(AuditEvent::new(AuditKind::Login).actor(user_data.user_id).ip(ip).detail("method", "password"))
+                .await;
+            if log_in(&req, &session, &state, &user_data).await.is_ok() {
+                HttpResponse::Found()
+                    .append_header(("LOCATION", "/"))
+                    .finish()
+            } else {
+                HttpResponse::Found()
+                    .append_header(("LOCATION", "/login"))
+                    .finish()
+            }
+        }
+        Err(user_id) => {
+            state.login_guard.record_failure(&login.username, ip).await;
+            audit_failed_login(&state, &login.username, user_id, ip, "password").await;
+            HttpResponse::Ok().json(json!(LoginErrorMessage::new(
+                "Invalid Please enter an email and a password".to_string()
+            )))
+        }
+    }
+}
+
+#[post("/login/2fa")]
+async fn two_factor_login(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    form: web::Json<TwoFactorLoginForm>,
+    session: Session,
+) -> impl Responder {
+    let ip = req.peer_addr().map(|addr| addr.ip());
+    if let Err(retry_after) = state.rate_limiter.check(None, ip, RateAction::TwoFactorLogin) {
+        return too_many_requests(retry_after);
+    }
+    let user = match pending_login_user(&session, &state).await {
+        Some(user) => user,
+        None => return HttpResponse::Unauthorized().json(json!({"error": "Log in with your password first"})),
+    };
+    if let Err(retry_after) = state.login_guard.check(&user.login, ip).await {
+        return too_many_requests(retry_after);
+    }
+    if !state.verify_second_factor(&user, form.code.as_deref(), form.recovery_code.as_deref()).await {
+        state.login_guard.record_failure(&user.login, ip).await;
+        state
+            .audit
+            .record(AuditEvent::new(AuditKind::LoginFailed).target(user.user_id).ip(ip).detail("method", "two_factor"))
+            .await;
+        return HttpResponse::Ok().json(json!(LoginErrorMessage::new("Invalid two-factor code".to_string())));
+    }
+    state.login_guard.record_success(&user.login).await;
+    state
+        .audit
+        .record(AuditEvent::new(AuditKind::Login).actor(user.user_id).ip(ip).detail("method", "two_factor"))
+        .await;
+    if log_in(&req, &session, &state, &user).await.is_ok() {
+        HttpResponse::Found()
+            .append_header(("LOCATION", "/"))
+            .finish()
+    } else {
+        HttpResponse::Found()
+            .append_header(("LOCATION", "/login"))
+            .finish()
+    }
+}
+
+// Token login for clients without a cookie jar, answering with a short-lived
+// access token and a refresh token
+#[post("/token")]
+async fn issue_token(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    form: web::Json<TokenRequestForm>,
+) -> impl Responder {
+    let ip = req.peer_addr().map(|addr| addr.ip());
+    if let Err(retry_after) = state.rate_limiter.check(None, ip, RateAction::Login) {
+        return too_many_requests(retry_after);
+    }
+    let form = form.into_inner();
+    let login = &form.login.username;
+    if let Err(retry_after) = state.login_guard.check(login, ip).await {
+        return too_many_requests(retry_after);
+    }
+    let user = match state.authenticate_user(&form.login).await {
+        Ok(user) => user,
+        Err(user_id) => {
+            state.login_guard.record_failure(login, ip).await;
+            audit_failed_login(&state, login, user_id, ip, "token").await;
+            return HttpResponse::Unauthorized().json(json!({"error": "Invalid login or password"}));
+        }
+    };
+    if user.two_factor_enabled() {
+        if form.code.is_none() && form.recovery_code.is_none() {
+            return HttpResponse::Unauthorized().json(json!({"error": "Two-factor code required", "two_factor_required": true}));
+        }
+        if !state.verify_second_factor(&user, form.code.as_deref(), form.recovery_code.as_deref()).await {
+            state.login_guard.record_failure(login, ip).await;
+            state
+                .audit
+                .record(AuditEvent::new(AuditKind::LoginFailed).target(user.user_id).ip(ip).detail("method", "token"))
+                .await;
+            return HttpResponse::Unauthorized().json(json!({"error": "Invalid two-factor code", "two_factor_required": true}));
+        }
+    }
+    state.login_guard.record_success(login).await;
+    state
+        .audit
+        .record(AuditEvent::new(AuditKind::Login).actor(user.user_id).ip(ip).detail("method", "token"))
+        .await;
+    match state.tokens.issue(&user).await {
+        Some(tokens) => {
+            state.sessions.record(&user.user_id, tokens.session_id, SessionKind::Token, ClientInfo::from_request(&req)).await;
+            HttpResponse::Ok().json(tokens)
+        }
+        None => HttpResponse::InternalServerError().json(json!({"error": "Internal Error"})),
+    }
+}
+
+#[post("/token/refresh")]
+async fn refresh_token(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    form: web::Json<RefreshTokenForm>,
+) -> impl Responder {
+    let ip = req.peer_addr().map(|addr| addr.ip());
+    if let Err(retry_after) = state.rate_limiter.check(None, ip, RateAction::TokenRefresh) {
+        return too_many_requests(retry_after);
+    }
+    let invalid_token = || HttpResponse::Unauthorized().json(json!({"error": "Refresh token is invalid or has expired"}));
+    let (user_id, token_session_id, session_epoch) = match state.tokens.redeem_refresh(&form.refresh_token).await {
+        Redemption::Granted { user_id, session_id, session_epoch } => (user_id, session_id, session_epoch),
+        Redemption::Reused { user_id, session_id } => {
+            // Either the client or whoever copied the token already used it,
+            // there is no telling which, so neither keeps the session
+            log::warn!("Refresh token reused, revoking its session: fn refresh_token, session: {}", session_id);
+            if state.sessions.revoke(&user_id, &session_id).await {
+                state.disconnect_session(&user_id, session_id, "Session revoked".to_string()).await;
+            }
+            return invalid_token();
+        }
+        Redemption::Invalid => return invalid_token(),
+    };
+    // A password change since the grant ends the token session too
+    let user = match state.get_user(&user_id).await {
+        Some(user) if user.session_epoch == session_epoch => user,
+        _ => return invalid_token(),
+    };
+    if state.sessions.is_revoked(&token_session_id).await {
+        return invalid_token();
+    }
+    match state.tokens.rotate(&user, token_session_id).await {
+        Some(tokens) => {
+            state.sessions.touch(&user.user_id, token_session_id, SessionKind::Token, ClientInfo::from_request(&req)).await;
+            HttpResponse::Ok().json(tokens)
+        }
+        None => HttpResponse::InternalServerError().json(json!({"error": "Internal Error"})),
+    }
+}
+
+#[post("/token/revoke")]
+async fn revoke_token(req: HttpRequest, state: web::Data<AppState>, form: web::Json<RefreshTokenForm>) -> impl Responder {
+    let ip = req.peer_addr().map(|addr| addr.ip());
+    if let Err(retry_after) = state.rate_limiter.check(None, ip, RateAction::Login) {
+        return too_many_requests(retry_after);
+    }
+    // Revoking the refresh token ends its whole token session, like logging out
+    let (user_id, token_session_id) = match state.tokens.redeem_refresh(&form.refresh_token).await {
+        Redemption::Granted { user_id, session_id, .. } | Redemption::Reused { user_id, session_id } => (user_id, session_id),
+        Redemption::Invalid => return HttpResponse::Ok().json(json!({"message": "Token revoked"})),
+    };
+    if !state.sessions.revoke(&user_id, &token_session_id).await {
+        return HttpResponse::InternalServerError().json(json!({"error": "Internal Error"}));
+    }
+    state.disconnect_session(&user_id, token_session_id, "Session revoked".to_string()).await;
+    HttpResponse::Ok().json(json!({"message": "Token revoked"}))
+}
+
+#[post("/2fa/enroll")]
+async fn two_factor_enroll(req: HttpRequest, session: Session, state: web::Data<AppState>) -> impl Responder {
+    let user = match authenticate(&req, &session, &state).await {
+        Some(auth) => auth.user,
+        None => return HttpResponse::Unauthorized().json(json!({"error": "Not logged in"})),
+    };
+    let ip = req.peer_addr().map(|addr| addr.ip());
+    if let Err(retry_after) = state.rate_limiter.check(Some(user.user_id), ip, RateAction::TwoFactorSetup) {
+        return too_many_requests(retry_after);
+    }
+    if user.two_factor_enabled() {
+        return HttpResponse::BadRequest().json(json!({"error": "Two-factor authentication is already enabled"}));
+    }
+    // Starting over replaces any secret that was never confirmed
+    let two_factor = TwoFactor::pending();
+    let provisioning_uri = match two_factor.provisioning_uri(&state.two_factor.issuer, &user.login) {
+        Some(uri) => uri,
+        None => return HttpResponse::InternalServerError().json(json!({"error": "Internal Error"})),
+    };
+    if !state.set_two_factor(&user.user_id, Some(two_factor.clone())).await {
+        return HttpResponse::InternalServerError().json(json!({"error": "Internal DB Error"}));
+    }
+    HttpResponse::Ok().json(json!({"secret": two_factor.secret, "provisioning_uri": provisioning_uri}))
+}
+
+#[post("/2fa/confirm")]
+async fn two_factor_confirm(
+    req: HttpRequest,
+    form: web::Json<TwoFactorCodeForm>,
+    session: Session,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let user = match authenticate(&req, &session, &state).await {
+        Some(auth) => auth.user,
+        None => return HttpResponse::Unauthorized().json(json!({"error": "Not logged in"})),
+    };
+    let ip = req.peer_addr().map(|addr| addr.ip());
+    if let Err(retry_after) = state.rate_limiter.check(Some(user.user_id), ip, RateAction::TwoFactorSetup) {
+        return too_many_requests(retry_after);
+    }
+    let mut two_factor = match user.two_factor {
+        Some(two_factor) if !two_factor.enabled => two_factor,
+        Some(_) => return HttpResponse::BadRequest().json(json!({"error": "Two-factor authentication is already enabled"})),
+        None => return HttpResponse::BadRequest().json(json!({"error": "Start enrollment first"})),
+    };
+    let now = chrono::Utc::now().timestamp().max(0) as u64;
+    match two_factor.verify_code(&state.two_factor.issuer, &user.login, &form.code, now) {
+        Some(step) => two_factor.last_used_step = step,
+        None => return HttpResponse::BadRequest().json(json!({"error": "Invalid two-factor code"})),
+    }
+    let (recovery_codes, hashes) = generate_recovery_codes();
+    two_factor.enabled = true;
+    two_factor.recovery_codes = hashes;
+    if !state.set_two_factor(&user.user_id, Some(two_factor)).await {
+        return HttpResponse::InternalServerError().json(json!({"error": "Internal DB Error"}));
+    }
+    // The only time the recovery codes are ever shown
+    HttpResponse::Ok().json(json!({
+        "message": "Two-factor authentication enabled",
+        "recovery_codes": recovery_codes,
+    }))
+}
+
+#[post("/2fa/disable")]
+async fn two_factor_disable(
+    req: HttpRequest,
+    form: web::Json<TwoFactorDisableForm>,
+    session: Session,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let user = match authenticate(&req, &session, &state).await {
+        Some(auth) => auth.user,
+        None => return HttpResponse::Unauthorized().json(json!({"error": "Not logged in"})),
+    };
+    let ip = req.peer_addr().map(|addr| addr.ip());
+    if let Err(retry_after) = state.rate_limiter.check(Some(user.user_id), ip, RateAction::TwoFactorSetup) {
+        return too_many_requests(retry_after);
+    }
+    if !user.two_factor_enabled() {
+        return HttpResponse::BadRequest().json(json!({"error": "Two-factor authentication is not enabled"}));
+    }
+    if let Err(retry_after) = state.login_guard.check(&user.login, ip).await {
+        return too_many_requests(retry_after);
+    }
+    let form = form.into_inner();
+    let password_ok = bcrypt::verify(&form.password, &user.hashed_password).unwrap_or(false);
+    if !password_ok || !state.verify_second_factor(&user, form.code.as_deref(), form.recovery_code.as_deref()).await {
+        state.login_guard.record_failure(&user.login, ip).await;
+        return HttpResponse::Forbidden().json(json!({"error": "Password or two-factor code is incorrect"}));
+    }
+    state.login_guard.record_success(&user.login).await;
+    if state.set_two_factor(&user.user_id, None).await {
+        HttpResponse::Ok().json(json!({"message": "Two-factor authentication disabled"}))
+    } else {
+        HttpResponse::InternalServerError().json(json!({"error": "Internal DB Error"}))
+    }
+}
+
+#[get("/admin/lockouts")]
+async fn lockout_events(req: HttpRequest, state: web::Data<AppState>, session: Session) -> impl Responder {
+    let user = match authenticate(&req, &session, &state).await {
+        Some(auth) => auth.user,
+        None => return HttpResponse::Unauthorized().json(json!({"error": "Not logged in"})),
+    };
+    if !state.is_admin(&user) {
+        return HttpResponse::Forbidden().json(json!({"error": "Admin access required"}));
+    }
+    let ip = req.peer_addr().map(|addr| addr.ip());
+    state
+        .audit
+        .record(AuditEvent::new(AuditKind::AdminAction).actor(user.user_id).ip(ip).detail("action", "view_lockouts"))
+        .await;
+    HttpResponse::Ok().json(state.login_guard.lockout_events(100).await)
+}
+
+#[post("/change_password")]
+async fn change_password(
+    req: HttpRequest,
+    form: web::Json<PasswordChangeForm>,
+    session: Session,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let auth = match authenticate(&req, &session, &state).await {
+        Some(auth) => auth,
+        None => return HttpResponse::Unauthorized().json(json!({"error": "Not logged in"})),
+    };
+    let user = auth.user;
+    let ip = req.peer_addr().map(|addr| addr.ip());
+    if let Err(retry_after) = state.rate_limiter.check(Some(user.user_id), ip, RateAction::ChangePassword) {
+        return too_many_requests(retry_after);
+    }
+    // Guessing the current password here is guessing a login password
+    if let Err(retry_after) = state.login_guard.check(&user.login, ip).await {
+        return too_many_requests(retry_after);
+    }
+    let form = form.into_inner();
+    if !bcrypt::verify(&form.current_password, &user.hashed_password).unwrap_or(false) {
+        state.login_guard.record_failure(&user.login, ip).await;
+        return HttpResponse::Forbidden().json(json!({"error": "Current password is incorrect"}));
+    }
+    state.login_guard.record_success(&user.login).await;
+
+    let violations = state.password_policy.violations(&form.new_password, &user.login);
+    if !violations.is_empty() {
+        return HttpResponse::BadRequest().json(json!({"error": "Password rejected", "violations": violations}));
+    }
+    let hashed_password = match hash(form.new_password, DEFAULT_COST) {
+        Ok(hashed) => hashed,
+        Err(e) => {
+            log::error!("Failed to hash password: fn change_password, error: {:?}", e);
+            return HttpResponse::InternalServerError().json(json!({"error": "Internal Error"}));
+        }
+    };
+    match state.update_password(&user, hashed_password, auth.session_id).await {
+        Some(session_epoch) => {
+            // Bearer clients have to log in again for a token with the new epoch
+            if !auth.bearer && session.insert(EPOCH_KEY, session_epoch).is_err() {
+                session.purge();
+            }
+            HttpResponse::Ok().json(json!({"message": "Password changed successfully"}))
+        }
+        None => HttpResponse::InternalServerError().json(json!({"error": "Internal DB Error"})),
+    }
+}
+
+#[post("/forgot_password")]
+async fn forgot_password(
+    req: HttpRequest,
+    form: web::Json<ForgotPasswordForm>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let ip = req.peer_addr().map(|addr| addr.ip());
+    if let Err(retry_after) = state.rate_limiter.check(None, ip, RateAction::ForgotPassword) {
+        return too_many_requests(retry_after);
+    }
+    // The lookup and the mail happen after answering, so neither the
+    // response nor how long it takes tells whether the login exists
+    let login = form.into_inner().login;
+    let task_state = state.clone();
+    state.drain.spawn(async move { send_reset_email(&task_state, &login).await });
+    HttpResponse::Ok().json(json!({"message": "If the account exists a reset link has been sent"}))
+}
+
+async fn send_reset_email(state: &AppState, login: &str) {
+    let user = match state.get_user_by_login(login).await {
+        Some(user) => user,
+        None => return,
+    };
+    let token = match one_time_token::issue(&state.kv, TokenPurpose::PasswordReset, user.user_id, PASSWORD_RESET_TTL).await {
+        Ok(token) => token,
+        Err(e) => {
+            log::error!("Failed to issue reset token: fn send_reset_email, error: {:?}", e);
+            return;
+        }
+    };
+    let email = Email {
+        to: user.login,
+        subject: "Reset your BlackSignal password".to_string(),
+        body: format!(
+            "Use the link below to choose a new password. It expires in {} minutes and can only be used once.\n\n{}/reset_password?token={}\n\nIf you did not ask for this you can ignore this email.",
+            PASSWORD_RESET_TTL.as_secs() / 60,
+            state.public_url,
+            token
+        ),
+    };
+    if let Err(e) = state.mailer.send(email).await {
+        log::error!("Failed to send reset email: fn send_reset_email, error: {:?}", e);
+    }
+}
+
+#[post("/reset_password")]
+async fn reset_password(
+    req: HttpRequest,
+    form: web::Json<ResetPasswordForm>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let ip = req.peer_addr().map(|addr| addr.ip());
+    if let Err(retry_after) = state.rate_limiter.check(None, ip, RateAction::ResetPassword) {
+        return too_many_requests(retry_after);
+    }
+    let form = form.into_inner();
+    let invalid_token = || HttpResponse::BadRequest().json(json!({"error": "Reset link is invalid or has expired"}));
+    let user = match one_time_token::peek(&state.kv, TokenPurpose::PasswordReset, &form.token).await {
+        Ok(Some(user_id)) => match state.get_user(&user_id).await {
+            Some(user) => user,
+            None => return invalid_token(),
+        },
+        Ok(None) => return invalid_token(),
+        Err(e) => {
+            log::error!("Failed to look up reset token: fn reset_password, error: {:?}", e);
+            return HttpResponse::InternalServerError().json(json!({"error": "Internal Error"}));
+        }
+    };
+    // The link stays usable until a password is accepted
+    let violations = state.password_policy.violations(&form.new_password, &user.login);
+    if !violations.is_empty() {
+        return HttpResponse::BadRequest().json(json!({"error": "Password rejected", "violations": violations}));
+    }
+    let hashed_password = match hash(form.new_password, DEFAULT_COST) {
+        Ok(hashed) => hashed,
+        Err(e) => {
+            log::error!("Failed to hash password: fn reset_password, error: {:?}", e);
+            return HttpResponse::InternalServerError().json(json!({"error": "Internal Error"}));
+        }
+    };
+    // Spent right before the update, only one of two concurrent resets wins
+    match one_time_token::consume(&state.kv, TokenPurpose::PasswordReset, &form.token).await {
+        Ok(Some(user_id)) if user_id == user.user_id => {}
+        Ok(_) => return invalid_token(),
+        Err(e) => {
+            log::error!("Failed to redeem reset token: fn reset_password, error: {:?}", e);
+            return HttpResponse::InternalServerError().json(json!({"error": "Internal Error"}));
+        }
+    }
+    match state.update_password(&user, hashed_password, None).await {
+        Some(_) => {
+            state.login_guard.record_success(&user.login).await;
+            HttpResponse::Ok().json(json!({"message": "Password reset successfully"}))
+        }
+        None => HttpResponse::InternalServerError().json(json!({"error": "Internal DB Error"})),
+    }
+}
+
+#[post("/verify_email")]
+async fn verify_email(
+    req: HttpRequest,
+    form: web::Json<VerifyEmailForm>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let ip = req.peer_addr().map(|addr| addr.ip());
+    if let Err(retry_after) = state.rate_limiter.check(None, ip, RateAction::VerifyEmail) {
+        return too_many_requests(retry_after);
+    }
+    match one_time_token::consume(&state.kv, TokenPurpose::EmailVerification, &form.token).await {
+        Ok(Some(user_id)) => {
+            if state.activate_user(&user_id).await {
+                HttpResponse::Ok().json(json!({"message": "Email verified"}))
+            } else {
+                HttpResponse::InternalServerError().json(json!({"error": "Internal DB Error"}))
+            }
+        }
+        Ok(None) => HttpResponse::BadRequest().json(json!({"error": "Verification link is invalid or has expired"})),
+        Err(e) => {
+            log::error!("Failed to redeem verification token: fn verify_email, error: {:?}", e);
+            HttpResponse::InternalServerError().json(json!({"error": "Internal Error"}))
+        }
+    }
+}
+
+#[post("/resend_verification")]
+async fn resend_verification(
+    req: HttpRequest,
+    session: Session,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let user = match authenticate(&req, &session, &state).await {
+        Some(auth) => auth.user,
+        None => return HttpResponse::Unauthorized().json(json!({"error": "Not logged in"})),
+    };
+    let ip = req.peer_addr().map(|addr| addr.ip());
+    if let Err(retry_after) = state.rate_limiter.check(Some(user.user_id), ip, RateAction::ResendVerification) {
+        return too_many_requests(retry_after);
+    }
+    if user.account_state == AccountState::Active {
+        return HttpResponse::BadRequest().json(json!({"error": "Email is already verified"}));
+    }
+    if send_verification_email(&state, &user).await {
+        HttpResponse::Ok().json(json!({"message": "Verification email sent"}))
+    } else {
+        HttpResponse::InternalServerError().json(json!({"error": "Failed to send verification email"}))
+    }
+}
+
+#[post("/change_username")]
+async fn change_username(
+    req: HttpRequest,
+    username_change: web::Json<UserMessage>,
+    session: Session,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let arc_state: Arc<AppState> = state.clone().into_inner();
+    if let UserMessage::UsernameChange(message) = username_change.into_inner() {
+        let user_data = match authenticate(&req, &session, &state).await {
+            Some(auth) => auth.user,
+            None => {
+                return HttpResponse::BadRequest()
+                    .json(json!({"error": "Failed to get user_id from session"}))
+            }
+        };
+        if user_data.account_state != AccountState::Active {
+            return HttpResponse::Forbidden().json(json!({"error": "Verify your email address first"}));
+        }
+        let user_id = user_data.user_id;
+        let ip = req.peer_addr().map(|addr| addr.ip());
+        if let Err(retry_after) = state.rate_limiter.check(Some(user_id), ip, RateAction::ChangeUsername) {
+            return too_many_requests(retry_after);
+        }
+        match check_and_update_username(
+            user_id,
+            message.new_username.clone(),
+            ip,
+            arc_state,
+            UserMessage::UsernameChange(message),
+        )
+        .await
+        {
+            Ok(response) => response,
+            Err(e) => HttpResponse::InternalServerError().json(json!({"error": e.to_string()})),
+        }
+    } else {
+        HttpResponse::BadRequest()
+            .json(json!({"error": "Invalid message format for username change."}))
+    }
+}
+
+// Makes sure the main room exists, along with the test user when seeding
+// it is enabled, returning the room
+async fn seed_test_data(storage: &dyn Storage, accounts: &AccountsConfig) -> Option<Uuid> {
+    // A database that survives restarts may already have the user, looked up
+    // by login since logins are unique
+    let existing = match storage.get_user_by_login(TEST_LOGIN).await {
+        Ok(existing) => existing,
+        Err(e) => {
+            log::error!("Failed to look up test user: fn main, error: {:?}", e);
+            return None;
+        }
+    };
+    // Databases seeded before the main room had a fixed id keep theirs
+    let main_room_id = existing.as_ref().and_then(|test_user| test_user.rooms.first().copied()).unwrap_or(MAIN_ROOM_ID);
+    let test_user = match existing {
+        // Older versions made the test user an admin
+        Some(test_user) if test_user.is_admin || test_user.rooms.is_empty() => {
+            if test_user.is_admin {
+                log::warn!("Taking admin rights from the seeded test user: fn main, login: {}", TEST_LOGIN);
+            }
+            // Rooms are only set when a user is created, so the user is
+            // written again rather than a room being added
+            let test_user = UserData { rooms: vec![main_room_id], is_admin: false, ..test_user };
+            let replaced = async {
+                storage.delete_user(&test_user.user_id).await?;
+                storage.create_user(&test_user).await
+            };
+            if let Err(e) = replaced.await {
+                log::error!("Failed to rewrite test user: fn main, error: {:?}", e);
+                return None;
+            }
+            Some(test_user)
+        }
+        Some(test_user) => Some(test_user),
+        None if accounts.seed_test_user => {
+            let hashed_password = match hash("password", DEFAULT_COST) {
+                Ok(hashed) => hashed,
+                Err(e) => {
+                    log::error!("Failed to hash test user password: fn main, error: {:?}", e);
+                    return None;
+                }
+            };
+            let test_user = UserData {
+                user_id: Uuid::new_v4(),
+                login: TEST_LOGIN.to_string(),
+                username: "test".to_string(),
+                hashed_password,
+                status: ConnectionState::Online,
+                rooms: vec![main_room_id],
+                is_admin: false,
+                session_epoch: 0,
+                account_state: AccountState::Active,
+                two_factor: None,
+                kind: UserKind::Human,
+                owner_id: None,
+                api_tokens: Vec::new(),
+            };
+            if let Err(e) = storage.create_user(&test_user).await {
+                log::error!("Failed to create test user data: fn main, error: {:?}", e);
+                return None;
+            }
+            Some(test_user)
+        }
+        None => None,
+    };
+
+    // Missing on a fresh database or when creating it failed on an earlier start
+    match storage.get_room(&main_room_id).await {
+        Ok(Some(_)) => return Some(main_room_id),
+        Ok(None) => {}
+        Err(e) => {
+            log::error!("Failed to look up main room: fn main, error: {:?}", e);
+            return None;
+        }
+    }
+    let users = test_user.iter().map(|test_user| test_user.user_id).collect();
+
+    let main_room = Room {
+        name: "main".to_string(),
+        room_id: main_room_id,
+        users,
+    };
+    if let Err(e) = storage.create_room(&main_room).await {
+        log::error!("Failed to create room data: fn main, error: {:?}", e);
+        return None;
+    }
+    Some(main_room_id)
+}
+
+// Connects the configured backends and seeds the test data
+pub async fn init_state(config: &Config, session_store: SessionBackend) -> Option<web::Data<AppState>> {
+    let storage = match storage::connect(&config.database).await {
+        Ok(storage) => storage,
+        Err(e) => {
+            log::error!("Failed to set up storage: fn main, error: {:#}", e);
+            return None;
+        }
+    };
+    let kv = match KvStore::open(config.kv.backend, &config.redis.url).await {
+        Ok(connected) => connected,
+        Err(e) => {
+            log::error!("Failed to set up kv store: fn main, error: {:?}", e);
+            return None;
+        }
+    };
+    build_state(config, storage, kv, session_store).await
+}
+
+// Instances built on the same storage and kv store act as one cluster
+pub async fn build_state(
+    config: &Config,
+    storage: Arc<dyn Storage>,
+    kv: KvStore,
+    session_store: SessionBackend,
+) -> Option<web::Data<AppState>> {
+    let password_policy = match PasswordPolicy::load(config.password_policy.clone()) {
+        Ok(policy) => policy,
+        Err(e) => {
+            log::error!("Failed to load password policy: fn main, error: {:?}", e);
+            return None;
+        }
+    };
+    let mailer = match config.mail.build() {
+        Ok(mailer) => mailer,
+        Err(e) => {
+            log::error!("Failed to set up mailer: fn main, error: {:?}", e);
+            return None;
+        }
+    };
+    let main_room_id = match seed_test_data(storage.as_ref(), &config.accounts).await {
+        Some(main_room_id) => main_room_id,
+        None => return None,
+    };
+    let metrics = Metrics::new(&config.metrics);
+    let rate_limiter = match RateLimiter::new(config.rate_limit.clone(), metrics.clone()) {
+        Ok(rate_limiter) => rate_limiter,
+        Err(e) => {
+            log::error!("Failed to set up rate limiter: fn main, error: {:?}", e);
+            return None;
+        }
+    };
+    let storage: Arc<dyn Storage> = Arc::new(InstrumentedStorage::new(storage, metrics.clone()));
+    let audit = match AuditLog::open(storage.clone(), &config.audit) {
+        Ok(audit) => audit,
+        Err(e) => {
+            log::error!("Failed to open audit log file: fn main, error: {:?}", e);
+            return None;
+        }
+    };
+    let actor_registry = Arc::new(Mutex::new(HashMap::new()));
+    let cluster = Cluster::new(kv.clone(), actor_registry.clone());
+    cluster.listen();
+    cluster.reap_presence(storage.clone());
+
+    Some(web::Data::new(AppState {
+        storage,
+        channels: Arc::new(Mutex::new(HashMap::new())),
+        main_room_id,
+        actor_registry,
+        rate_limiter,
+        login_guard: LoginGuard::new(kv.clone(), config.login_guard.clone(), metrics.clone()),
+        password_policy,
+        kv: kv.clone(),
+        mailer,
+        public_url: config.server.public_url.clone(),
+        two_factor: config.two_factor.clone(),
+        tokens: TokenService::new(kv.clone(), config.tokens.clone()),
+        sessions: SessionTracker::new(kv.clone(), session_store),
+        admin_logins: config.accounts.admin_logins.iter().cloned().collect(),
+        cluster,
+        websocket: config.websocket.clone(),
+        shutdown: config.shutdown.clone(),
+        drain: Drain::default(),
+        metrics,
+        audit,
+    }))
+}
+
+// The whole application, shared by the server binary and the integration tests
+pub fn app(
+    state: web::Data<AppState>,
+    session_keys: web::Data<SessionKeys>,
+    session_store: SessionBackend,
+    cors_origin: &str,
+) -> App<
+    impl ServiceFactory<
+        ServiceRequest,
+        Config = (),
+        Response = ServiceResponse<impl MessageBody>,
+        Error = actix_web::Error,
+        InitError = (),
+    >,
+> {
+    let cors = Cors::default()
+        .allowed_origin(cors_origin) // Specify the allowed origin
+        .allowed_methods(vec!["GET", "POST", "DELETE"]) // Specify the allowed HTTP methods
+        .allowed_headers(vec![http::header::AUTHORIZATION, http::header::ACCEPT])
+        .allowed_header(http::header::CONTENT_TYPE)
+        .supports_credentials() // If your requests include credentials like cookies
+        .max_age(3600); // Cache the CORS preflight requests
+    App::new()
+        .wrap(from_fn(reject_while_degraded))
+        .wrap(cors)
+        .wrap(
+            SessionMiddleware::builder(session_store, session_keys.current.clone())
+                .cookie_name(SESSION_COOKIE_NAME.to_string())
+                .build(),
+        )
+        .wrap(from_fn(reissue_rotated_session_cookie))
+        .app_data(session_keys)
+        .app_data(state)
+        .service(healthz)
+        .service(readyz)
+        .service(metrics_endpoint)
+        .service(login_action)
+        .service(create_login_action)
+        .service(logout)
+        .service(change_username)
+        .service(lockout_events)
+        .service(audit_events)
+        .service(change_password)
+        .service(forgot_password)
+        .service(reset_password)
+        .service(verify_email)
+        .service(resend_verification)
+        .service(two_factor_login)
+        .service(two_factor_enroll)
+        .service(two_factor_confirm)
+        .service(two_factor_disable)
+        .service(issue_token)
+        .service(refresh_token)
+        .service(revoke_token)
+        .service(bots::create_bot)
+        .service(bots::list_bots)
+        .service(bots::delete_bot)
+        .service(bots::create_api_token)
+        .service(bots::revoke_api_token)
+        .service(bots::post_to_room)
+        .service(sessions::list_sessions)
+        .service(sessions::revoke_session)
+        .route("/ws/", web::get().to(ws_index))
+}
@@ -0,0 +1,229 @@
+use crate::kv::KvStore;
+use crate::metrics::Metrics;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::net::IpAddr;
+use std::time::Duration;
+
+const LOCKOUT_EVENTS_KEY: &str = "login_lockout_events";
+const MAX_LOCKOUT_EVENTS: usize = 1000;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct LoginGuardConfig {
+    // Failures allowed before back-off starts
+    pub free_attempts: u64,
+    pub base_backoff_secs: u64,
+    pub max_backoff_secs: u64,
+    pub account_lockout_threshold: u64,
+    pub ip_lockout_threshold: u64,
+    pub lockout_secs: u64,
+    // Failures older than this are forgotten
+    pub failure_window_secs: u64,
+}
+
+impl Default for LoginGuardConfig {
+    fn default() -> Self {
+        LoginGuardConfig {
+            free_attempts: 3,
+            base_backoff_secs: 1,
+            max_backoff_secs: 300,
+            account_lockout_threshold: 10,
+            ip_lockout_threshold: 50,
+            lockout_secs: 900,
+            failure_window_secs: 3600,
+        }
+    }
+}
+
+impl LoginGuardConfig {
+    // Overrides settings with LOGIN_GUARD_* environment variables
+    pub fn apply_env(&mut self) {
+        let overrides: [(&str, &mut u64); 7] = [
+            ("LOGIN_GUARD_FREE_ATTEMPTS", &mut self.free_attempts),
+            ("LOGIN_GUARD_BASE_BACKOFF_SECS", &mut self.base_backoff_secs),
+            ("LOGIN_GUARD_MAX_BACKOFF_SECS", &mut self.max_backoff_secs),
+            ("LOGIN_GUARD_ACCOUNT_LOCKOUT_THRESHOLD", &mut self.account_lockout_threshold),
+            ("LOGIN_GUARD_IP_LOCKOUT_THRESHOLD", &mut self.ip_lockout_threshold),
+            ("LOGIN_GUARD_LOCKOUT_SECS", &mut self.lockout_secs),
+            ("LOGIN_GUARD_FAILURE_WINDOW_SECS", &mut self.failure_window_secs),
+        ];
+        for (name, field) in overrides {
+            if let Some(value) = env::var(name).ok().and_then(|value| value.parse().ok()) {
+                *field = value;
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum LockoutTarget {
+    Account,
+    Ip,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LockoutEvent {
+    pub target: LockoutTarget,
+    pub subject: String,
+    pub failures: u64,
+    pub ip: Option<String>,
+    pub locked_at: DateTime<Utc>,
+    pub locked_until: DateTime<Utc>,
+}
+
+// Failed login tracking per account and per source address. Redis errors fail
+// open so that an outage does not lock everyone out.
+pub struct LoginGuard {
+    kv: KvStore,
+    config: LoginGuardConfig,
+    metrics: Metrics,
+}
+
+impl LoginGuard {
+    pub fn new(kv: KvStore, config: LoginGuardConfig, metrics: Metrics) -> Self {
+        LoginGuard { kv, config, metrics }
+    }
+
+    // Err holds how long the caller has to wait before another attempt
+    pub async fn check(&self, login: &str, ip: Option<IpAddr>) -> Result<(), Duration> {
+        let mut keys = vec![
+            lock_key(LockoutTarget::Account, &account_subject(login)),
+            backoff_key(&account_subject(login)),
+        ];
+        if let Some(ip) = ip {
+            keys.push(lock_key(LockoutTarget::Ip, &ip.to_string()));
+        }
+        let mut wait = Duration::ZERO;
+        for key in keys {
+            match self.kv.ttl(&key).await {
+                Ok(Some(remaining)) => wait = wait.max(remaining),
+                Ok(None) => {}
+                Err(e) => log::error!("Failed to read login lock: fn check, error: {:?}", e),
+            }
+        }
+        if wait > Duration::ZERO {
+            Err(wait)
+        } else {
+            Ok(())
+        }
+    }
+
+    pub async fn record_failure(&self, login: &str, ip: Option<IpAddr>) {
+        self.metrics.login_failures.inc();
+        let window = Duration::from_secs(self.config.failure_window_secs);
+        let account = account_subject(login);
+        match self.kv.incr(&failures_key(LockoutTarget::Account, &account), window).await {
+            Ok(failures) if failures >= self.config.account_lockout_threshold => {
+                self.lock(LockoutTarget::Account, &account, failures, ip).await;
+            }
+            Ok(failures) if failures > self.config.free_attempts => {
+                let exponent = (failures - self.config.free_attempts - 1).min(32) as u32;
+                let backoff = self
+                    .config
+                    .base_backoff_secs
+                    .saturating_mul(2u64.saturating_pow(exponent))
+                    .min(self.config.max_backoff_secs);
+                if let Err(e) = self.kv.set_ex(&backoff_key(&account), "1", Duration::from_secs(backoff)).await {
+                    log::error!("Failed to set login back-off: fn record_failure, error: {:?}", e);
+                }
+            }
+            Ok(_) => {}
+            Err(e) => log::error!("Failed to count login failure: fn record_failure, error: {:?}", e),
+        }
+
+        if let Some(ip) = ip {
+            let address = ip.to_string();
+            match self.kv.incr(&failures_key(LockoutTarget::Ip, &address), window).await {
+                Ok(failures) if failures >= self.config.ip_lockout_threshold => {
+                    self.lock(LockoutTarget::Ip, &address, failures, Some(ip)).await;
+                }
+                Ok(_) => {}
+                Err(e) => log::error!("Failed to count login failure: fn record_failure, error: {:?}", e),
+            }
+        }
+    }
+
+    // A successful login clears the account's history but not the address's,
+    // so an attacker cannot reset their counter with their own account.
+    pub async fn record_success(&self, login: &str) {
+        let account = account_subject(login);
+        let failures = failures_key(LockoutTarget::Account, &account);
+        let backoff = backoff_key(&account);
+        if let Err(e) = self.kv.del(&[&failures, &backoff]).await {
+            log::error!("Failed to clear login failures: fn record_success, error: {:?}", e);
+        }
+    }
+
+    pub async fn lockout_events(&self, limit: usize) -> Vec<LockoutEvent> {
+        match self.kv.list(LOCKOUT_EVENTS_KEY, limit).await {
+            Ok(events) => events
+                .iter()
+                .filter_map(|event| serde_json::from_str(event).ok())
+                .collect(),
+            Err(e) => {
+                log::error!("Failed to read lockout events: fn lockout_events, error: {:?}", e);
+                Vec::new()
+            }
+        }
+    }
+
+    async fn lock(&self, target: LockoutTarget, subject: &str, failures: u64, ip: Option<IpAddr>) {
+        let duration = Duration::from_secs(self.config.lockout_secs);
+        if let Err(e) = self.kv.set_ex(&lock_key(target, subject), "1", duration).await {
+            log::error!("Failed to lock out login: fn lock, error: {:?}", e);
+            return;
+        }
+        if let Err(e) = self.kv.del(&[&failures_key(target, subject)]).await {
+            log::error!("Failed to reset login failures: fn lock, error: {:?}", e);
+        }
+
+        let locked_at = Utc::now();
+        let event = LockoutEvent {
+            target,
+            subject: subject.to_string(),
+            failures,
+            ip: ip.map(|ip| ip.to_string()),
+            locked_at,
+            locked_until: locked_at + chrono::Duration::seconds(duration.as_secs() as i64),
+        };
+        log::warn!(
+            "Locked out {:?} {} after {} failed logins from {:?}",
+            target,
+            subject,
+            failures,
+            event.ip
+        );
+        match serde_json::to_string(&event) {
+            Ok(serialized) => {
+                if let Err(e) = self.kv.push_capped(LOCKOUT_EVENTS_KEY, &serialized, MAX_LOCKOUT_EVENTS).await {
+                    log::error!("Failed to record lockout event: fn lock, error: {:?}", e);
+                }
+            }
+            Err(e) => log::error!("Failed to serialize lockout event: fn lock, error: {:?}", e),
+        }
+    }
+}
+
+fn account_subject(login: &str) -> String {
+    login.trim().to_lowercase()
+}
+
+fn failures_key(target: LockoutTarget, subject: &str) -> String {
+    match target {
+        LockoutTarget::Account => format!("login_failures:account:{}", subject),
+        LockoutTarget::Ip => format!("login_failures:ip:{}", subject),
+    }
+}
+
+fn lock_key(target: LockoutTarget, subject: &str) -> String {
+    match target {
+        LockoutTarget::Account => format!("login_lock:account:{}", subject),
+        LockoutTarget::Ip => format!("login_lock:ip:{}", subject),
+    }
+}
+
+fn backoff_key(subject: &str) -> String {
+    format!("login_backoff:account:{}", subject)
+}
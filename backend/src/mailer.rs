@@ -0,0 +1,153 @@
+use async_trait::async_trait;
+use chrono::Utc;
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::path::PathBuf;
+use std::sync::Arc;
+use surrealdb::sql::Uuid;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+#[async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, email: Email) -> anyhow::Result<()>;
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "transport", rename_all = "lowercase")]
+pub enum MailerConfig {
+    // Writes each email as a JSON file, for development and tests
+    Outbox { dir: String },
+    Smtp {
+        host: String,
+        port: u16,
+        username: String,
+        password: String,
+        from: String,
+    },
+}
+
+impl Default for MailerConfig {
+    fn default() -> Self {
+        MailerConfig::Outbox { dir: "outbox".to_string() }
+    }
+}
+
+impl MailerConfig {
+    fn default_smtp() -> Self {
+        MailerConfig::Smtp {
+            host: "localhost".to_string(),
+            port: 587,
+            username: String::new(),
+            password: String::new(),
+            from: "BlackSignal <noreply@localhost>".to_string(),
+        }
+    }
+
+    // MAIL_TRANSPORT selects "smtp" or "outbox", configured by SMTP_* or MAIL_OUTBOX_DIR
+    pub fn apply_env(&mut self) {
+        match env::var("MAIL_TRANSPORT").as_deref() {
+            Ok("smtp") if !matches!(self, MailerConfig::Smtp { .. }) => *self = MailerConfig::default_smtp(),
+            Ok("outbox") if !matches!(self, MailerConfig::Outbox { .. }) => *self = MailerConfig::default(),
+            _ => {}
+        }
+        match self {
+            MailerConfig::Outbox { dir } => {
+                if let Ok(value) = env::var("MAIL_OUTBOX_DIR") {
+                    *dir = value;
+                }
+            }
+            MailerConfig::Smtp { host, port, username, password, from } => {
+                if let Ok(value) = env::var("SMTP_HOST") {
+                    *host = value;
+                }
+                if let Some(value) = env::var("SMTP_PORT").ok().and_then(|value| value.parse().ok()) {
+                    *port = value;
+                }
+                if let Ok(value) = env::var("SMTP_USERNAME") {
+                    *username = value;
+                }
+                if let Ok(value) = env::var("SMTP_PASSWORD") {
+                    *password = value;
+                }
+                if let Ok(value) = env::var("SMTP_FROM") {
+                    *from = value;
+                }
+            }
+        }
+    }
+
+    pub fn build(&self) -> anyhow::Result<Arc<dyn Mailer>> {
+        match self {
+            MailerConfig::Outbox { dir } => Ok(Arc::new(OutboxMailer::new(dir.into())?)),
+            MailerConfig::Smtp { host, port, username, password, from } => Ok(Arc::new(SmtpMailer::new(
+                host,
+                *port,
+                Credentials::new(username.clone(), password.clone()),
+                from.parse()?,
+            )?)),
+        }
+    }
+}
+
+pub struct OutboxMailer {
+    dir: PathBuf,
+}
+
+impl OutboxMailer {
+    pub fn new(dir: PathBuf) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+        Ok(OutboxMailer { dir })
+    }
+}
+
+#[async_trait]
+impl Mailer for OutboxMailer {
+    async fn send(&self, email: Email) -> anyhow::Result<()> {
+        // Timestamp first so the outbox lists in the order mail was sent
+        let file_name = format!("{}-{}.json", Utc::now().format("%Y%m%dT%H%M%S%.6f"), Uuid::new_v4().0);
+        let contents = serde_json::to_vec_pretty(&email)?;
+        actix_web::rt::task::spawn_blocking({
+            let path = self.dir.join(file_name);
+            move || std::fs::write(path, contents)
+        })
+        .await??;
+        Ok(())
+    }
+}
+
+pub struct SmtpMailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+impl SmtpMailer {
+    pub fn new(host: &str, port: u16, credentials: Credentials, from: Mailbox) -> anyhow::Result<Self> {
+        let transport = AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(host)?
+            .port(port)
+            .credentials(credentials)
+            .build();
+        Ok(SmtpMailer { transport, from })
+    }
+}
+
+#[async_trait]
+impl Mailer for SmtpMailer {
+    async fn send(&self, email: Email) -> anyhow::Result<()> {
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(email.to.parse()?)
+            .subject(email.subject)
+            .body(email.body)?;
+        self.transport.send(message).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,138 @@
+use crate::appstate::AppState;
+use crate::auth::bearer_token;
+use crate::rate_limit::RateAction;
+use actix_web::{get, web, HttpRequest, HttpResponse, Responder};
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry, TextEncoder,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::env;
+use std::time::Instant;
+
+const NAMESPACE: &str = "black_signal";
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct MetricsConfig {
+    // Scrapers send it as `Authorization: Bearer <token>`; without one
+    // /metrics is not served at all
+    pub token: Option<String>,
+}
+
+impl MetricsConfig {
+    // Overrides settings with METRICS_* environment variables
+    pub fn apply_env(&mut self) {
+        if let Ok(token) = env::var("METRICS_TOKEN") {
+            self.token = Some(token).filter(|token| !token.is_empty());
+        }
+    }
+}
+
+// Everything exported on /metrics. Each instance has its own registry so
+// several apps in one process, as in the integration tests, do not collide.
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+    pub sockets_connected: IntGauge,
+    messages_sent: IntCounter,
+    messages_broadcast: IntCounter,
+    broadcast_duration: Histogram,
+    db_query_duration: HistogramVec,
+    rate_limit_rejections: IntCounterVec,
+    pub login_failures: IntCounter,
+    token: Option<String>,
+}
+
+impl Metrics {
+    pub fn new(config: &MetricsConfig) -> Self {
+        let registry = Registry::new_custom(Some(NAMESPACE.to_string()), None).unwrap();
+        let metrics = Metrics {
+            sockets_connected: IntGauge::new("websockets_connected", "WebSockets open on this instance").unwrap(),
+            // Totals across rooms, a label per room would expose room ids and
+            // grow with every room ever created
+            messages_sent: IntCounter::new("messages_sent_total", "Chat messages stored").unwrap(),
+            messages_broadcast: IntCounter::new("messages_broadcast_total", "Frames fanned out to the members of a room")
+                .unwrap(),
+            broadcast_duration: Histogram::with_opts(HistogramOpts::new(
+                "broadcast_duration_seconds",
+                "Time to look up a room and fan a frame out to it",
+            ))
+            .unwrap(),
+            db_query_duration: HistogramVec::new(
+                HistogramOpts::new("db_query_duration_seconds", "Storage latency by operation"),
+                &["operation"],
+            )
+            .unwrap(),
+            rate_limit_rejections: IntCounterVec::new(
+                Opts::new("rate_limit_rejections_total", "Requests refused by the rate limiter"),
+                &["action"],
+            )
+            .unwrap(),
+            login_failures: IntCounter::new("login_failures_total", "Failed password and second factor checks").unwrap(),
+            token: config.token.clone(),
+            registry,
+        };
+        metrics.register();
+        metrics
+    }
+
+    // Names are fixed and unique, so registration cannot fail
+    fn register(&self) {
+        self.registry.register(Box::new(self.sockets_connected.clone())).unwrap();
+        self.registry.register(Box::new(self.messages_sent.clone())).unwrap();
+        self.registry.register(Box::new(self.messages_broadcast.clone())).unwrap();
+        self.registry.register(Box::new(self.broadcast_duration.clone())).unwrap();
+        self.registry.register(Box::new(self.db_query_duration.clone())).unwrap();
+        self.registry.register(Box::new(self.rate_limit_rejections.clone())).unwrap();
+        self.registry.register(Box::new(self.login_failures.clone())).unwrap();
+    }
+
+    pub fn message_sent(&self) {
+        self.messages_sent.inc();
+    }
+
+    pub fn message_broadcast(&self, started: Instant) {
+        self.messages_broadcast.inc();
+        self.broadcast_duration.observe(started.elapsed().as_secs_f64());
+    }
+
+    // Digests are compared so the check takes as long for every wrong token
+    fn authorized(&self, token: Option<&str>) -> bool {
+        match (&self.token, token) {
+            (Some(expected), Some(token)) => Sha256::digest(expected) == Sha256::digest(token),
+            _ => false,
+        }
+    }
+
+    pub fn db_query(&self, operation: &str, started: Instant) {
+        self.db_query_duration.with_label_values(&[operation]).observe(started.elapsed().as_secs_f64());
+    }
+
+    pub fn rate_limited(&self, action: RateAction) {
+        self.rate_limit_rejections.with_label_values(&[&format!("{:?}", action)]).inc();
+    }
+
+    // Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut buffer = Vec::new();
+        if let Err(e) = TextEncoder::new().encode(&self.registry.gather(), &mut buffer) {
+            log::error!("Failed to encode metrics: fn render, error: {:?}", e);
+        }
+        String::from_utf8(buffer).unwrap_or_default()
+    }
+}
+
+#[get("/metrics")]
+pub async fn metrics_endpoint(req: HttpRequest, state: web::Data<AppState>) -> impl Responder {
+    if state.metrics.token.is_none() {
+        return HttpResponse::NotFound().json(json!({"error": "Metrics are disabled"}));
+    }
+    if !state.metrics.authorized(bearer_token(&req).as_deref()) {
+        return HttpResponse::Unauthorized().json(json!({"error": "Invalid metrics token"}));
+    }
+    HttpResponse::Ok()
+        .content_type(TextEncoder::new().format_type())
+        .body(state.metrics.render())
+}
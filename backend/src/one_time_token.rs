@@ -0,0 +1,95 @@
+use crate::kv::KvStore;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use std::fmt::Write;
+use std::time::Duration;
+use surrealdb::sql::Uuid;
+
+#[derive(Clone, Copy, Debug)]
+pub enum TokenPurpose {
+    PasswordReset,
+    EmailVerification,
+}
+
+impl TokenPurpose {
+    fn prefix(&self) -> &'static str {
+        match self {
+            TokenPurpose::PasswordReset => "password_reset",
+            TokenPurpose::EmailVerification => "email_verification",
+        }
+    }
+}
+
+// Random token handed to the user, only its hash is stored
+pub fn generate_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    to_hex(&bytes)
+}
+
+pub fn hash_token(token: &str) -> String {
+    to_hex(&Sha256::digest(token.as_bytes()))
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::with_capacity(bytes.len() * 2), |mut hex, byte| {
+        let _ = write!(hex, "{:02x}", byte);
+        hex
+    })
+}
+
+pub fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+fn token_key(purpose: TokenPurpose, token: &str) -> String {
+    token_hash_key(purpose, &hash_token(token))
+}
+
+fn token_hash_key(purpose: TokenPurpose, token_hash: &str) -> String {
+    format!("{}:{}", purpose.prefix(), token_hash)
+}
+
+// Hashes of every token a user has outstanding, so they can all be revoked
+fn user_tokens_key(purpose: TokenPurpose, user_id: &Uuid) -> String {
+    format!("{}_tokens:{}", purpose.prefix(), user_id.0)
+}
+
+pub async fn issue(kv: &KvStore, purpose: TokenPurpose, user_id: Uuid, ttl: Duration) -> anyhow::Result<String> {
+    let token = generate_token();
+    kv.set_ex(&token_key(purpose, &token), &user_id.0.to_string(), ttl).await?;
+    kv.set_add(&user_tokens_key(purpose, &user_id), &hash_token(&token), ttl).await?;
+    Ok(token)
+}
+
+// Invalidates every token issued to the user, e.g. older reset links once
+// the password has changed
+pub async fn revoke_all(kv: &KvStore, purpose: TokenPurpose, user_id: &Uuid) -> anyhow::Result<()> {
+    let index = user_tokens_key(purpose, user_id);
+    let keys: Vec<String> = kv
+        .set_members(&index)
+        .await?
+        .iter()
+        .map(|token_hash| token_hash_key(purpose, token_hash))
+        .chain([index.clone()])
+        .collect();
+    kv.del(&keys.iter().map(String::as_str).collect::<Vec<_>>()).await
+}
+
+// The user a token was issued to, leaving it usable
+pub async fn peek(kv: &KvStore, purpose: TokenPurpose, token: &str) -> anyhow::Result<Option<Uuid>> {
+    let user_id = kv.get(&token_key(purpose, token)).await?;
+    Ok(user_id.and_then(|user_id| Uuid::try_from(user_id.as_str()).ok()))
+}
+
+// Redeems a token, after which it can never be used again
+pub async fn consume(kv: &KvStore, purpose: TokenPurpose, token: &str) -> anyhow::Result<Option<Uuid>> {
+    let user_id = kv.take(&token_key(purpose, token)).await?;
+    Ok(user_id.and_then(|user_id| Uuid::try_from(user_id.as_str()).ok()))
+}
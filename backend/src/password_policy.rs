@@ -0,0 +1,84 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+
+// bcrypt ignores everything past the 72nd byte
+const BCRYPT_MAX_BYTES: usize = 72;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct PasswordPolicyConfig {
+    pub min_length: usize,
+    pub max_length: usize,
+    // Newline separated list of known breached passwords
+    pub breached_list_path: Option<String>,
+}
+
+impl Default for PasswordPolicyConfig {
+    fn default() -> Self {
+        PasswordPolicyConfig {
+            min_length: 8,
+            max_length: BCRYPT_MAX_BYTES,
+            breached_list_path: None,
+        }
+    }
+}
+
+impl PasswordPolicyConfig {
+    // Overrides settings with PASSWORD_* environment variables
+    pub fn apply_env(&mut self) {
+        if let Some(min_length) = env::var("PASSWORD_MIN_LENGTH").ok().and_then(|value| value.parse().ok()) {
+            self.min_length = min_length;
+        }
+        if let Some(max_length) = env::var("PASSWORD_MAX_LENGTH").ok().and_then(|value| value.parse().ok()) {
+            self.max_length = max_length;
+        }
+        if let Ok(path) = env::var("PASSWORD_BREACHED_LIST") {
+            self.breached_list_path = Some(path);
+        }
+    }
+}
+
+pub struct PasswordPolicy {
+    config: PasswordPolicyConfig,
+    breached: HashSet<String>,
+}
+
+impl PasswordPolicy {
+    pub fn load(config: PasswordPolicyConfig) -> anyhow::Result<Self> {
+        let breached = match &config.breached_list_path {
+            Some(path) => fs::read_to_string(path)?
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .map(String::from)
+                .collect(),
+            None => HashSet::new(),
+        };
+        log::info!("Loaded {} breached passwords", breached.len());
+        Ok(PasswordPolicy { config, breached })
+    }
+
+    // Every rule the password breaks, empty when it is acceptable
+    pub fn violations(&self, password: &str, login: &str) -> Vec<String> {
+        let mut violations = Vec::new();
+        let length = password.chars().count();
+        if length < self.config.min_length {
+            violations.push(format!("Password must be at least {} characters", self.config.min_length));
+        }
+        if length > self.config.max_length || password.len() > BCRYPT_MAX_BYTES {
+            violations.push(format!(
+                "Password must be at most {} characters",
+                self.config.max_length.min(BCRYPT_MAX_BYTES)
+            ));
+        }
+        if password.eq_ignore_ascii_case(login) {
+            violations.push("Password must not be the same as the login".to_string());
+        }
+        if self.breached.contains(password) {
+            violations.push("Password appears in a list of breached passwords".to_string());
+        }
+        violations
+    }
+}
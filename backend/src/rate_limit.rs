@@ -0,0 +1,296 @@
+use crate::metrics::Metrics;
+use actix_web::HttpResponse;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+use std::env;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use surrealdb::sql::Uuid;
+
+// Buckets are only pruned once the map grows past this many entries
+const PRUNE_THRESHOLD: usize = 10_000;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RateAction {
+    Message,
+    Deletion,
+    CreateRoom,
+    ChangeRoom,
+    UserRemoval,
+    Connect,
+    Login,
+    CreateLogin,
+    ChangeUsername,
+    ChangePassword,
+    ForgotPassword,
+    ResetPassword,
+    VerifyEmail,
+    ResendVerification,
+    TwoFactorSetup,
+    TwoFactorLogin,
+    TokenRefresh,
+    ManageBots,
+}
+
+impl RateAction {
+    const ALL: [RateAction; 18] = [
+        RateAction::Message,
+        RateAction::Deletion,
+        RateAction::CreateRoom,
+        RateAction::ChangeRoom,
+        RateAction::UserRemoval,
+        RateAction::Connect,
+        RateAction::Login,
+        RateAction::CreateLogin,
+        RateAction::ChangeUsername,
+        RateAction::ChangePassword,
+        RateAction::ForgotPassword,
+        RateAction::ResetPassword,
+        RateAction::VerifyEmail,
+        RateAction::ResendVerification,
+        RateAction::TwoFactorSetup,
+        RateAction::TwoFactorLogin,
+        RateAction::TokenRefresh,
+        RateAction::ManageBots,
+    ];
+
+    fn default_cost(&self) -> u32 {
+        match self {
+            RateAction::Message => 1,
+            RateAction::Deletion => 1,
+            RateAction::CreateRoom => 10,
+            RateAction::ChangeRoom => 2,
+            RateAction::UserRemoval => 5,
+            RateAction::Connect => 5,
+            RateAction::Login => 10,
+            RateAction::CreateLogin => 20,
+            RateAction::ChangeUsername => 10,
+            RateAction::ChangePassword => 20,
+            RateAction::ForgotPassword => 30,
+            RateAction::ResetPassword => 10,
+            RateAction::VerifyEmail => 10,
+            RateAction::ResendVerification => 30,
+            RateAction::TwoFactorSetup => 10,
+            RateAction::TwoFactorLogin => 10,
+            RateAction::TokenRefresh => 5,
+            RateAction::ManageBots => 10,
+        }
+    }
+}
+
+#[derive(Hash, PartialEq, Eq, Clone, Debug)]
+enum RateKey {
+    User(Uuid),
+    Ip(IpAddr),
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct BucketConfig {
+    pub capacity: u32,
+    pub refill_per_second: f64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    pub user: BucketConfig,
+    pub ip: BucketConfig,
+    pub costs: HashMap<RateAction, u32>,
+    // Environment variables that could not be parsed, reported by problems
+    #[serde(skip)]
+    invalid_env: Vec<String>,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            user: BucketConfig { capacity: 100, refill_per_second: 10.0 },
+            // Many users can share an address behind NAT
+            ip: BucketConfig { capacity: 300, refill_per_second: 30.0 },
+            costs: HashMap::new(),
+            invalid_env: Vec::new(),
+        }
+    }
+}
+
+impl RateLimitConfig {
+    // Overrides settings with RATE_LIMIT_* environment variables, costs
+    // are given as a list like "Message=1,Login=20"
+    pub fn apply_env(&mut self) {
+        let mut invalid = Vec::new();
+        let mut parse_capacity = |name: &str, capacity: &mut u32| {
+            if let Ok(value) = env::var(name) {
+                match value.parse() {
+                    Ok(parsed) => *capacity = parsed,
+                    Err(_) => invalid.push(format!("{} must be a whole number, got {:?}", name, value)),
+                }
+            }
+        };
+        parse_capacity("RATE_LIMIT_USER_CAPACITY", &mut self.user.capacity);
+        parse_capacity("RATE_LIMIT_IP_CAPACITY", &mut self.ip.capacity);
+        let mut parse_refill = |name: &str, refill: &mut f64| {
+            if let Ok(value) = env::var(name) {
+                match value.parse() {
+                    Ok(parsed) => *refill = parsed,
+                    Err(_) => invalid.push(format!("{} must be a number, got {:?}", name, value)),
+                }
+            }
+        };
+        parse_refill("RATE_LIMIT_USER_REFILL_PER_SECOND", &mut self.user.refill_per_second);
+        parse_refill("RATE_LIMIT_IP_REFILL_PER_SECOND", &mut self.ip.refill_per_second);
+        if let Ok(costs) = env::var("RATE_LIMIT_COSTS") {
+            for entry in costs.split(',') {
+                let parsed = entry.split_once('=').and_then(|(action, cost)| {
+                    let action = serde_json::from_value(json!(action.trim())).ok()?;
+                    Some((action, cost.trim().parse().ok()?))
+                });
+                match parsed {
+                    Some((action, cost)) => {
+                        self.costs.insert(action, cost);
+                    }
+                    None => invalid.push(format!("RATE_LIMIT_COSTS has an invalid entry {:?}", entry)),
+                }
+            }
+        }
+        self.invalid_env = invalid;
+    }
+
+    pub fn cost(&self, action: RateAction) -> u32 {
+        self.costs.get(&action).copied().unwrap_or_else(|| action.default_cost())
+    }
+
+    // Buckets that never refill, and costs no bucket can hold, would refuse
+    // requests forever. Environment variables that did not parse are listed
+    // too rather than ignored.
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = self.invalid_env.clone();
+        for (name, bucket) in [("rate_limit.user", &self.user), ("rate_limit.ip", &self.ip)] {
+            if bucket.capacity == 0 || !bucket.refill_per_second.is_finite() || bucket.refill_per_second <= 0.0 {
+                problems.push(format!("{} needs a positive capacity and refill_per_second", name));
+            }
+        }
+        let capacity = self.user.capacity.min(self.ip.capacity);
+        for action in RateAction::ALL {
+            if self.cost(action) > capacity {
+                problems.push(format!(
+                    "rate_limit.costs.{:?} is {}, more than a bucket holds ({})",
+                    action,
+                    self.cost(action),
+                    capacity
+                ));
+            }
+        }
+        problems
+    }
+}
+
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn full(config: &BucketConfig) -> Self {
+        TokenBucket { tokens: config.capacity as f64, last_refill: Instant::now() }
+    }
+
+    fn refill(&mut self, config: &BucketConfig, now: Instant) {
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * config.refill_per_second).min(config.capacity as f64);
+        self.last_refill = now;
+    }
+
+    fn time_until(&self, config: &BucketConfig, cost: f64) -> Duration {
+        if self.tokens >= cost {
+            return Duration::ZERO;
+        }
+        if config.refill_per_second <= 0.0 {
+            return Duration::MAX;
+        }
+        Duration::from_secs_f64((cost - self.tokens) / config.refill_per_second)
+    }
+}
+
+// Token buckets shared by every socket and HTTP endpoint, one per user and one
+// per source address
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    buckets: Mutex<HashMap<RateKey, TokenBucket>>,
+    metrics: Metrics,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig, metrics: Metrics) -> anyhow::Result<Self> {
+        let problems = config.problems();
+        if !problems.is_empty() {
+            anyhow::bail!("invalid rate limits: {}", problems.join(", "));
+        }
+        Ok(RateLimiter { config, buckets: Mutex::new(HashMap::new()), metrics })
+    }
+
+    // Takes the action's cost from every applicable bucket, or from none of
+    // them and returns how long to wait before retrying.
+    pub fn check(&self, user_id: Option<Uuid>, ip: Option<IpAddr>, action: RateAction) -> Result<(), Duration> {
+        let cost = self.config.cost(action) as f64;
+        let keys: Vec<RateKey> = user_id
+            .map(RateKey::User)
+            .into_iter()
+            .chain(ip.map(RateKey::Ip))
+            .collect();
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() > PRUNE_THRESHOLD {
+            self.prune(&mut buckets, now);
+        }
+
+        let mut retry_after = Duration::ZERO;
+        for key in &keys {
+            let config = self.bucket_config(key);
+            let bucket = buckets.entry(key.clone()).or_insert_with(|| TokenBucket::full(config));
+            bucket.refill(config, now);
+            retry_after = retry_after.max(bucket.time_until(config, cost));
+        }
+        if retry_after > Duration::ZERO {
+            self.metrics.rate_limited(action);
+            return Err(retry_after);
+        }
+        for key in &keys {
+            if let Some(bucket) = buckets.get_mut(key) {
+                bucket.tokens -= cost;
+            }
+        }
+        Ok(())
+    }
+
+    fn bucket_config(&self, key: &RateKey) -> &BucketConfig {
+        match key {
+            RateKey::User(_) => &self.config.user,
+            RateKey::Ip(_) => &self.config.ip,
+        }
+    }
+
+    // Full buckets hold no state worth keeping
+    fn prune(&self, buckets: &mut HashMap<RateKey, TokenBucket>, now: Instant) {
+        buckets.retain(|key, bucket| {
+            let config = self.bucket_config(key);
+            bucket.refill(config, now);
+            bucket.tokens < config.capacity as f64
+        });
+    }
+}
+
+pub fn retry_after_ms(retry_after: Duration) -> u64 {
+    retry_after.as_millis().min(u64::MAX as u128) as u64
+}
+
+pub fn too_many_requests(retry_after: Duration) -> HttpResponse {
+    let retry_after_secs = retry_after.as_secs().saturating_add(u64::from(retry_after.subsec_nanos() > 0));
+    HttpResponse::TooManyRequests()
+        .append_header(("Retry-After", retry_after_secs.to_string()))
+        .json(json!({
+            "error": "Too many requests, slow down",
+            "retry_after_ms": retry_after_ms(retry_after),
+        }))
+}
@@ -0,0 +1,201 @@
+use crate::one_time_token::{from_hex, to_hex};
+use actix_web::body::MessageBody;
+use actix_web::cookie::{Cookie, CookieJar, Key};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::{web, Error};
+use actix_web_lab::middleware::Next;
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+pub const SESSION_COOKIE_NAME: &str = "id";
+// Keys shorter than this are refused by the cookie crate
+const KEY_BYTES: usize = 64;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct SessionKeyConfig {
+    // Hex encoded key, takes precedence over the key file
+    pub key: Option<String>,
+    // Created with a random key on first start when it does not exist
+    pub key_file: String,
+    // The key being rotated away from, still accepted until the deadline
+    pub previous_key: Option<String>,
+    pub previous_key_file: Option<String>,
+    // Without a deadline the previous key is accepted until it is removed
+    pub previous_key_expires_at: Option<DateTime<Utc>>,
+}
+
+impl Default for SessionKeyConfig {
+    fn default() -> Self {
+        SessionKeyConfig {
+            key: None,
+            key_file: "session.key".to_string(),
+            previous_key: None,
+            previous_key_file: None,
+            previous_key_expires_at: None,
+        }
+    }
+}
+
+impl SessionKeyConfig {
+    // Overrides settings with SESSION_*KEY* environment variables
+    pub fn apply_env(&mut self) {
+        if let Ok(key) = env::var("SESSION_KEY") {
+            self.key = Some(key);
+        }
+        if let Ok(path) = env::var("SESSION_KEY_FILE") {
+            self.key_file = path;
+        }
+        if let Ok(key) = env::var("SESSION_PREVIOUS_KEY") {
+            self.previous_key = Some(key);
+        }
+        if let Ok(path) = env::var("SESSION_PREVIOUS_KEY_FILE") {
+            self.previous_key_file = Some(path);
+        }
+        if let Ok(deadline) = env::var("SESSION_PREVIOUS_KEY_EXPIRES_AT") {
+            match DateTime::parse_from_rfc3339(&deadline) {
+                Ok(parsed) => self.previous_key_expires_at = Some(parsed.with_timezone(&Utc)),
+                Err(e) => log::warn!("Ignoring invalid SESSION_PREVIOUS_KEY_EXPIRES_AT: {}", e),
+            }
+        }
+    }
+}
+
+fn parse_key(hex: &str) -> anyhow::Result<Key> {
+    let bytes = from_hex(hex.trim()).context("session key is not valid hex")?;
+    Key::try_from(bytes.as_slice())
+        .map_err(|_| anyhow::anyhow!("session key must be at least {} bytes", KEY_BYTES))
+}
+
+fn read_key_file(path: &str) -> anyhow::Result<Key> {
+    let contents = fs::read_to_string(path).with_context(|| format!("failed to read session key file {}", path))?;
+    parse_key(&contents).with_context(|| format!("invalid session key file {}", path))
+}
+
+// Writes a fresh key readable only by the owner
+fn create_key_file(path: &str) -> anyhow::Result<Key> {
+    let key = Key::generate();
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path).with_context(|| format!("failed to create session key file {}", path))?;
+    writeln!(file, "{}", to_hex(key.master()))?;
+    Ok(key)
+}
+
+#[derive(Clone)]
+pub struct SessionKeys {
+    pub current: Key,
+    previous: Option<(Key, Option<DateTime<Utc>>)>,
+}
+
+impl SessionKeys {
+    pub fn load(config: &SessionKeyConfig) -> anyhow::Result<Self> {
+        let current = match &config.key {
+            Some(key) => parse_key(key)?,
+            None if Path::new(&config.key_file).exists() => read_key_file(&config.key_file)?,
+            None => {
+                log::warn!("No session key configured, generating one in {}", config.key_file);
+                create_key_file(&config.key_file)?
+            }
+        };
+        let previous = match (&config.previous_key, &config.previous_key_file) {
+            (Some(key), _) => Some(parse_key(key)?),
+            (None, Some(path)) => Some(read_key_file(path)?),
+            (None, None) => None,
+        };
+        if previous.is_some() {
+            match config.previous_key_expires_at {
+                Some(deadline) => log::info!("Accepting cookies signed with the previous session key until {}", deadline),
+                None => log::info!("Accepting cookies signed with the previous session key until it is removed"),
+            }
+        }
+        Ok(SessionKeys {
+            current,
+            previous: previous.map(|key| (key, config.previous_key_expires_at)),
+        })
+    }
+
+    // The session cookie encrypted again under the current key, if it was
+    // encrypted with the previous one while that is still accepted
+    fn reissue(&self, session_cookie: Cookie<'static>) -> Option<Cookie<'static>> {
+        let (previous, expires_at) = self.previous.as_ref()?;
+        if expires_at.is_some_and(|deadline| Utc::now() > deadline) {
+            return None;
+        }
+        let mut jar = CookieJar::new();
+        jar.add_original(session_cookie);
+        if jar.private(&self.current).get(SESSION_COOKIE_NAME).is_some() {
+            return None;
+        }
+        let decrypted = jar.private(previous).get(SESSION_COOKIE_NAME)?;
+
+        // Same attributes the session middleware gives its cookie
+        let mut cookie = Cookie::new(SESSION_COOKIE_NAME, decrypted.value().to_string());
+        cookie.set_secure(true);
+        cookie.set_http_only(true);
+        cookie.set_same_site(actix_web::cookie::SameSite::Lax);
+        cookie.set_path("/");
+        let mut reissued = CookieJar::new();
+        reissued.private_mut(&self.current).add(cookie);
+        reissued.get(SESSION_COOKIE_NAME).cloned()
+    }
+}
+
+// Parses the Cookie header by hand, HttpRequest::cookies() caches its result
+// and the session middleware would then never see the rewritten header
+fn request_cookies(req: &ServiceRequest) -> Vec<Cookie<'static>> {
+    req.headers()
+        .get_all(header::COOKIE)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| Cookie::parse_encoded(pair.trim().to_string()).ok())
+        .collect()
+}
+
+// Runs outside the session middleware. Cookies from the previous key are
+// swapped for current ones before the session is loaded, and the browser is
+// handed the new cookie so it stops depending on the old key.
+pub async fn reissue_rotated_session_cookie(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let keys = req.app_data::<web::Data<SessionKeys>>().cloned();
+    let mut cookies = request_cookies(&req);
+    let reissued = keys.and_then(|keys| {
+        let session_cookie = cookies.iter().find(|cookie| cookie.name() == SESSION_COOKIE_NAME)?.clone();
+        keys.reissue(session_cookie)
+    });
+    if let Some(reissued) = &reissued {
+        for cookie in cookies.iter_mut().filter(|cookie| cookie.name() == SESSION_COOKIE_NAME) {
+            cookie.set_value(reissued.value().to_string());
+        }
+        let header_value = cookies
+            .iter()
+            .map(|cookie| Cookie::new(cookie.name().to_string(), cookie.value().to_string()).encoded().to_string())
+            .collect::<Vec<_>>()
+            .join("; ");
+        if let Ok(header_value) = HeaderValue::from_str(&header_value) {
+            req.headers_mut().insert(header::COOKIE, header_value);
+        }
+    }
+
+    let mut res = next.call(req).await?;
+    if let Some(reissued) = reissued {
+        // A session that changed during the request already got a new cookie
+        let already_set = res.response().cookies().any(|cookie| cookie.name() == SESSION_COOKIE_NAME);
+        if !already_set {
+            if let Ok(value) = HeaderValue::from_str(&reissued.encoded().to_string()) {
+                res.headers_mut().append(header::SET_COOKIE, value);
+            }
+        }
+    }
+    Ok(res)
+}
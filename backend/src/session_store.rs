@@ -0,0 +1,194 @@
+use crate::auth::SESSION_ID_KEY;
+use crate::kv::KvStore;
+use actix_session::storage::{
+    CookieSessionStore, LoadError, RedisSessionStore, SaveError, SessionKey, SessionStore, UpdateError,
+};
+use actix_web::cookie::time::Duration;
+use clap::ValueEnum;
+use rand::distributions::{Alphanumeric, DistString};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use surrealdb::sql::Uuid;
+
+const SESSION_STATE_PREFIX: &str = "session_state";
+// Which stored state holds a login session, so revoking can delete it
+const SESSION_INDEX_PREFIX: &str = "session_state_key";
+// Same length as the keys actix-session generates itself
+const SESSION_KEY_LENGTH: usize = 64;
+
+type SessionState = HashMap<String, String>;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum SessionStoreKind {
+    #[default]
+    Redis,
+    // The whole session lives in the encrypted cookie, nothing is kept server side
+    Cookie,
+    // Lost on restart and not shared between processes
+    Memory,
+}
+
+// The session store picked by configuration, SessionMiddleware needs a
+// single concrete type. The kv store beside the Redis session store holds
+// the index from login sessions to their state.
+#[derive(Clone)]
+pub enum SessionBackend {
+    Redis(RedisSessionStore, KvStore),
+    Cookie,
+    Memory(KvStore),
+}
+
+impl SessionBackend {
+    pub async fn build(kind: SessionStoreKind, redis_url: &str) -> anyhow::Result<Self> {
+        match kind {
+            SessionStoreKind::Redis => Ok(SessionBackend::Redis(
+                RedisSessionStore::new(redis_url).await?,
+                KvStore::connect(redis_url).await?,
+            )),
+            SessionStoreKind::Cookie => Ok(SessionBackend::Cookie),
+            SessionStoreKind::Memory => Ok(SessionBackend::Memory(KvStore::memory())),
+        }
+    }
+
+    fn index(&self) -> Option<&KvStore> {
+        match self {
+            SessionBackend::Redis(_, kv) | SessionBackend::Memory(kv) => Some(kv),
+            SessionBackend::Cookie => None,
+        }
+    }
+
+    // Cookie sessions live in the browser and can only be refused, not deleted
+    pub fn keeps_state(&self) -> bool {
+        self.index().is_some()
+    }
+
+    // Failing to index only means the state outlives a revocation until its
+    // TTL, so the request is not failed over it
+    async fn index_state(&self, session_key: &SessionKey, session_state: &SessionState, ttl: &Duration) {
+        let (Some(kv), Some(session_id)) = (self.index(), session_state.get(SESSION_ID_KEY)) else {
+            return;
+        };
+        let Ok(session_id) = serde_json::from_str::<Uuid>(session_id) else {
+            return;
+        };
+        if let Err(e) = kv.set_ex(&index_key(&session_id), session_key.as_ref(), kv_ttl(ttl)).await {
+            log::error!("Failed to index session state: fn index_state, error: {:?}", e);
+        }
+    }
+
+    // Deletes the stored state of a login session, so it is gone even if the
+    // revocation marker cannot be read later
+    pub async fn kill(&self, session_id: &Uuid) -> anyhow::Result<()> {
+        let Some(kv) = self.index() else {
+            return Ok(());
+        };
+        let Some(session_key) = kv.take(&index_key(session_id)).await? else {
+            return Ok(());
+        };
+        let session_key = SessionKey::try_from(session_key)?;
+        self.delete(&session_key).await
+    }
+}
+
+fn state_key(session_key: &SessionKey) -> String {
+    format!("{}:{}", SESSION_STATE_PREFIX, session_key.as_ref())
+}
+
+fn index_key(session_id: &Uuid) -> String {
+    format!("{}:{}", SESSION_INDEX_PREFIX, session_id.0)
+}
+
+fn kv_ttl(ttl: &Duration) -> std::time::Duration {
+    std::time::Duration::from_secs(ttl.whole_seconds().max(1) as u64)
+}
+
+async fn save_state(kv: &KvStore, session_state: SessionState, ttl: &Duration) -> Result<SessionKey, SaveError> {
+    let serialized = serde_json::to_string(&session_state).map_err(|e| SaveError::Serialization(e.into()))?;
+    let session_key = SessionKey::try_from(Alphanumeric.sample_string(&mut rand::thread_rng(), SESSION_KEY_LENGTH))
+        .map_err(|e| SaveError::Other(e.into()))?;
+    kv.set_ex(&state_key(&session_key), &serialized, kv_ttl(ttl))
+        .await
+        .map_err(SaveError::Other)?;
+    Ok(session_key)
+}
+
+async fn update_state(
+    kv: &KvStore,
+    session_key: SessionKey,
+    session_state: SessionState,
+    ttl: &Duration,
+) -> Result<SessionKey, UpdateError> {
+    // Like the Redis store, a session that expired meanwhile gets a new key
+    let exists = kv.get(&state_key(&session_key)).await.map_err(UpdateError::Other)?.is_some();
+    if !exists {
+        return save_state(kv, session_state, ttl).await.map_err(|e| match e {
+            SaveError::Serialization(e) => UpdateError::Serialization(e),
+            SaveError::Other(e) => UpdateError::Other(e),
+        });
+    }
+    let serialized = serde_json::to_string(&session_state).map_err(|e| UpdateError::Serialization(e.into()))?;
+    kv.set_ex(&state_key(&session_key), &serialized, kv_ttl(ttl))
+        .await
+        .map_err(UpdateError::Other)?;
+    Ok(session_key)
+}
+
+impl SessionStore for SessionBackend {
+    async fn load(&self, session_key: &SessionKey) -> Result<Option<SessionState>, LoadError> {
+        match self {
+            SessionBackend::Redis(store, _) => store.load(session_key).await,
+            SessionBackend::Cookie => CookieSessionStore::default().load(session_key).await,
+            SessionBackend::Memory(kv) => {
+                let serialized = kv.get(&state_key(session_key)).await.map_err(LoadError::Other)?;
+                serialized
+                    .map(|serialized| serde_json::from_str(&serialized))
+                    .transpose()
+                    .map_err(|e| LoadError::Deserialization(e.into()))
+            }
+        }
+    }
+
+    async fn save(&self, session_state: SessionState, ttl: &Duration) -> Result<SessionKey, SaveError> {
+        let indexed = session_state.clone();
+        let session_key = match self {
+            SessionBackend::Redis(store, _) => store.save(session_state, ttl).await,
+            SessionBackend::Cookie => CookieSessionStore::default().save(session_state, ttl).await,
+            SessionBackend::Memory(kv) => save_state(kv, session_state, ttl).await,
+        }?;
+        self.index_state(&session_key, &indexed, ttl).await;
+        Ok(session_key)
+    }
+
+    async fn update(
+        &self,
+        session_key: SessionKey,
+        session_state: SessionState,
+        ttl: &Duration,
+    ) -> Result<SessionKey, UpdateError> {
+        let indexed = session_state.clone();
+        let session_key = match self {
+            SessionBackend::Redis(store, _) => store.update(session_key, session_state, ttl).await,
+            SessionBackend::Cookie => CookieSessionStore::default().update(session_key, session_state, ttl).await,
+            SessionBackend::Memory(kv) => update_state(kv, session_key, session_state, ttl).await,
+        }?;
+        self.index_state(&session_key, &indexed, ttl).await;
+        Ok(session_key)
+    }
+
+    async fn update_ttl(&self, session_key: &SessionKey, ttl: &Duration) -> Result<(), anyhow::Error> {
+        match self {
+            SessionBackend::Redis(store, _) => store.update_ttl(session_key, ttl).await,
+            SessionBackend::Cookie => CookieSessionStore::default().update_ttl(session_key, ttl).await,
+            SessionBackend::Memory(kv) => kv.expire(&state_key(session_key), kv_ttl(ttl)).await,
+        }
+    }
+
+    async fn delete(&self, session_key: &SessionKey) -> Result<(), anyhow::Error> {
+        match self {
+            SessionBackend::Redis(store, _) => store.delete(session_key).await,
+            SessionBackend::Cookie => CookieSessionStore::default().delete(session_key).await,
+            SessionBackend::Memory(kv) => kv.del(&[&state_key(session_key)]).await,
+        }
+    }
+}
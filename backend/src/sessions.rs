@@ -0,0 +1,278 @@
+use crate::appstate::AppState;
+use crate::auth::authenticate;
+use crate::kv::KvStore;
+use crate::session_store::SessionBackend;
+use crate::tokens::revoke_session_refresh;
+use actix_session::Session;
+use actix_web::http::header;
+use actix_web::{delete, get, web, HttpRequest, HttpResponse, Responder};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::time::Duration;
+use surrealdb::sql::Uuid;
+
+// Outlives both cookie sessions and refresh tokens, so a revoked session can
+// never come back once its marker expires
+const SESSION_TTL: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+// Activity is only written back this often to spare Redis a write per request
+const TOUCH_INTERVAL_SECS: i64 = 60;
+const MAX_DEVICE_LENGTH: usize = 256;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum SessionKind {
+    Cookie,
+    Token,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SessionRecord {
+    pub session_id: Uuid,
+    pub kind: SessionKind,
+    // The client's User-Agent
+    pub device: Option<String>,
+    pub ip: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub last_active: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+pub struct SessionView {
+    #[serde(flatten)]
+    pub record: SessionRecord,
+    pub current: bool,
+    // Sockets currently open from the session
+    pub ws_ids: Vec<Uuid>,
+}
+
+pub struct ClientInfo {
+    pub device: Option<String>,
+    pub ip: Option<String>,
+}
+
+impl ClientInfo {
+    pub fn from_request(req: &HttpRequest) -> Self {
+        let device = req
+            .headers()
+            .get(header::USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .map(|agent| agent.chars().take(MAX_DEVICE_LENGTH).collect());
+        ClientInfo { device, ip: req.peer_addr().map(|addr| addr.ip().to_string()) }
+    }
+}
+
+fn record_key(session_id: &Uuid) -> String {
+    format!("session:{}", session_id.0)
+}
+
+fn index_key(user_id: &Uuid) -> String {
+    format!("user_sessions:{}", user_id.0)
+}
+
+fn revoked_key(session_id: &Uuid) -> String {
+    format!("session_revoked:{}", session_id.0)
+}
+
+// Index of each user's login sessions, cookie and token alike. Revoking
+// deletes the stored session state and refresh token, and leaves a marker
+// that refuses what cannot be deleted: cookie-store sessions and access
+// tokens that have not expired yet.
+pub struct SessionTracker {
+    kv: KvStore,
+    store: SessionBackend,
+}
+
+impl SessionTracker {
+    pub fn new(kv: KvStore, store: SessionBackend) -> Self {
+        SessionTracker { kv, store }
+    }
+
+    pub async fn record(&self, user_id: &Uuid, session_id: Uuid, kind: SessionKind, client: ClientInfo) {
+        let now = Utc::now();
+        let record = SessionRecord {
+            session_id,
+            kind,
+            device: client.device,
+            ip: client.ip,
+            created_at: now,
+            last_active: now,
+        };
+        self.save(user_id, &record).await;
+    }
+
+    // Updates the last activity, indexing sessions that predate the index
+    pub async fn touch(&self, user_id: &Uuid, session_id: Uuid, kind: SessionKind, client: ClientInfo) {
+        let now = Utc::now();
+        let record = match self.get(&session_id).await {
+            Some(record) if (now - record.last_active).num_seconds() < TOUCH_INTERVAL_SECS => return,
+            Some(record) => SessionRecord {
+                device: client.device.or(record.device),
+                ip: client.ip.or(record.ip),
+                last_active: now,
+                ..record
+            },
+            None => SessionRecord {
+                session_id,
+                kind,
+                device: client.device,
+                ip: client.ip,
+                created_at: now,
+                last_active: now,
+            },
+        };
+        self.save(user_id, &record).await;
+    }
+
+    async fn save(&self, user_id: &Uuid, record: &SessionRecord) {
+        let serialized = match serde_json::to_string(record) {
+            Ok(serialized) => serialized,
+            Err(e) => {
+                log::error!("Failed to serialize session record: fn save, error: {:?}", e);
+                return;
+            }
+        };
+        let result = async {
+            self.kv.set_ex(&record_key(&record.session_id), &serialized, SESSION_TTL).await?;
+            self.kv.set_add(&index_key(user_id), &record.session_id.0.to_string(), SESSION_TTL).await
+        };
+        if let Err(e) = result.await {
+            log::error!("Failed to save session record: fn save, error: {:?}", e);
+        }
+    }
+
+    async fn get(&self, session_id: &Uuid) -> Option<SessionRecord> {
+        match self.kv.get(&record_key(session_id)).await {
+            Ok(record) => serde_json::from_str(&record?).ok(),
+            Err(e) => {
+                log::error!("Failed to load session record: fn get, error: {:?}", e);
+                None
+            }
+        }
+    }
+
+    // Fails open like the login guard when revoked sessions are deleted from
+    // the session store, an outage should not log everyone out. Cookie-store
+    // sessions only have the marker, so they fail closed.
+    pub async fn is_revoked(&self, session_id: &Uuid) -> bool {
+        match self.kv.get(&revoked_key(session_id)).await {
+            Ok(marker) => marker.is_some(),
+            Err(e) => {
+                log::error!("Failed to check session revocation: fn is_revoked, error: {:?}", e);
+                !self.store.keeps_state()
+            }
+        }
+    }
+
+    // Newest activity first, dropping index entries whose record expired
+    pub async fn list(&self, user_id: &Uuid) -> Option<Vec<SessionRecord>> {
+        let session_ids = match self.kv.set_members(&index_key(user_id)).await {
+            Ok(session_ids) => session_ids,
+            Err(e) => {
+                log::error!("Failed to list sessions: fn list, error: {:?}", e);
+                return None;
+            }
+        };
+        let mut records = Vec::new();
+        for session_id in session_ids {
+            let record = match Uuid::try_from(session_id.as_str()) {
+                Ok(parsed) => self.get(&parsed).await,
+                Err(_) => None,
+            };
+            match record {
+                Some(record) => records.push(record),
+                None => {
+                    let _ = self.kv.set_remove(&index_key(user_id), &session_id).await;
+                }
+            }
+        }
+        records.sort_by_key(|record| std::cmp::Reverse(record.last_active));
+        Some(records)
+    }
+
+    pub async fn revoke(&self, user_id: &Uuid, session_id: &Uuid) -> bool {
+        let result = async {
+            self.kv.set_ex(&revoked_key(session_id), "1", SESSION_TTL).await?;
+            self.store.kill(session_id).await?;
+            revoke_session_refresh(&self.kv, session_id).await?;
+            self.kv.del(&[&record_key(session_id)]).await?;
+            self.kv.set_remove(&index_key(user_id), &session_id.0.to_string()).await
+        };
+        match result.await {
+            Ok(()) => true,
+            Err(e) => {
+                log::error!("Failed to revoke session: fn revoke, error: {:?}", e);
+                false
+            }
+        }
+    }
+
+    pub async fn revoke_all(&self, user_id: &Uuid, keep_session: Option<Uuid>) {
+        let records = self.list(user_id).await.unwrap_or_default();
+        for record in records {
+            if Some(record.session_id) != keep_session {
+                self.revoke(user_id, &record.session_id).await;
+            }
+        }
+    }
+}
+
+#[get("/sessions")]
+pub async fn list_sessions(req: HttpRequest, session: Session, state: web::Data<AppState>) -> impl Responder {
+    let auth = match authenticate(&req, &session, &state).await {
+        Some(auth) => auth,
+        None => return HttpResponse::Unauthorized().json(json!({"error": "Not logged in"})),
+    };
+    let records = match state.sessions.list(&auth.user.user_id).await {
+        Some(records) => records,
+        None => return HttpResponse::InternalServerError().json(json!({"error": "Internal Error"})),
+    };
+    // Sockets may be open on any backend instance
+    let sockets = state.cluster.sockets(&auth.user.user_id).await.unwrap_or_default();
+    let views: Vec<SessionView> = records
+        .into_iter()
+        .map(|record| {
+            let ws_ids = sockets
+                .iter()
+                .filter(|socket| socket.session_id == Some(record.session_id))
+                .map(|socket| socket.ws_id)
+                .collect();
+            SessionView { current: auth.session_id == Some(record.session_id), ws_ids, record }
+        })
+        .collect();
+    HttpResponse::Ok().json(views)
+}
+
+#[delete("/sessions/{session_id}")]
+pub async fn revoke_session(
+    req: HttpRequest,
+    path: web::Path<String>,
+    session: Session,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let auth = match authenticate(&req, &session, &state).await {
+        Some(auth) => auth,
+        None => return HttpResponse::Unauthorized().json(json!({"error": "Not logged in"})),
+    };
+    let not_found = || HttpResponse::NotFound().json(json!({"error": "Session not found"}));
+    let session_id = match Uuid::try_from(path.as_str()) {
+        Ok(session_id) => session_id,
+        Err(_) => return not_found(),
+    };
+    // Only the user's own sessions are in their index
+    let owned = state
+        .sessions
+        .list(&auth.user.user_id)
+        .await
+        .is_some_and(|records| records.iter().any(|record| record.session_id == session_id));
+    if !owned {
+        return not_found();
+    }
+    if !state.sessions.revoke(&auth.user.user_id, &session_id).await {
+        return HttpResponse::InternalServerError().json(json!({"error": "Internal Error"}));
+    }
+    state.disconnect_session(&auth.user.user_id, session_id, "Session revoked".to_string()).await;
+    if !auth.bearer && auth.session_id == Some(session_id) {
+        session.purge();
+    }
+    HttpResponse::Ok().json(json!({"message": "Session revoked"}))
+}
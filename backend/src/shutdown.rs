@@ -0,0 +1,136 @@
+use crate::appstate::AppState;
+use crate::websocket::{change_to_offline, ServerGoingAway};
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+const DRAIN_POLL: Duration = Duration::from_millis(20);
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct ShutdownConfig {
+    // How long clients are told to wait before reconnecting
+    pub reconnect_after_secs: u64,
+    // Upper bound on waiting for sockets to close and writes to finish
+    pub drain_timeout_secs: u64,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        ShutdownConfig { reconnect_after_secs: 5, drain_timeout_secs: 10 }
+    }
+}
+
+impl ShutdownConfig {
+    // Overrides settings with SHUTDOWN_* environment variables
+    pub fn apply_env(&mut self) {
+        let overrides: [(&str, &mut u64); 2] = [
+            ("SHUTDOWN_RECONNECT_AFTER_SECS", &mut self.reconnect_after_secs),
+            ("SHUTDOWN_DRAIN_TIMEOUT_SECS", &mut self.drain_timeout_secs),
+        ];
+        for (name, field) in overrides {
+            if let Some(value) = env::var(name).ok().and_then(|value| value.parse().ok()) {
+                *field = value;
+            }
+        }
+    }
+}
+
+// Tracks writes spawned on behalf of sockets, which would otherwise be
+// dropped when the runtime stops, and whether the instance is going away
+#[derive(Clone, Default)]
+pub struct Drain {
+    draining: Arc<AtomicBool>,
+    in_flight: Arc<AtomicUsize>,
+    idle: Arc<Notify>,
+}
+
+struct InFlight(Drain);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        if self.0.in_flight.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0.idle.notify_waiters();
+        }
+    }
+}
+
+impl Drain {
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    pub fn start(&self) {
+        self.draining.store(true, Ordering::SeqCst);
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    pub fn spawn(&self, task: impl Future<Output = ()> + 'static) {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        let guard = InFlight(self.clone());
+        actix::spawn(async move {
+            task.await;
+            drop(guard);
+        });
+    }
+
+    // Whether every tracked task finished before the deadline
+    pub async fn wait_idle(&self, deadline: Instant) -> bool {
+        loop {
+            let idle = self.idle.notified();
+            if self.in_flight() == 0 {
+                return true;
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if actix_web::rt::time::timeout(remaining, idle).await.is_err() {
+                return self.in_flight() == 0;
+            }
+        }
+    }
+}
+
+// Refuses new sockets, tells the open ones to reconnect elsewhere and waits
+// for them to close and for their writes to land. Users whose sockets did
+// not close in time are marked offline here instead.
+pub async fn drain(state: &AppState) {
+    let config = &state.shutdown;
+    let deadline = Instant::now() + Duration::from_secs(config.drain_timeout_secs);
+    state.drain.start();
+
+    let reconnect_after_ms = config.reconnect_after_secs * 1000;
+    for socket in state.actor_registry.lock().unwrap().values().flat_map(|sockets| sockets.values()) {
+        socket.addr.do_send(ServerGoingAway { reconnect_after_ms });
+    }
+    while !state.actor_registry.lock().unwrap().is_empty() && Instant::now() < deadline {
+        actix_web::rt::time::sleep(DRAIN_POLL).await;
+    }
+
+    let lingering: Vec<_> = state
+        .actor_registry
+        .lock()
+        .unwrap()
+        .drain()
+        .flat_map(|(user_id, sockets)| {
+            sockets.into_iter().map(move |(ws_id, socket)| (user_id, ws_id, socket.session_id, socket.presence))
+        })
+        .collect();
+    if !lingering.is_empty() {
+        log::warn!("Sockets still open after the drain timeout: fn drain, count: {}", lingering.len());
+    }
+    for (user_id, ws_id, session_id, presence) in lingering {
+        if state.cluster.socket_closed(&presence, &user_id, ws_id, session_id).await {
+            change_to_offline(state.storage.clone(), user_id).await;
+        }
+    }
+
+    if !state.drain.wait_idle(deadline).await {
+        log::warn!("Writes still pending after the drain timeout: fn drain, count: {}", state.drain.in_flight());
+    }
+}
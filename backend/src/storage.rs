@@ -0,0 +1,159 @@
+use crate::audit::{AuditEvent, AuditQuery};
+use crate::bots::ApiToken;
+use crate::config::DatabaseConfig;
+use crate::structs::{AccountState, ConnectionState, Room, User, UserData};
+use crate::two_factor::TwoFactor;
+use async_trait::async_trait;
+use black_signal_protocol::BasicMessage;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::Arc;
+use surrealdb::sql::Uuid;
+
+mod instrumented;
+mod memory;
+mod migrations;
+mod resilient;
+mod surreal;
+
+pub use instrumented::InstrumentedStorage;
+pub use memory::MemoryStorage;
+pub use resilient::ResilientStorage;
+pub use surreal::SurrealStorage;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    // A SurrealDB server reached over websocket
+    #[default]
+    Surreal,
+    // Kept in process, everything is lost on restart
+    Memory,
+}
+
+pub const LOGIN_INDEX: &str = "users_login";
+pub const USERNAME_INDEX: &str = "users_username";
+
+// A write that would give two records the same value for a unique field
+#[derive(Debug)]
+pub struct UniqueViolation {
+    pub index: String,
+}
+
+impl fmt::Display for UniqueViolation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unique index {} already contains the value", self.index)
+    }
+}
+
+impl std::error::Error for UniqueViolation {}
+
+// The unique index a failed write collided with, if that is why it failed
+pub fn unique_violation(error: &anyhow::Error) -> Option<&str> {
+    error.downcast_ref::<UniqueViolation>().map(|violation| violation.index.as_str())
+}
+
+// Refused without trying because the backend is being reconnected
+#[derive(Debug)]
+pub struct StorageUnavailable;
+
+impl fmt::Display for StorageUnavailable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "storage is temporarily unavailable")
+    }
+}
+
+impl std::error::Error for StorageUnavailable {}
+
+pub fn unavailable(error: &anyhow::Error) -> bool {
+    error.downcast_ref::<StorageUnavailable>().is_some()
+}
+
+// Whether a failed call lost its way to the database rather than being
+// turned down by it, only the former says anything about the connection
+pub fn connection_error(error: &anyhow::Error) -> bool {
+    use surrealdb::error::Api;
+    matches!(
+        error.downcast_ref::<surrealdb::Error>(),
+        Some(surrealdb::Error::Api(Api::Http(_) | Api::Ws(_) | Api::ConnectionUninitialised | Api::InternalError(_)))
+    )
+}
+
+#[async_trait]
+pub trait UserRepository: Send + Sync {
+    async fn get_user(&self, user_id: &Uuid) -> anyhow::Result<Option<UserData>>;
+    async fn get_user_by_login(&self, login: &str) -> anyhow::Result<Option<UserData>>;
+    // Fails with UniqueViolation when the login or username is taken
+    async fn create_user(&self, user: &UserData) -> anyhow::Result<()>;
+    // Also takes the user out of every room
+    async fn delete_user(&self, user_id: &Uuid) -> anyhow::Result<()>;
+    // Fails with UniqueViolation when the username is taken
+    async fn set_username(&self, user_id: &Uuid, username: &str) -> anyhow::Result<()>;
+    async fn set_password(&self, user_id: &Uuid, hashed_password: &str, session_epoch: u64) -> anyhow::Result<()>;
+    async fn set_account_state(&self, user_id: &Uuid, account_state: AccountState) -> anyhow::Result<()>;
+    async fn set_status(&self, user_id: &Uuid, status: ConnectionState) -> anyhow::Result<()>;
+    async fn set_two_factor(&self, user_id: &Uuid, two_factor: Option<TwoFactor>) -> anyhow::Result<()>;
+    // Records the step as used unless it or a later one already was, false
+    // when the code has been spent
+    async fn spend_totp_step(&self, user_id: &Uuid, step: u64) -> anyhow::Result<bool>;
+    // Removes the recovery code hash, false when it was already gone
+    async fn spend_recovery_code(&self, user_id: &Uuid, hashed_code: &str) -> anyhow::Result<bool>;
+    async fn set_api_tokens(&self, user_id: &Uuid, api_tokens: Vec<ApiToken>) -> anyhow::Result<()>;
+    async fn bots_owned_by(&self, owner_id: &Uuid) -> anyhow::Result<Vec<UserData>>;
+    async fn users_in_room(&self, room_id: &Uuid) -> anyhow::Result<Vec<User>>;
+}
+
+#[async_trait]
+pub trait RoomRepository: Send + Sync {
+    async fn get_room(&self, room_id: &Uuid) -> anyhow::Result<Option<Room>>;
+    async fn create_room(&self, room: &Room) -> anyhow::Result<()>;
+    async fn add_room_member(&self, room_id: &Uuid, user_id: &Uuid) -> anyhow::Result<()>;
+    async fn remove_room_member(&self, room_id: &Uuid, user_id: &Uuid) -> anyhow::Result<()>;
+}
+
+#[async_trait]
+pub trait MessageRepository: Send + Sync {
+    async fn create_message(&self, message: &BasicMessage) -> anyhow::Result<()>;
+    async fn get_message(&self, message_id: &Uuid) -> anyhow::Result<Option<BasicMessage>>;
+    async fn delete_message(&self, message_id: &Uuid) -> anyhow::Result<()>;
+    // Oldest first
+    async fn room_messages(&self, room_id: &Uuid) -> anyhow::Result<Vec<BasicMessage>>;
+}
+
+#[async_trait]
+pub trait AuditRepository: Send + Sync {
+    async fn record_audit_event(&self, event: &AuditEvent) -> anyhow::Result<()>;
+    // Newest first, at most query.limit events
+    async fn audit_events(&self, query: &AuditQuery) -> anyhow::Result<Vec<AuditEvent>>;
+}
+
+#[async_trait]
+pub trait HealthRepository: Send + Sync {
+    // Fails when the backend is unreachable or its schema is not the version
+    // this build migrates to
+    async fn check_health(&self) -> anyhow::Result<()>;
+    // Opens a fresh connection and signs in again
+    async fn reconnect(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+    // False while a lost connection is being re-established
+    fn is_available(&self) -> bool {
+        true
+    }
+}
+
+pub trait Storage: UserRepository + RoomRepository + MessageRepository + AuditRepository + HealthRepository {}
+
+impl<T: UserRepository + RoomRepository + MessageRepository + AuditRepository + HealthRepository> Storage for T {}
+
+pub async fn connect(config: &DatabaseConfig) -> anyhow::Result<Arc<dyn Storage>> {
+    let storage: Arc<dyn Storage> = match config.backend {
+        StorageBackend::Surreal => Arc::new(SurrealStorage::connect(config).await?),
+        StorageBackend::Memory => {
+            log::warn!("Using in-memory storage, nothing will survive a restart");
+            Arc::new(MemoryStorage::default())
+        }
+    };
+    Ok(Arc::new(ResilientStorage::new(storage, config)))
+}
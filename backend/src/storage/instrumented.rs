@@ -0,0 +1,221 @@
+use super::{AuditRepository, HealthRepository, MessageRepository, RoomRepository, Storage, UserRepository};
+use crate::audit::{AuditEvent, AuditQuery};
+use crate::bots::ApiToken;
+use crate::metrics::Metrics;
+use crate::structs::{AccountState, ConnectionState, Room, User, UserData};
+use crate::two_factor::TwoFactor;
+use async_trait::async_trait;
+use black_signal_protocol::BasicMessage;
+use std::sync::Arc;
+use std::time::Instant;
+use surrealdb::sql::Uuid;
+
+// Records how long every call to the wrapped backend takes, by operation
+pub struct InstrumentedStorage {
+    inner: Arc<dyn Storage>,
+    metrics: Metrics,
+}
+
+impl InstrumentedStorage {
+    pub fn new(inner: Arc<dyn Storage>, metrics: Metrics) -> Self {
+        InstrumentedStorage { inner, metrics }
+    }
+}
+
+#[async_trait]
+impl UserRepository for InstrumentedStorage {
+    async fn get_user(&self, user_id: &Uuid) -> anyhow::Result<Option<UserData>> {
+        let started = Instant::now();
+        let result = self.inner.get_user(user_id).await;
+        self.metrics.db_query("get_user", started);
+        result
+    }
+
+    async fn get_user_by_login(&self, login: &str) -> anyhow::Result<Option<UserData>> {
+        let started = Instant::now();
+        let result = self.inner.get_user_by_login(login).await;
+        self.metrics.db_query("get_user_by_login", started);
+        result
+    }
+
+    async fn create_user(&self, user: &UserData) -> anyhow::Result<()> {
+        let started = Instant::now();
+        let result = self.inner.create_user(user).await;
+        self.metrics.db_query("create_user", started);
+        result
+    }
+
+    async fn delete_user(&self, user_id: &Uuid) -> anyhow::Result<()> {
+        let started = Instant::now();
+        let result = self.inner.delete_user(user_id).await;
+        self.metrics.db_query("delete_user", started);
+        result
+    }
+
+    async fn set_username(&self, user_id: &Uuid, username: &str) -> anyhow::Result<()> {
+        let started = Instant::now();
+        let result = self.inner.set_username(user_id, username).await;
+        self.metrics.db_query("set_username", started);
+        result
+    }
+
+    async fn set_password(&self, user_id: &Uuid, hashed_password: &str, session_epoch: u64) -> anyhow::Result<()> {
+        let started = Instant::now();
+        let result = self.inner.set_password(user_id, hashed_password, session_epoch).await;
+        self.metrics.db_query("set_password", started);
+        result
+    }
+
+    async fn set_account_state(&self, user_id: &Uuid, account_state: AccountState) -> anyhow::Result<()> {
+        let started = Instant::now();
+        let result = self.inner.set_account_state(user_id, account_state).await;
+        self.metrics.db_query("set_account_state", started);
+        result
+    }
+
+    async fn set_status(&self, user_id: &Uuid, status: ConnectionState) -> anyhow::Result<()> {
+        let started = Instant::now();
+        let result = self.inner.set_status(user_id, status).await;
+        self.metrics.db_query("set_status", started);
+        result
+    }
+
+    async fn set_two_factor(&self, user_id: &Uuid, two_factor: Option<TwoFactor>) -> anyhow::Result<()> {
+        let started = Instant::now();
+        let result = self.inner.set_two_factor(user_id, two_factor).await;
+        self.metrics.db_query("set_two_factor", started);
+        result
+    }
+
+    async fn spend_totp_step(&self, user_id: &Uuid, step: u64) -> anyhow::Result<bool> {
+        let started = Instant::now();
+        let result = self.inner.spend_totp_step(user_id, step).await;
+        self.metrics.db_query("spend_totp_step", started);
+        result
+    }
+
+    async fn spend_recovery_code(&self, user_id: &Uuid, hashed_code: &str) -> anyhow::Result<bool> {
+        let started = Instant::now();
+        let result = self.inner.spend_recovery_code(user_id, hashed_code).await;
+        self.metrics.db_query("spend_recovery_code", started);
+        result
+    }
+
+    async fn set_api_tokens(&self, user_id: &Uuid, api_tokens: Vec<ApiToken>) -> anyhow::Result<()> {
+        let started = Instant::now();
+        let result = self.inner.set_api_tokens(user_id, api_tokens).await;
+        self.metrics.db_query("set_api_tokens", started);
+        result
+    }
+
+    async fn bots_owned_by(&self, owner_id: &Uuid) -> anyhow::Result<Vec<UserData>> {
+        let started = Instant::now();
+        let result = self.inner.bots_owned_by(owner_id).await;
+        self.metrics.db_query("bots_owned_by", started);
+        result
+    }
+
+    async fn users_in_room(&self, room_id: &Uuid) -> anyhow::Result<Vec<User>> {
+        let started = Instant::now();
+        let result = self.inner.users_in_room(room_id).await;
+        self.metrics.db_query("users_in_room", started);
+        result
+    }
+}
+
+#[async_trait]
+impl RoomRepository for InstrumentedStorage {
+    async fn get_room(&self, room_id: &Uuid) -> anyhow::Result<Option<Room>> {
+        let started = Instant::now();
+        let result = self.inner.get_room(room_id).await;
+        self.metrics.db_query("get_room", started);
+        result
+    }
+
+    async fn create_room(&self, room: &Room) -> anyhow::Result<()> {
+        let started = Instant::now();
+        let result = self.inner.create_room(room).await;
+        self.metrics.db_query("create_room", started);
+        result
+    }
+
+    async fn add_room_member(&self, room_id: &Uuid, user_id: &Uuid) -> anyhow::Result<()> {
+        let started = Instant::now();
+        let result = self.inner.add_room_member(room_id, user_id).await;
+        self.metrics.db_query("add_room_member", started);
+        result
+    }
+
+    async fn remove_room_member(&self, room_id: &Uuid, user_id: &Uuid) -> anyhow::Result<()> {
+        let started = Instant::now();
+        let result = self.inner.remove_room_member(room_id, user_id).await;
+        self.metrics.db_query("remove_room_member", started);
+        result
+    }
+}
+
+#[async_trait]
+impl MessageRepository for InstrumentedStorage {
+    async fn create_message(&self, message: &BasicMessage) -> anyhow::Result<()> {
+        let started = Instant::now();
+        let result = self.inner.create_message(message).await;
+        self.metrics.db_query("create_message", started);
+        result
+    }
+
+    async fn get_message(&self, message_id: &Uuid) -> anyhow::Result<Option<BasicMessage>> {
+        let started = Instant::now();
+        let result = self.inner.get_message(message_id).await;
+        self.metrics.db_query("get_message", started);
+        result
+    }
+
+    async fn delete_message(&self, message_id: &Uuid) -> anyhow::Result<()> {
+        let started = Instant::now();
+        let result = self.inner.delete_message(message_id).await;
+        self.metrics.db_query("delete_message", started);
+        result
+    }
+
+    async fn room_messages(&self, room_id: &Uuid) -> anyhow::Result<Vec<BasicMessage>> {
+        let started = Instant::now();
+        let result = self.inner.room_messages(room_id).await;
+        self.metrics.db_query("room_messages", started);
+        result
+    }
+}
+
+#[async_trait]
+impl AuditRepository for InstrumentedStorage {
+    async fn record_audit_event(&self, event: &AuditEvent) -> anyhow::Result<()> {
+        let started = Instant::now();
+        let result = self.inner.record_audit_event(event).await;
+        self.metrics.db_query("record_audit_event", started);
+        result
+    }
+
+    async fn audit_events(&self, query: &AuditQuery) -> anyhow::Result<Vec<AuditEvent>> {
+        let started = Instant::now();
+        let result = self.inner.audit_events(query).await;
+        self.metrics.db_query("audit_events", started);
+        result
+    }
+}
+
+#[async_trait]
+impl HealthRepository for InstrumentedStorage {
+    async fn check_health(&self) -> anyhow::Result<()> {
+        let started = Instant::now();
+        let result = self.inner.check_health().await;
+        self.metrics.db_query("check_health", started);
+        result
+    }
+
+    async fn reconnect(&self) -> anyhow::Result<()> {
+        self.inner.reconnect().await
+    }
+
+    fn is_available(&self) -> bool {
+        self.inner.is_available()
+    }
+}
@@ -0,0 +1,245 @@
+use super::{AuditRepository, HealthRepository, MessageRepository, RoomRepository, UniqueViolation, UserRepository, LOGIN_INDEX, USERNAME_INDEX};
+use crate::audit::{AuditEvent, AuditQuery};
+use crate::bots::ApiToken;
+use crate::structs::{AccountState, ConnectionState, Room, User, UserData, UserKind};
+use crate::two_factor::TwoFactor;
+use async_trait::async_trait;
+use black_signal_protocol::BasicMessage;
+use std::collections::HashMap;
+#[cfg(feature = "test-hooks")]
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use surrealdb::sql::Uuid;
+
+#[derive(Default)]
+struct Tables {
+    users: HashMap<Uuid, UserData>,
+    rooms: HashMap<Uuid, Room>,
+    // In the order they were posted
+    messages: Vec<BasicMessage>,
+    // In the order they were recorded
+    audit_events: Vec<AuditEvent>,
+}
+
+// Runs the server without a database, for development and tests
+#[derive(Default)]
+pub struct MemoryStorage {
+    tables: Mutex<Tables>,
+    #[cfg(feature = "test-hooks")]
+    unreachable: AtomicBool,
+}
+
+impl MemoryStorage {
+    // Makes health checks and reconnects fail as if a database server went
+    // away, for exercising outage handling
+    #[cfg(feature = "test-hooks")]
+    pub fn set_unreachable(&self, unreachable: bool) {
+        self.unreachable.store(unreachable, Ordering::SeqCst);
+    }
+
+    fn reachable(&self) -> anyhow::Result<()> {
+        #[cfg(feature = "test-hooks")]
+        if self.unreachable.load(Ordering::SeqCst) {
+            anyhow::bail!("in-memory storage is set to unreachable");
+        }
+        Ok(())
+    }
+
+    fn tables(&self) -> MutexGuard<'_, Tables> {
+        self.tables.lock().unwrap()
+    }
+
+    // Like an UPDATE in SurrealDB, a missing user is not an error
+    fn update_user(&self, user_id: &Uuid, update: impl FnOnce(&mut UserData)) -> anyhow::Result<()> {
+        if let Some(user) = self.tables().users.get_mut(user_id) {
+            update(user);
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl UserRepository for MemoryStorage {
+    async fn get_user(&self, user_id: &Uuid) -> anyhow::Result<Option<UserData>> {
+        Ok(self.tables().users.get(user_id).cloned())
+    }
+
+    async fn get_user_by_login(&self, login: &str) -> anyhow::Result<Option<UserData>> {
+        Ok(self.tables().users.values().find(|user| user.login == login).cloned())
+    }
+
+    async fn create_user(&self, user: &UserData) -> anyhow::Result<()> {
+        let mut tables = self.tables();
+        for existing in tables.users.values() {
+            if existing.login == user.login {
+                return Err(UniqueViolation { index: LOGIN_INDEX.to_string() }.into());
+            }
+            if existing.username == user.username {
+                return Err(UniqueViolation { index: USERNAME_INDEX.to_string() }.into());
+            }
+        }
+        tables.users.insert(user.user_id, user.clone());
+        Ok(())
+    }
+
+    async fn delete_user(&self, user_id: &Uuid) -> anyhow::Result<()> {
+        let mut tables = self.tables();
+        tables.users.remove(user_id);
+        for room in tables.rooms.values_mut() {
+            room.users.remove(user_id);
+        }
+        Ok(())
+    }
+
+    async fn set_username(&self, user_id: &Uuid, username: &str) -> anyhow::Result<()> {
+        let taken = self.tables().users.values().any(|user| user.username == username && user.user_id != *user_id);
+        if taken {
+            return Err(UniqueViolation { index: USERNAME_INDEX.to_string() }.into());
+        }
+        self.update_user(user_id, |user| user.username = username.to_string())
+    }
+
+    async fn set_password(&self, user_id: &Uuid, hashed_password: &str, session_epoch: u64) -> anyhow::Result<()> {
+        self.update_user(user_id, |user| {
+            user.hashed_password = hashed_password.to_string();
+            user.session_epoch = session_epoch;
+        })
+    }
+
+    async fn set_account_state(&self, user_id: &Uuid, account_state: AccountState) -> anyhow::Result<()> {
+        self.update_user(user_id, |user| user.account_state = account_state)
+    }
+
+    async fn set_status(&self, user_id: &Uuid, status: ConnectionState) -> anyhow::Result<()> {
+        self.update_user(user_id, |user| user.status = status)
+    }
+
+    async fn set_two_factor(&self, user_id: &Uuid, two_factor: Option<TwoFactor>) -> anyhow::Result<()> {
+        self.update_user(user_id, |user| user.two_factor = two_factor)
+    }
+
+    async fn spend_totp_step(&self, user_id: &Uuid, step: u64) -> anyhow::Result<bool> {
+        let mut tables = self.tables();
+        match tables.users.get_mut(user_id).and_then(|user| user.two_factor.as_mut()) {
+            Some(two_factor) if two_factor.last_used_step < step => {
+                two_factor.last_used_step = step;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn spend_recovery_code(&self, user_id: &Uuid, hashed_code: &str) -> anyhow::Result<bool> {
+        let mut tables = self.tables();
+        let two_factor = match tables.users.get_mut(user_id).and_then(|user| user.two_factor.as_mut()) {
+            Some(two_factor) => two_factor,
+            None => return Ok(false),
+        };
+        let before = two_factor.recovery_codes.len();
+        two_factor.recovery_codes.retain(|code| code != hashed_code);
+        Ok(two_factor.recovery_codes.len() < before)
+    }
+
+    async fn set_api_tokens(&self, user_id: &Uuid, api_tokens: Vec<ApiToken>) -> anyhow::Result<()> {
+        self.update_user(user_id, |user| user.api_tokens = api_tokens)
+    }
+
+    async fn bots_owned_by(&self, owner_id: &Uuid) -> anyhow::Result<Vec<UserData>> {
+        Ok(self
+            .tables()
+            .users
+            .values()
+            .filter(|user| user.kind == UserKind::Bot && user.owner_id.as_ref() == Some(owner_id))
+            .cloned()
+            .collect())
+    }
+
+    async fn users_in_room(&self, room_id: &Uuid) -> anyhow::Result<Vec<User>> {
+        Ok(self
+            .tables()
+            .users
+            .values()
+            .filter(|user| user.rooms.contains(room_id))
+            .map(|user| User { user_id: user.user_id, username: user.username.clone(), kind: user.kind })
+            .collect())
+    }
+}
+
+#[async_trait]
+impl RoomRepository for MemoryStorage {
+    async fn get_room(&self, room_id: &Uuid) -> anyhow::Result<Option<Room>> {
+        Ok(self.tables().rooms.get(room_id).cloned())
+    }
+
+    async fn create_room(&self, room: &Room) -> anyhow::Result<()> {
+        self.tables().rooms.insert(room.room_id, room.clone());
+        Ok(())
+    }
+
+    async fn add_room_member(&self, room_id: &Uuid, user_id: &Uuid) -> anyhow::Result<()> {
+        if let Some(room) = self.tables().rooms.get_mut(room_id) {
+            room.users.insert(*user_id);
+        }
+        Ok(())
+    }
+
+    async fn remove_room_member(&self, room_id: &Uuid, user_id: &Uuid) -> anyhow::Result<()> {
+        if let Some(room) = self.tables().rooms.get_mut(room_id) {
+            room.users.remove(user_id);
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl MessageRepository for MemoryStorage {
+    async fn create_message(&self, message: &BasicMessage) -> anyhow::Result<()> {
+        self.tables().messages.push(message.clone());
+        Ok(())
+    }
+
+    async fn get_message(&self, message_id: &Uuid) -> anyhow::Result<Option<BasicMessage>> {
+        Ok(self.tables().messages.iter().find(|message| message.message_id == message_id.0).cloned())
+    }
+
+    async fn delete_message(&self, message_id: &Uuid) -> anyhow::Result<()> {
+        self.tables().messages.retain(|message| message.message_id != message_id.0);
+        Ok(())
+    }
+
+    async fn room_messages(&self, room_id: &Uuid) -> anyhow::Result<Vec<BasicMessage>> {
+        let mut messages: Vec<BasicMessage> =
+            self.tables().messages.iter().filter(|message| message.room_id == room_id.0).cloned().collect();
+        // Stable, so messages from the same second keep their order
+        messages.sort_by_key(|message| message.timestamp);
+        Ok(messages)
+    }
+}
+
+#[async_trait]
+impl AuditRepository for MemoryStorage {
+    async fn record_audit_event(&self, event: &AuditEvent) -> anyhow::Result<()> {
+        self.tables().audit_events.push(event.clone());
+        Ok(())
+    }
+
+    async fn audit_events(&self, query: &AuditQuery) -> anyhow::Result<Vec<AuditEvent>> {
+        let mut events: Vec<AuditEvent> =
+            self.tables().audit_events.iter().rev().filter(|event| query.matches(event)).cloned().collect();
+        // Stable, so events from the same second stay newest first
+        events.sort_by_key(|event| std::cmp::Reverse(event.timestamp));
+        events.truncate(query.limit);
+        Ok(events)
+    }
+}
+
+#[async_trait]
+impl HealthRepository for MemoryStorage {
+    async fn check_health(&self) -> anyhow::Result<()> {
+        self.reachable()
+    }
+
+    async fn reconnect(&self) -> anyhow::Result<()> {
+        self.reachable()
+    }
+}
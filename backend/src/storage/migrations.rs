@@ -0,0 +1,145 @@
+use anyhow::Context;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use surrealdb::engine::remote::ws::Client;
+use surrealdb::sql::Uuid;
+use surrealdb::Surreal;
+
+// Adds the unique login and username indexes, which databases from before
+// migrations never enforced
+const UNIQUE_USERS_VERSION: u32 = 1;
+
+struct Migration {
+    version: u32,
+    name: &'static str,
+    script: &'static str,
+}
+
+// Applied in order at startup. A released migration is never edited, changes
+// go into a new one with the next version.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "initial",
+        script: include_str!("../../migrations/001_initial.surql"),
+    },
+    Migration {
+        version: 2,
+        name: "audit_events",
+        script: include_str!("../../migrations/002_audit_events.surql"),
+    },
+];
+
+#[derive(Deserialize)]
+struct AppliedVersion {
+    version: u32,
+}
+
+#[derive(Deserialize)]
+struct UserNames {
+    user_id: Uuid,
+    login: String,
+    username: String,
+}
+
+// Users sharing each value, only values used more than once
+fn clashes(users: &[UserNames], value: impl Fn(&UserNames) -> &str) -> BTreeMap<String, Vec<Uuid>> {
+    let mut by_value: BTreeMap<String, Vec<Uuid>> = BTreeMap::new();
+    for user in users {
+        by_value.entry(value(user).to_string()).or_default().push(user.user_id);
+    }
+    by_value.retain(|_, user_ids| user_ids.len() > 1);
+    for user_ids in by_value.values_mut() {
+        user_ids.sort_by_key(|user_id| user_id.0);
+    }
+    by_value
+}
+
+// Gets existing users ready for the unique indexes. Clashing usernames are
+// display names and get a suffix; clashing logins are reported, since only
+// an operator can tell which account to keep.
+async fn prepare_unique_users(db: &Surreal<Client>) -> anyhow::Result<()> {
+    let mut response = db.query("SELECT user_id, login, username FROM users;").await?;
+    let users: Vec<UserNames> = response.take(0)?;
+
+    let logins = clashes(&users, |user| &user.login);
+    if !logins.is_empty() {
+        let listed: Vec<String> = logins
+            .iter()
+            .map(|(login, user_ids)| {
+                let user_ids: Vec<String> = user_ids.iter().map(|user_id| user_id.0.to_string()).collect();
+                format!("{} (users {})", login, user_ids.join(", "))
+            })
+            .collect();
+        anyhow::bail!(
+            "logins must be unique but these belong to more than one user: {}; delete or change the extra accounts and restart",
+            listed.join("; ")
+        );
+    }
+
+    for (username, user_ids) in clashes(&users, |user| &user.username) {
+        // The first user keeps the name
+        for user_id in user_ids.iter().skip(1) {
+            let renamed = format!("{}-{}", username, &user_id.0.simple().to_string()[..8]);
+            db.query("UPDATE users SET username = $username WHERE user_id = $user_id;")
+                .bind(("username", &renamed))
+                .bind(("user_id", user_id))
+                .await?
+                .check()?;
+            log::warn!("Renamed duplicate username {} of user {} to {}", username, user_id.0, renamed);
+        }
+    }
+    Ok(())
+}
+
+async fn current_version(db: &Surreal<Client>) -> anyhow::Result<u32> {
+    let query = "SELECT version FROM schema_version ORDER BY version DESC LIMIT 1;";
+    let mut response = db.query(query).await?;
+    let applied: Option<AppliedVersion> = response.take(0)?;
+    Ok(applied.map_or(0, |applied| applied.version))
+}
+
+// Whether the database is at exactly the version this build migrates to
+pub async fn check_version(db: &Surreal<Client>) -> anyhow::Result<()> {
+    let current = current_version(db).await.context("failed to read schema version")?;
+    let latest = MIGRATIONS.last().map_or(0, |migration| migration.version);
+    if current != latest {
+        anyhow::bail!("database schema is at version {} but this build expects version {}", current, latest);
+    }
+    Ok(())
+}
+
+// Brings the schema up to date, refusing to touch a database that a newer
+// build has already migrated
+pub async fn migrate(db: &Surreal<Client>) -> anyhow::Result<()> {
+    let current = current_version(db).await.context("failed to read schema version")?;
+    let latest = MIGRATIONS.last().map_or(0, |migration| migration.version);
+    if current > latest {
+        anyhow::bail!(
+            "database schema is at version {} but this build only knows up to version {}, refusing to start",
+            current,
+            latest
+        );
+    }
+    for migration in MIGRATIONS.iter().filter(|migration| migration.version > current) {
+        log::info!("Applying schema migration {} ({})", migration.version, migration.name);
+        if migration.version == UNIQUE_USERS_VERSION {
+            prepare_unique_users(db)
+                .await
+                .with_context(|| format!("schema migration {} ({}) cannot be applied", migration.version, migration.name))?;
+        }
+        // The version record has a fixed id, so a second server migrating at
+        // the same time fails instead of applying the script twice
+        let query = format!(
+            "BEGIN TRANSACTION;\n{}\nCREATE type::thing('schema_version', $version) SET version = $version, name = $name, applied_at = time::now();\nCOMMIT TRANSACTION;",
+            migration.script
+        );
+        db.query(query)
+            .bind(("version", migration.version))
+            .bind(("name", migration.name))
+            .await?
+            .check()
+            .with_context(|| format!("schema migration {} ({}) failed", migration.version, migration.name))?;
+    }
+    Ok(())
+}
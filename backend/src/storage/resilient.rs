@@ -0,0 +1,284 @@
+use super::{connection_error, AuditRepository, HealthRepository, MessageRepository, RoomRepository, Storage, StorageUnavailable, UserRepository};
+use crate::audit::{AuditEvent, AuditQuery};
+use crate::bots::ApiToken;
+use crate::config::DatabaseConfig;
+use crate::structs::{AccountState, ConnectionState, Room, User, UserData};
+use crate::two_factor::TwoFactor;
+use actix_web::rt::time::{sleep, timeout};
+use async_trait::async_trait;
+use black_signal_protocol::BasicMessage;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+use surrealdb::sql::Uuid;
+
+// Doubling waits between connection attempts
+pub struct Backoff {
+    next: Duration,
+    max: Duration,
+}
+
+impl Backoff {
+    pub fn new(config: &DatabaseConfig) -> Self {
+        Backoff {
+            next: Duration::from_millis(config.retry_initial_ms),
+            max: Duration::from_millis(config.retry_max_ms),
+        }
+    }
+
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = (self.next * 2).min(self.max);
+        delay
+    }
+}
+
+struct Shared {
+    inner: Arc<dyn Storage>,
+    config: DatabaseConfig,
+    available: AtomicBool,
+    recovering: AtomicBool,
+}
+
+// Puts a time limit on every call to the wrapped backend and notices when
+// its connection is lost, either from a call that fails or times out or from
+// a periodic health check. Until a background task has reconnected, calls
+// fail straight away with StorageUnavailable instead of piling up.
+pub struct ResilientStorage {
+    shared: Arc<Shared>,
+}
+
+impl ResilientStorage {
+    pub fn new(inner: Arc<dyn Storage>, config: &DatabaseConfig) -> Self {
+        let shared = Arc::new(Shared {
+            inner,
+            config: config.clone(),
+            available: AtomicBool::new(true),
+            recovering: AtomicBool::new(false),
+        });
+        watch(Arc::downgrade(&shared));
+        ResilientStorage { shared }
+    }
+
+    async fn guard<T>(&self, call: impl Future<Output = anyhow::Result<T>>) -> anyhow::Result<T> {
+        if !self.shared.available.load(Ordering::SeqCst) {
+            return Err(StorageUnavailable.into());
+        }
+        match timeout(self.shared.query_timeout(), call).await {
+            Ok(Ok(value)) => Ok(value),
+            // Rejected queries, like a taken username, say nothing about the
+            // connection, transport errors are confirmed by a health check
+            Ok(Err(e)) if connection_error(&e) => {
+                let shared = self.shared.clone();
+                actix_web::rt::spawn(async move {
+                    if let Err(health) = shared.check().await {
+                        shared.connection_lost(health);
+                    }
+                });
+                Err(e)
+            }
+            Ok(Err(e)) => Err(e),
+            Err(_) => {
+                self.shared.connection_lost(anyhow::anyhow!("query timed out"));
+                Err(StorageUnavailable.into())
+            }
+        }
+    }
+}
+
+impl Shared {
+    fn query_timeout(&self) -> Duration {
+        Duration::from_secs(self.config.query_timeout_secs)
+    }
+
+    async fn check(&self) -> anyhow::Result<()> {
+        match timeout(self.query_timeout(), self.inner.check_health()).await {
+            Ok(result) => result,
+            Err(_) => anyhow::bail!("health check timed out"),
+        }
+    }
+
+    fn connection_lost(self: &Arc<Self>, cause: anyhow::Error) {
+        self.available.store(false, Ordering::SeqCst);
+        if self.recovering.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        log::error!("Lost the storage connection, reconnecting: fn connection_lost, error: {:#}", cause);
+        actix_web::rt::spawn(recover(Arc::downgrade(self)));
+    }
+}
+
+// Reconnects with back-off until the backend is healthy again
+async fn recover(shared: Weak<Shared>) {
+    let mut backoff = match shared.upgrade() {
+        Some(shared) => Backoff::new(&shared.config),
+        None => return,
+    };
+    loop {
+        sleep(backoff.next_delay()).await;
+        let Some(shared) = shared.upgrade() else { return };
+        let attempt = async {
+            match timeout(shared.query_timeout(), shared.inner.reconnect()).await {
+                Ok(result) => result?,
+                Err(_) => anyhow::bail!("reconnect timed out"),
+            }
+            shared.check().await
+        };
+        match attempt.await {
+            Ok(()) => {
+                shared.recovering.store(false, Ordering::SeqCst);
+                shared.available.store(true, Ordering::SeqCst);
+                log::info!("Storage connection restored");
+                return;
+            }
+            Err(e) => log::warn!("Failed to reconnect to storage: fn recover, error: {:#}", e),
+        }
+    }
+}
+
+// Checks the connection periodically for as long as the storage is in use
+fn watch(shared: Weak<Shared>) {
+    actix_web::rt::spawn(async move {
+        loop {
+            let interval = match shared.upgrade() {
+                Some(shared) => Duration::from_secs(shared.config.health_check_interval_secs),
+                None => return,
+            };
+            sleep(interval).await;
+            let Some(shared) = shared.upgrade() else { return };
+            if shared.recovering.load(Ordering::SeqCst) {
+                continue;
+            }
+            if let Err(e) = shared.check().await {
+                shared.connection_lost(e);
+            }
+        }
+    });
+}
+
+#[async_trait]
+impl UserRepository for ResilientStorage {
+    async fn get_user(&self, user_id: &Uuid) -> anyhow::Result<Option<UserData>> {
+        self.guard(self.shared.inner.get_user(user_id)).await
+    }
+
+    async fn get_user_by_login(&self, login: &str) -> anyhow::Result<Option<UserData>> {
+        self.guard(self.shared.inner.get_user_by_login(login)).await
+    }
+
+    async fn create_user(&self, user: &UserData) -> anyhow::Result<()> {
+        self.guard(self.shared.inner.create_user(user)).await
+    }
+
+    async fn delete_user(&self, user_id: &Uuid) -> anyhow::Result<()> {
+        self.guard(self.shared.inner.delete_user(user_id)).await
+    }
+
+    async fn set_username(&self, user_id: &Uuid, username: &str) -> anyhow::Result<()> {
+        self.guard(self.shared.inner.set_username(user_id, username)).await
+    }
+
+    async fn set_password(&self, user_id: &Uuid, hashed_password: &str, session_epoch: u64) -> anyhow::Result<()> {
+        self.guard(self.shared.inner.set_password(user_id, hashed_password, session_epoch)).await
+    }
+
+    async fn set_account_state(&self, user_id: &Uuid, account_state: AccountState) -> anyhow::Result<()> {
+        self.guard(self.shared.inner.set_account_state(user_id, account_state)).await
+    }
+
+    async fn set_status(&self, user_id: &Uuid, status: ConnectionState) -> anyhow::Result<()> {
+        self.guard(self.shared.inner.set_status(user_id, status)).await
+    }
+
+    async fn set_two_factor(&self, user_id: &Uuid, two_factor: Option<TwoFactor>) -> anyhow::Result<()> {
+        self.guard(self.shared.inner.set_two_factor(user_id, two_factor)).await
+    }
+
+    async fn spend_totp_step(&self, user_id: &Uuid, step: u64) -> anyhow::Result<bool> {
+        self.guard(self.shared.inner.spend_totp_step(user_id, step)).await
+    }
+
+    async fn spend_recovery_code(&self, user_id: &Uuid, hashed_code: &str) -> anyhow::Result<bool> {
+        self.guard(self.shared.inner.spend_recovery_code(user_id, hashed_code)).await
+    }
+
+    async fn set_api_tokens(&self, user_id: &Uuid, api_tokens: Vec<ApiToken>) -> anyhow::Result<()> {
+        self.guard(self.shared.inner.set_api_tokens(user_id, api_tokens)).await
+    }
+
+    async fn bots_owned_by(&self, owner_id: &Uuid) -> anyhow::Result<Vec<UserData>> {
+        self.guard(self.shared.inner.bots_owned_by(owner_id)).await
+    }
+
+    async fn users_in_room(&self, room_id: &Uuid) -> anyhow::Result<Vec<User>> {
+        self.guard(self.shared.inner.users_in_room(room_id)).await
+    }
+}
+
+#[async_trait]
+impl RoomRepository for ResilientStorage {
+    async fn get_room(&self, room_id: &Uuid) -> anyhow::Result<Option<Room>> {
+        self.guard(self.shared.inner.get_room(room_id)).await
+    }
+
+    async fn create_room(&self, room: &Room) -> anyhow::Result<()> {
+        self.guard(self.shared.inner.create_room(room)).await
+    }
+
+    async fn add_room_member(&self, room_id: &Uuid, user_id: &Uuid) -> anyhow::Result<()> {
+        self.guard(self.shared.inner.add_room_member(room_id, user_id)).await
+    }
+
+    async fn remove_room_member(&self, room_id: &Uuid, user_id: &Uuid) -> anyhow::Result<()> {
+        self.guard(self.shared.inner.remove_room_member(room_id, user_id)).await
+    }
+}
+
+#[async_trait]
+impl MessageRepository for ResilientStorage {
+    async fn create_message(&self, message: &BasicMessage) -> anyhow::Result<()> {
+        self.guard(self.shared.inner.create_message(message)).await
+    }
+
+    async fn get_message(&self, message_id: &Uuid) -> anyhow::Result<Option<BasicMessage>> {
+        self.guard(self.shared.inner.get_message(message_id)).await
+    }
+
+    async fn delete_message(&self, message_id: &Uuid) -> anyhow::Result<()> {
+        self.guard(self.shared.inner.delete_message(message_id)).await
+    }
+
+    async fn room_messages(&self, room_id: &Uuid) -> anyhow::Result<Vec<BasicMessage>> {
+        self.guard(self.shared.inner.room_messages(room_id)).await
+    }
+}
+
+#[async_trait]
+impl AuditRepository for ResilientStorage {
+    async fn record_audit_event(&self, event: &AuditEvent) -> anyhow::Result<()> {
+        self.guard(self.shared.inner.record_audit_event(event)).await
+    }
+
+    async fn audit_events(&self, query: &AuditQuery) -> anyhow::Result<Vec<AuditEvent>> {
+        self.guard(self.shared.inner.audit_events(query)).await
+    }
+}
+
+#[async_trait]
+impl HealthRepository for ResilientStorage {
+    async fn check_health(&self) -> anyhow::Result<()> {
+        if !self.is_available() {
+            return Err(StorageUnavailable.into());
+        }
+        self.shared.check().await
+    }
+
+    async fn reconnect(&self) -> anyhow::Result<()> {
+        self.shared.inner.reconnect().await
+    }
+
+    fn is_available(&self) -> bool {
+        self.shared.available.load(Ordering::SeqCst)
+    }
+}
@@ -0,0 +1,368 @@
+use super::migrations::{check_version, migrate};
+use super::resilient::Backoff;
+use super::{AuditRepository, HealthRepository, MessageRepository, RoomRepository, UniqueViolation, UserRepository};
+use crate::audit::{AuditEvent, AuditQuery};
+use crate::bots::ApiToken;
+use crate::config::DatabaseConfig;
+use crate::structs::{AccountState, ConnectionState, Room, User, UserData};
+use crate::two_factor::TwoFactor;
+use anyhow::Context;
+use async_trait::async_trait;
+use black_signal_protocol::BasicMessage;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use surrealdb::engine::remote::ws::{Client, Ws};
+use surrealdb::opt::auth::Root;
+use surrealdb::sql::Uuid;
+use surrealdb::Surreal;
+
+pub struct SurrealStorage {
+    // Replaced by a fresh client when the connection is re-established
+    db: RwLock<Surreal<Client>>,
+    config: DatabaseConfig,
+}
+
+async fn open(config: &DatabaseConfig) -> anyhow::Result<Surreal<Client>> {
+    let db = Surreal::new::<Ws>(config.address.as_str())
+        .await
+        .with_context(|| format!("failed to connect to database at {}", config.address))?;
+    db.signin(Root {
+        username: &config.username,
+        password: &config.password,
+    })
+    .await
+    .context("failed to login to database")?;
+    db.use_ns(&config.namespace)
+        .use_db(&config.database)
+        .await
+        .context("failed to use namespace of database")?;
+    Ok(db)
+}
+
+impl SurrealStorage {
+    // Retries with back-off, the database often starts after the server
+    pub async fn connect(config: &DatabaseConfig) -> anyhow::Result<Self> {
+        let mut backoff = Backoff::new(config);
+        let mut attempt = 1;
+        let db = loop {
+            match open(config).await {
+                Ok(db) => break db,
+                Err(e) if config.connect_attempts == 0 || attempt < config.connect_attempts => {
+                    let delay = backoff.next_delay();
+                    log::warn!(
+                        "Failed to connect to database, attempt {}, retrying in {:?}: fn connect, error: {:#}",
+                        attempt,
+                        delay,
+                        e
+                    );
+                    actix_web::rt::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e.context(format!("gave up after {} attempts", attempt))),
+            }
+        };
+        migrate(&db).await?;
+        Ok(SurrealStorage { db: RwLock::new(db), config: config.clone() })
+    }
+
+    fn db(&self) -> Surreal<Client> {
+        self.db.read().clone()
+    }
+}
+
+// Turns SurrealDB's unique index error into UniqueViolation so callers can
+// tell a taken name from a broken database
+fn unique_violation(error: surrealdb::Error) -> anyhow::Error {
+    let message = error.to_string();
+    let index = message
+        .split_once("Database index `")
+        .and_then(|(_, rest)| rest.split_once('`'))
+        .filter(|_| message.contains("already contains"))
+        .map(|(index, _)| index.to_string());
+    match index {
+        Some(index) => UniqueViolation { index }.into(),
+        None => error.into(),
+    }
+}
+
+#[async_trait]
+impl UserRepository for SurrealStorage {
+    async fn get_user(&self, user_id: &Uuid) -> anyhow::Result<Option<UserData>> {
+        let query = "SELECT * FROM users WHERE user_id = $user_id;";
+        let mut response = self.db().query(query).bind(("user_id", user_id)).await?;
+        Ok(response.take(0)?)
+    }
+
+    async fn get_user_by_login(&self, login: &str) -> anyhow::Result<Option<UserData>> {
+        let query = "SELECT * FROM users WHERE login = $login;";
+        let mut response = self.db().query(query).bind(("login", login)).await?;
+        Ok(response.take(0)?)
+    }
+
+    async fn create_user(&self, user: &UserData) -> anyhow::Result<()> {
+        let _: Vec<UserData> = self.db().create("users").content(user.clone()).await.map_err(unique_violation)?;
+        Ok(())
+    }
+
+    async fn delete_user(&self, user_id: &Uuid) -> anyhow::Result<()> {
+        let query = "DELETE users WHERE user_id = $user_id; UPDATE rooms SET users -= $user_id;";
+        self.db().query(query).bind(("user_id", user_id)).await?.check()?;
+        Ok(())
+    }
+
+    async fn set_username(&self, user_id: &Uuid, username: &str) -> anyhow::Result<()> {
+        let query = "UPDATE users SET username = $username WHERE user_id = $user_id;";
+        self.db()
+            .query(query)
+            .bind(("username", username))
+            .bind(("user_id", user_id))
+            .await?
+            .check()
+            .map_err(unique_violation)?;
+        Ok(())
+    }
+
+    async fn set_password(&self, user_id: &Uuid, hashed_password: &str, session_epoch: u64) -> anyhow::Result<()> {
+        let query = "UPDATE users SET hashed_password = $hashed_password, session_epoch = $session_epoch WHERE user_id = $user_id;";
+        self.db()
+            .query(query)
+            .bind(("hashed_password", hashed_password))
+            .bind(("session_epoch", session_epoch))
+            .bind(("user_id", user_id))
+            .await?
+            .check()?;
+        Ok(())
+    }
+
+    async fn set_account_state(&self, user_id: &Uuid, account_state: AccountState) -> anyhow::Result<()> {
+        let query = "UPDATE users SET account_state = $account_state WHERE user_id = $user_id;";
+        self.db()
+            .query(query)
+            .bind(("account_state", account_state))
+            .bind(("user_id", user_id))
+            .await?
+            .check()?;
+        Ok(())
+    }
+
+    async fn set_status(&self, user_id: &Uuid, status: ConnectionState) -> anyhow::Result<()> {
+        let query = "UPDATE users SET status = $status WHERE user_id = $user_id;";
+        self.db()
+            .query(query)
+            .bind(("status", status))
+            .bind(("user_id", user_id))
+            .await?
+            .check()?;
+        Ok(())
+    }
+
+    async fn set_two_factor(&self, user_id: &Uuid, two_factor: Option<TwoFactor>) -> anyhow::Result<()> {
+        let query = "UPDATE users SET two_factor = $two_factor WHERE user_id = $user_id;";
+        self.db()
+            .query(query)
+            .bind(("two_factor", two_factor))
+            .bind(("user_id", user_id))
+            .await?
+            .check()?;
+        Ok(())
+    }
+
+    // The condition makes the update a compare-and-swap, so concurrent
+    // logins cannot both spend the same code
+    async fn spend_totp_step(&self, user_id: &Uuid, step: u64) -> anyhow::Result<bool> {
+        let query = "UPDATE users SET two_factor.last_used_step = $step WHERE user_id = $user_id AND two_factor.last_used_step < $step;";
+        let mut response = self.db().query(query).bind(("step", step)).bind(("user_id", user_id)).await?;
+        let updated: Vec<UserData> = response.take(0)?;
+        Ok(!updated.is_empty())
+    }
+
+    async fn spend_recovery_code(&self, user_id: &Uuid, hashed_code: &str) -> anyhow::Result<bool> {
+        let query = "UPDATE users SET two_factor.recovery_codes -= $code WHERE user_id = $user_id AND two_factor.recovery_codes CONTAINS $code;";
+        let mut response = self.db().query(query).bind(("code", hashed_code)).bind(("user_id", user_id)).await?;
+        let updated: Vec<UserData> = response.take(0)?;
+        Ok(!updated.is_empty())
+    }
+
+    async fn set_api_tokens(&self, user_id: &Uuid, api_tokens: Vec<ApiToken>) -> anyhow::Result<()> {
+        let query = "UPDATE users SET api_tokens = $api_tokens WHERE user_id = $user_id;";
+        self.db()
+            .query(query)
+            .bind(("api_tokens", api_tokens))
+            .bind(("user_id", user_id))
+            .await?
+            .check()?;
+        Ok(())
+    }
+
+    async fn bots_owned_by(&self, owner_id: &Uuid) -> anyhow::Result<Vec<UserData>> {
+        let query = "SELECT * FROM users WHERE kind = 'Bot' AND owner_id = $owner_id;";
+        let mut response = self.db().query(query).bind(("owner_id", owner_id)).await?;
+        Ok(response.take(0)?)
+    }
+
+    async fn users_in_room(&self, room_id: &Uuid) -> anyhow::Result<Vec<User>> {
+        let query = "SELECT user_id, username, kind FROM users WHERE $room_id IN rooms;";
+        let mut response = self.db().query(query).bind(("room_id", room_id)).await?;
+        Ok(response.take(0)?)
+    }
+}
+
+#[async_trait]
+impl RoomRepository for SurrealStorage {
+    async fn get_room(&self, room_id: &Uuid) -> anyhow::Result<Option<Room>> {
+        let query = "SELECT * FROM rooms WHERE room_id = $room_id;";
+        let mut response = self.db().query(query).bind(("room_id", room_id)).await?;
+        Ok(response.take(0)?)
+    }
+
+    async fn create_room(&self, room: &Room) -> anyhow::Result<()> {
+        let _: Vec<Room> = self.db().create("rooms").content(room.clone()).await?;
+        Ok(())
+    }
+
+    async fn add_room_member(&self, room_id: &Uuid, user_id: &Uuid) -> anyhow::Result<()> {
+        let query = "UPDATE rooms SET users += $user_id WHERE room_id = $room_id;";
+        self.db()
+            .query(query)
+            .bind(("user_id", user_id))
+            .bind(("room_id", room_id))
+            .await?
+            .check()?;
+        Ok(())
+    }
+
+    async fn remove_room_member(&self, room_id: &Uuid, user_id: &Uuid) -> anyhow::Result<()> {
+        let query = "UPDATE rooms SET users -= $user_id WHERE room_id = $room_id;";
+        self.db()
+            .query(query)
+            .bind(("user_id", user_id))
+            .bind(("room_id", room_id))
+            .await?
+            .check()?;
+        Ok(())
+    }
+}
+
+// A chat message as it is stored. The protocol carries plain uuids, which
+// are turned into SurrealDB uuids here so the fields keep their uuid type.
+#[derive(Serialize, Deserialize, Clone)]
+struct StoredMessage {
+    content: String,
+    sender_id: Uuid,
+    timestamp: u64,
+    message_id: Uuid,
+    room_id: Uuid,
+    ws_id: Uuid,
+}
+
+impl From<&BasicMessage> for StoredMessage {
+    fn from(message: &BasicMessage) -> Self {
+        StoredMessage {
+            content: message.content.clone(),
+            sender_id: message.sender_id.into(),
+            timestamp: message.timestamp,
+            message_id: message.message_id.into(),
+            room_id: message.room_id.into(),
+            ws_id: message.ws_id.into(),
+        }
+    }
+}
+
+impl From<StoredMessage> for BasicMessage {
+    fn from(message: StoredMessage) -> Self {
+        BasicMessage {
+            content: message.content,
+            sender_id: message.sender_id.into(),
+            timestamp: message.timestamp,
+            message_id: message.message_id.into(),
+            room_id: message.room_id.into(),
+            ws_id: message.ws_id.into(),
+        }
+    }
+}
+
+#[async_trait]
+impl MessageRepository for SurrealStorage {
+    async fn create_message(&self, message: &BasicMessage) -> anyhow::Result<()> {
+        let stored = StoredMessage::from(message);
+        let _: Option<StoredMessage> = self.db().create(("messages", stored.message_id)).content(stored).await?;
+        Ok(())
+    }
+
+    async fn get_message(&self, message_id: &Uuid) -> anyhow::Result<Option<BasicMessage>> {
+        let stored: Option<StoredMessage> = self.db().select(("messages", *message_id)).await?;
+        Ok(stored.map(BasicMessage::from))
+    }
+
+    async fn delete_message(&self, message_id: &Uuid) -> anyhow::Result<()> {
+        let _: Option<StoredMessage> = self.db().delete(("messages", *message_id)).await?;
+        Ok(())
+    }
+
+    async fn room_messages(&self, room_id: &Uuid) -> anyhow::Result<Vec<BasicMessage>> {
+        let query = "SELECT * FROM messages WHERE room_id = $room_id ORDER BY timestamp ASC;";
+        let mut response = self.db().query(query).bind(("room_id", room_id)).await?;
+        let stored: Vec<StoredMessage> = response.take(0)?;
+        Ok(stored.into_iter().map(BasicMessage::from).collect())
+    }
+}
+
+#[async_trait]
+impl AuditRepository for SurrealStorage {
+    async fn record_audit_event(&self, event: &AuditEvent) -> anyhow::Result<()> {
+        let _: Option<AuditEvent> = self.db().create(("audit_events", event.event_id)).content(event.clone()).await?;
+        Ok(())
+    }
+
+    // Only the filters that are set end up in the WHERE clause, every value
+    // is bound rather than formatted in
+    async fn audit_events(&self, query: &AuditQuery) -> anyhow::Result<Vec<AuditEvent>> {
+        let mut conditions = Vec::new();
+        if query.user_id.is_some() {
+            conditions.push("(actor_id = $user_id OR target_id = $user_id)");
+        }
+        if query.room_id.is_some() {
+            conditions.push("room_id = $room_id");
+        }
+        if query.kind.is_some() {
+            conditions.push("kind = $kind");
+        }
+        if query.since.is_some() {
+            conditions.push("timestamp >= $since");
+        }
+        if query.until.is_some() {
+            conditions.push("timestamp <= $until");
+        }
+        let filter = match conditions.is_empty() {
+            true => String::new(),
+            false => format!(" WHERE {}", conditions.join(" AND ")),
+        };
+        let statement = format!("SELECT * FROM audit_events{} ORDER BY timestamp DESC LIMIT $limit;", filter);
+        let mut response = self
+            .db()
+            .query(statement)
+            .bind(("user_id", query.user_id))
+            .bind(("room_id", query.room_id))
+            .bind(("kind", query.kind))
+            .bind(("since", query.since))
+            .bind(("until", query.until))
+            .bind(("limit", query.limit))
+            .await?;
+        Ok(response.take(0)?)
+    }
+}
+
+#[async_trait]
+impl HealthRepository for SurrealStorage {
+    async fn check_health(&self) -> anyhow::Result<()> {
+        check_version(&self.db()).await
+    }
+
+    async fn reconnect(&self) -> anyhow::Result<()> {
+        let db = open(&self.config).await?;
+        // The server may have come back empty, e.g. restarted without its data
+        migrate(&db).await?;
+        *self.db.write() = db;
+        Ok(())
+    }
+}
@@ -8,6 +8,11 @@ use std::collections::HashSet;
 
 use surrealdb::sql::Uuid;
 
+use black_signal_protocol::{NegotiatedProtocol, ProtocolErrorCode, ProtocolErrorMessage};
+
+use crate::bots::{ApiScope, ApiToken};
+use crate::two_factor::TwoFactor;
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct UserData {
     pub user_id: Uuid,
@@ -16,6 +21,43 @@ pub struct UserData {
     pub hashed_password: String,
     pub status: ConnectionState,
     pub rooms: Vec<Uuid>,
+    #[serde(default)]
+    pub is_admin: bool,
+    #[serde(default)]
+    pub session_epoch: u64,
+    #[serde(default)]
+    pub account_state: AccountState,
+    #[serde(default)]
+    pub two_factor: Option<TwoFactor>,
+    #[serde(default)]
+    pub kind: UserKind,
+    // The human who created a bot and may manage it
+    #[serde(default)]
+    pub owner_id: Option<Uuid>,
+    // Only bots hold API tokens
+    #[serde(default)]
+    pub api_tokens: Vec<ApiToken>,
+}
+
+impl UserData {
+    pub fn two_factor_enabled(&self) -> bool {
+        self.two_factor.as_ref().is_some_and(|two_factor| two_factor.enabled)
+    }
+}
+
+// Accounts created before verification existed deserialize as Active
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub enum AccountState {
+    PendingVerification,
+    #[default]
+    Active,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub enum UserKind {
+    #[default]
+    Human,
+    Bot,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -40,6 +82,11 @@ pub struct Room {
     pub users: HashSet<Uuid>,
 }
 
+#[derive(Deserialize)]
+pub struct RoomUsers {
+    pub users: Vec<Uuid>,
+}
+
 #[derive(Deserialize, Validate)]
 pub struct LoginForm {
     #[validate(email)]
@@ -49,12 +96,115 @@ pub struct LoginForm {
 }
 
 #[derive(Deserialize)]
-pub struct User {
-    pub user_id: Uuid,
+pub struct TokenRequestForm {
+    #[serde(flatten)]
+    pub login: LoginForm,
+    // Required when the account has two-factor authentication enabled
+    pub code: Option<String>,
+    pub recovery_code: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct RefreshTokenForm {
+    pub refresh_token: String,
+}
+
+#[derive(Deserialize)]
+pub struct PasswordChangeForm {
+    pub current_password: String,
+    pub new_password: String,
+}
+
+#[derive(Deserialize)]
+pub struct ForgotPasswordForm {
+    pub login: String,
+}
+
+#[derive(Deserialize)]
+pub struct ResetPasswordForm {
+    pub token: String,
+    pub new_password: String,
+}
+
+#[derive(Deserialize)]
+pub struct VerifyEmailForm {
+    pub token: String,
+}
+
+#[derive(Deserialize)]
+pub struct TwoFactorCodeForm {
+    pub code: String,
+}
+
+// Either an authenticator code or one of the recovery codes
+#[derive(Deserialize)]
+pub struct TwoFactorLoginForm {
+    pub code: Option<String>,
+    pub recovery_code: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct TwoFactorDisableForm {
+    pub password: String,
+    pub code: Option<String>,
+    pub recovery_code: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct CreateBotForm {
     pub username: String,
 }
 
 #[derive(Deserialize)]
-pub struct RoomUsers {
-    pub users: Vec<Uuid>,
+pub struct CreateApiTokenForm {
+    pub name: String,
+    pub scopes: Vec<ApiScope>,
+}
+
+#[derive(Deserialize)]
+pub struct PostMessageForm {
+    pub content: String,
+}
+
+#[derive(Deserialize)]
+pub struct HandshakeQuery {
+    pub protocol_version: Option<String>,
+    pub capabilities: Option<String>,
+}
+
+impl HandshakeQuery {
+    // Clients that predate the handshake send neither field and are served
+    // the legacy protocol.
+    pub fn negotiate(&self) -> Result<NegotiatedProtocol, ProtocolErrorMessage> {
+        let requested_version = match &self.protocol_version {
+            Some(version) => match version.parse::<u32>() {
+                Ok(parsed) => parsed,
+                Err(_) => {
+                    return Err(ProtocolErrorMessage::new(
+                        ProtocolErrorCode::MalformedHandshake,
+                        format!("Invalid protocol version: {}", version),
+                    ))
+                }
+            },
+            None => return Ok(NegotiatedProtocol::legacy()),
+        };
+        let capabilities: Vec<String> = self
+            .capabilities
+            .as_deref()
+            .unwrap_or("")
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(String::from)
+            .collect();
+        NegotiatedProtocol::negotiate(requested_version, &capabilities)
+    }
+}
+
+#[derive(Deserialize)]
+pub struct User {
+    pub user_id: Uuid,
+    pub username: String,
+    #[serde(default)]
+    pub kind: UserKind,
 }
@@ -0,0 +1,240 @@
+use crate::kv::KvStore;
+use crate::one_time_token::{generate_token, hash_token};
+use crate::structs::UserData;
+use chrono::Utc;
+use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::time::Duration;
+use surrealdb::sql::Uuid;
+
+const ISSUER: &str = "black_signal";
+const REFRESH_TOKEN_PREFIX: &str = "refresh_token";
+// Refresh tokens that were already rotated, kept until they would have
+// expired so presenting one again can be noticed
+const SPENT_REFRESH_PREFIX: &str = "spent_refresh_token";
+// The current refresh token of each token session, so revoking the session
+// can delete it
+const SESSION_REFRESH_PREFIX: &str = "session_refresh_token";
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct TokenConfig {
+    // HMAC secret for access tokens, a random one is used when unset
+    pub secret: Option<String>,
+    pub access_ttl_secs: u64,
+    pub refresh_ttl_secs: u64,
+}
+
+impl Default for TokenConfig {
+    fn default() -> Self {
+        TokenConfig {
+            secret: None,
+            access_ttl_secs: 15 * 60,
+            refresh_ttl_secs: 30 * 24 * 60 * 60,
+        }
+    }
+}
+
+impl TokenConfig {
+    // Overrides settings with JWT_* environment variables
+    pub fn apply_env(&mut self) {
+        if let Ok(secret) = env::var("JWT_SECRET") {
+            self.secret = Some(secret);
+        }
+        if let Some(ttl) = env::var("JWT_ACCESS_TTL_SECS").ok().and_then(|value| value.parse().ok()) {
+            self.access_ttl_secs = ttl;
+        }
+        if let Some(ttl) = env::var("JWT_REFRESH_TTL_SECS").ok().and_then(|value| value.parse().ok()) {
+            self.refresh_ttl_secs = ttl;
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Claims {
+    pub sub: Uuid,
+    // Shared by every token issued from one login, like a cookie session id
+    pub sid: Uuid,
+    // Tokens from before a password change stop working, as sessions do
+    pub epoch: u64,
+    pub iss: String,
+    pub iat: i64,
+    pub exp: i64,
+}
+
+// What a refresh token stands for, stored in Redis under its hash
+#[derive(Serialize, Deserialize)]
+struct RefreshGrant {
+    user_id: Uuid,
+    session_id: Uuid,
+    session_epoch: u64,
+}
+
+// What presenting a refresh token turned out to be
+pub enum Redemption {
+    Granted { user_id: Uuid, session_id: Uuid, session_epoch: u64 },
+    // Spent before, so a copy of it is out there and the session has to end
+    Reused { user_id: Uuid, session_id: Uuid },
+    Invalid,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct TokenPair {
+    pub session_id: Uuid,
+    pub access_token: String,
+    pub token_type: &'static str,
+    pub expires_in: u64,
+    pub refresh_token: String,
+}
+
+// Short-lived signed access tokens plus opaque refresh tokens that are
+// rotated on every use
+pub struct TokenService {
+    encoding_key: EncodingKey,
+    decoding_key: DecodingKey,
+    validation: Validation,
+    access_ttl: Duration,
+    refresh_ttl: Duration,
+    kv: KvStore,
+}
+
+impl TokenService {
+    pub fn new(kv: KvStore, config: TokenConfig) -> Self {
+        let secret = match config.secret {
+            Some(secret) => secret.into_bytes(),
+            None => {
+                // Config validation insists on a secret whenever several
+                // instances could share tokens, so this is a single instance
+                log::warn!("JWT_SECRET is not set, access tokens will not survive a restart");
+                let mut secret = vec![0u8; 32];
+                rand::thread_rng().fill_bytes(&mut secret);
+                secret
+            }
+        };
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.set_issuer(&[ISSUER]);
+        TokenService {
+            encoding_key: EncodingKey::from_secret(&secret),
+            decoding_key: DecodingKey::from_secret(&secret),
+            validation,
+            access_ttl: Duration::from_secs(config.access_ttl_secs),
+            refresh_ttl: Duration::from_secs(config.refresh_ttl_secs),
+            kv,
+        }
+    }
+
+    // Starts a new token session for a user who just logged in
+    pub async fn issue(&self, user: &UserData) -> Option<TokenPair> {
+        self.issue_for_session(user, Uuid::new_v4()).await
+    }
+
+    async fn issue_for_session(&self, user: &UserData, session_id: Uuid) -> Option<TokenPair> {
+        let now = Utc::now().timestamp();
+        let claims = Claims {
+            sub: user.user_id,
+            sid: session_id,
+            epoch: user.session_epoch,
+            iss: ISSUER.to_string(),
+            iat: now,
+            exp: now + self.access_ttl.as_secs() as i64,
+        };
+        let access_token = match encode(&Header::default(), &claims, &self.encoding_key) {
+            Ok(token) => token,
+            Err(e) => {
+                log::error!("Failed to sign access token: fn issue_for_session, error: {:?}", e);
+                return None;
+            }
+        };
+        let refresh_token = generate_token();
+        let grant = RefreshGrant { user_id: user.user_id, session_id, session_epoch: user.session_epoch };
+        let grant = serde_json::to_string(&grant).ok()?;
+        let stored = async {
+            self.kv.set_ex(&refresh_key(&refresh_token), &grant, self.refresh_ttl).await?;
+            self.kv.set_ex(&session_refresh_key(&session_id), &hash_token(&refresh_token), self.refresh_ttl).await
+        };
+        if let Err(e) = stored.await {
+            log::error!("Failed to store refresh token: fn issue_for_session, error: {:?}", e);
+            return None;
+        }
+        Some(TokenPair {
+            session_id,
+            access_token,
+            token_type: "Bearer",
+            expires_in: self.access_ttl.as_secs(),
+            refresh_token,
+        })
+    }
+
+    // Checks the signature and expiry, the caller still has to compare the
+    // epoch against the user
+    pub fn verify_access(&self, token: &str) -> Option<Claims> {
+        match decode::<Claims>(token, &self.decoding_key, &self.validation) {
+            Ok(data) => Some(data.claims),
+            Err(e) => {
+                log::debug!("Rejected access token: fn verify_access, error: {:?}", e);
+                None
+            }
+        }
+    }
+
+    // Spends a refresh token, returning who it was issued to
+    pub async fn redeem_refresh(&self, refresh_token: &str) -> Redemption {
+        let token_hash = hash_token(refresh_token);
+        let redeemed = async {
+            if let Some(grant) = self.kv.take(&refresh_hash_key(&token_hash)).await? {
+                self.kv.set_ex(&spent_refresh_key(&token_hash), &grant, self.refresh_ttl).await?;
+                return Ok(Some((grant, false)));
+            }
+            let spent = self.kv.get(&spent_refresh_key(&token_hash)).await?;
+            Ok::<_, anyhow::Error>(spent.map(|grant| (grant, true)))
+        };
+        let (grant, reused) = match redeemed.await {
+            Ok(Some(redeemed)) => redeemed,
+            Ok(None) => return Redemption::Invalid,
+            Err(e) => {
+                log::error!("Failed to redeem refresh token: fn redeem_refresh, error: {:?}", e);
+                return Redemption::Invalid;
+            }
+        };
+        match serde_json::from_str::<RefreshGrant>(&grant) {
+            Ok(grant) if reused => Redemption::Reused { user_id: grant.user_id, session_id: grant.session_id },
+            Ok(grant) => Redemption::Granted {
+                user_id: grant.user_id,
+                session_id: grant.session_id,
+                session_epoch: grant.session_epoch,
+            },
+            Err(_) => Redemption::Invalid,
+        }
+    }
+
+    // A fresh pair for the same token session, after a refresh
+    pub async fn rotate(&self, user: &UserData, session_id: Uuid) -> Option<TokenPair> {
+        self.issue_for_session(user, session_id).await
+    }
+}
+
+fn refresh_key(refresh_token: &str) -> String {
+    refresh_hash_key(&hash_token(refresh_token))
+}
+
+fn refresh_hash_key(token_hash: &str) -> String {
+    format!("{}:{}", REFRESH_TOKEN_PREFIX, token_hash)
+}
+
+fn spent_refresh_key(token_hash: &str) -> String {
+    format!("{}:{}", SPENT_REFRESH_PREFIX, token_hash)
+}
+
+fn session_refresh_key(session_id: &Uuid) -> String {
+    format!("{}:{}", SESSION_REFRESH_PREFIX, session_id.0)
+}
+
+// Deletes the refresh token a token session could still be renewed with
+pub async fn revoke_session_refresh(kv: &KvStore, session_id: &Uuid) -> anyhow::Result<()> {
+    if let Some(token_hash) = kv.take(&session_refresh_key(session_id)).await? {
+        kv.del(&[&refresh_hash_key(&token_hash)]).await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,131 @@
+use crate::one_time_token::hash_token;
+use rand::distributions::Alphanumeric;
+use rand::{Rng, RngCore};
+use serde::{Deserialize, Serialize};
+use std::env;
+use totp_rs::{Algorithm, Secret, TOTP};
+
+const DIGITS: usize = 6;
+const STEP_SECS: u64 = 30;
+// Codes from one step either side are accepted to allow for clock drift
+const SKEW_STEPS: u64 = 1;
+const RECOVERY_CODE_COUNT: usize = 10;
+const RECOVERY_CODE_LENGTH: usize = 10;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TwoFactor {
+    // Base32, as shown to authenticator apps
+    pub secret: String,
+    // False until the user proves their authenticator works
+    pub enabled: bool,
+    // Hashes of the recovery codes that have not been used yet
+    pub recovery_codes: Vec<String>,
+    // Codes from this step or earlier are refused so that none can be replayed
+    pub last_used_step: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct TwoFactorConfig {
+    // Refuses chat connections from users who have not enabled 2FA
+    pub required: bool,
+    pub issuer: String,
+}
+
+impl Default for TwoFactorConfig {
+    fn default() -> Self {
+        TwoFactorConfig { required: false, issuer: "BlackSignal".to_string() }
+    }
+}
+
+impl TwoFactorConfig {
+    // Overrides settings with TWO_FACTOR_* environment variables
+    pub fn apply_env(&mut self) {
+        if let Ok(required) = env::var("TWO_FACTOR_REQUIRED") {
+            self.required = matches!(required.as_str(), "1" | "true" | "yes");
+        }
+        if let Ok(issuer) = env::var("TWO_FACTOR_ISSUER") {
+            self.issuer = issuer;
+        }
+    }
+}
+
+impl TwoFactor {
+    // A fresh secret awaiting confirmation
+    pub fn pending() -> Self {
+        let mut secret = [0u8; 20];
+        rand::thread_rng().fill_bytes(&mut secret);
+        TwoFactor {
+            secret: Secret::Raw(secret.to_vec()).to_encoded().to_string(),
+            enabled: false,
+            recovery_codes: Vec::new(),
+            last_used_step: 0,
+        }
+    }
+
+    fn totp(&self, issuer: &str, login: &str) -> Option<TOTP> {
+        let secret = match Secret::Encoded(self.secret.clone()).to_bytes() {
+            Ok(secret) => secret,
+            Err(e) => {
+                log::error!("Stored TOTP secret is not base32: fn totp, error: {:?}", e);
+                return None;
+            }
+        };
+        // The otpauth label may not contain ':'
+        let account = login.replace(':', "");
+        match TOTP::new(Algorithm::SHA1, DIGITS, 0, STEP_SECS, secret, Some(issuer.replace(':', "")), account) {
+            Ok(totp) => Some(totp),
+            Err(e) => {
+                log::error!("Failed to build TOTP: fn totp, error: {:?}", e);
+                None
+            }
+        }
+    }
+
+    // otpauth:// URI for authenticator apps, usually rendered as a QR code
+    pub fn provisioning_uri(&self, issuer: &str, login: &str) -> Option<String> {
+        self.totp(issuer, login).map(|totp| totp.get_url())
+    }
+
+    // The time step the code belongs to, if it is valid and newer than the
+    // last one used
+    pub fn verify_code(&self, issuer: &str, login: &str, code: &str, now: u64) -> Option<u64> {
+        let totp = self.totp(issuer, login)?;
+        let code = code.trim();
+        let current_step = now / STEP_SECS;
+        (current_step.saturating_sub(SKEW_STEPS)..=current_step + SKEW_STEPS)
+            .filter(|step| *step > self.last_used_step)
+            .find(|step| totp.generate(step * STEP_SECS) == code)
+    }
+
+    // Hash of the recovery code if it is one of the unused ones
+    pub fn find_recovery_code(&self, code: &str) -> Option<String> {
+        let hashed = hash_recovery_code(code);
+        self.recovery_codes.contains(&hashed).then_some(hashed)
+    }
+}
+
+// Returns the codes to show the user once and the hashes to store
+pub fn generate_recovery_codes() -> (Vec<String>, Vec<String>) {
+    let codes: Vec<String> = (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            rand::thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(RECOVERY_CODE_LENGTH)
+                .map(|c| char::from(c).to_ascii_lowercase())
+                .collect()
+        })
+        .collect();
+    let hashes = codes.iter().map(|code| hash_recovery_code(code)).collect();
+    (codes, hashes)
+}
+
+// Case and dashes are ignored so codes can be typed however they were written down
+fn hash_recovery_code(code: &str) -> String {
+    let normalized: String = code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    hash_token(&normalized)
+}
@@ -1,37 +1,85 @@
-use crate::appstate::AppState;
-use crate::message_structs::*;
-use crate::structs::{Room, User, UserData};
-use actix::{Actor, Addr, AsyncContext, Handler, StreamHandler};
+use crate::appstate::{AppState, ConnectedActor, WsActorMap};
+use crate::audit::{AuditEvent, AuditKind};
+use crate::auth::{bearer_token, room_token_user, session_id, session_user, subprotocol_token, Authenticated};
+use crate::bots::ApiScope;
+use crate::cluster::{PresenceLock, PRESENCE_REFRESH};
+use crate::rate_limit::{retry_after_ms, too_many_requests, RateAction};
+use black_signal_protocol::*;
+use crate::storage::{unique_violation, Storage};
+use crate::structs::{AccountState, ConnectionState, HandshakeQuery, Room, UserKind};
+use actix::{Actor, ActorContext, Addr, AsyncContext, Handler, StreamHandler};
 use actix_session::Session;
 use actix_web::{web, HttpResponse, Error};
 use actix_web_actors::ws;
 use chrono::Utc;
 use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
-use surrealdb::engine::remote::ws::Client;
-use surrealdb::Surreal;
 use surrealdb::sql::Uuid;
+use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::time::{Instant, Duration};
+use std::env;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
 
-const MESSAGE_TOKENS: u32 = 100;
-const TIME_FRAME: Duration = Duration::from_secs(10);
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct WebSocketConfig {
+    // How often the server pings every socket
+    pub heartbeat_interval_secs: u64,
+    // Sockets that send nothing, not even a pong, for this long are closed
+    pub client_timeout_secs: u64,
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        WebSocketConfig { heartbeat_interval_secs: 10, client_timeout_secs: 30 }
+    }
+}
+
+impl WebSocketConfig {
+    // Overrides settings with WS_* environment variables
+    pub fn apply_env(&mut self) {
+        let overrides: [(&str, &mut u64); 2] = [
+            ("WS_HEARTBEAT_INTERVAL_SECS", &mut self.heartbeat_interval_secs),
+            ("WS_CLIENT_TIMEOUT_SECS", &mut self.client_timeout_secs),
+        ];
+        for (name, field) in overrides {
+            if let Some(value) = env::var(name).ok().and_then(|value| value.parse().ok()) {
+                *field = value;
+            }
+        }
+    }
+}
 
 pub async fn get_messages(
     app_state: Arc<AppState>, 
     actor_addr: Addr<WsActor>, 
-    room_id: Uuid) {
-    if let Some(messages) = app_state.catch_up(&room_id).await {
-        for message in messages {
-            let serialized_msg = serde_json::to_string(&message).unwrap();
-            actor_addr.do_send(WsMessage(serialized_msg));
+    room_id: Uuid,
+    request_id: Option<String>) {
+    match app_state.catch_up(&room_id).await {
+        Some(messages) => {
+            for message in messages {
+                let serialized_msg = serde_json::to_string(&message).unwrap();
+                actor_addr.do_send(WsMessage(serialized_msg));
+            }
         }
+        None => actor_addr.do_send(WsError(ErrorMessage::new(
+            ErrorCode::DatabaseError,
+            "Failed to load messages for room".to_string(),
+            request_id,
+        ))),
     }
 }
 
-pub async fn change_to_online(db: Arc<Surreal<Client>>, user_id: Uuid) {
-    let query = "UPDATE users SET status = 'Online' WHERE user_id = $user_id;";
-    if let Err(e) = db.query(query).bind(("user_id", user_id)).await {
+// Best effort recovery of the request id from a frame that failed to deserialize
+fn request_id_from_raw(text: &str) -> Option<String> {
+    let value: serde_json::Value = serde_json::from_str(text).ok()?;
+    let (_, inner) = value.as_object()?.iter().next()?;
+    inner.get("request_id")?.as_str().map(String::from)
+}
+
+pub async fn change_to_online(storage: Arc<dyn Storage>, user_id: Uuid) {
+    if let Err(e) = storage.set_status(&user_id, ConnectionState::Online).await {
         log::error!(
             "Failed to change user to online in db: fn change_to_online, error: {:?}",
             e
@@ -39,9 +87,8 @@ pub async fn change_to_online(db: Arc<Surreal<Client>>, user_id: Uuid) {
     }
 }
 
-pub async fn change_to_offline(db: Arc<Surreal<Client>>, user_id: Uuid) {
-    let query = "UPDATE users SET status = 'Offline' WHERE user_id = $user_id;";
-    if let Err(e) = db.query(query).bind(("user_id", user_id)).await {
+pub async fn change_to_offline(storage: Arc<dyn Storage>, user_id: Uuid) {
+    if let Err(e) = storage.set_status(&user_id, ConnectionState::Offline).await {
         log::error!(
             "Failed to change user to offline in db: fn change_to_offline, error: {:?}",
             e
@@ -52,96 +99,216 @@ pub async fn change_to_offline(db: Arc<Surreal<Client>>, user_id: Uuid) {
 pub struct WsActor {
     pub ws_id: Uuid,
     pub user_id: Uuid,
+    // Login session the socket was opened from, absent for sessions that
+    // predate session ids
+    pub session_id: Option<Uuid>,
     pub username: String,
     pub current_room: Uuid,
     pub rooms: Vec<Uuid>,
     pub state: Arc<AppState>,
-    pub request_token_count: u32,
-    pub start_time: Instant,
+    pub ip: Option<IpAddr>,
+    pub protocol: NegotiatedProtocol,
+    // Unverified accounts may read any of their rooms, but not post, delete,
+    // create rooms or remove members. They are announced on signup like
+    // everyone else.
+    pub verified: bool,
+    // Opened with an API token that lacks the Post scope
+    pub read_only: bool,
+    // Last time the client sent anything, pongs included
+    pub last_heartbeat: Instant,
+    pub presence: PresenceLock,
 }
 
 impl WsActor {
-    fn reset_rate_limit(&mut self) {
-        self.request_token_count = 10;
-        self.start_time = Instant::now();
+    // Pings the client and closes the socket once it stops answering
+    fn heartbeat(&self, ctx: &mut ws::WebsocketContext<Self>) {
+        let config = &self.state.websocket;
+        let timeout = Duration::from_secs(config.client_timeout_secs);
+        ctx.run_interval(Duration::from_secs(config.heartbeat_interval_secs), move |actor, ctx| {
+            if actor.last_heartbeat.elapsed() > timeout {
+                log::info!("Closing websocket that stopped answering pings: fn heartbeat, ws_id: {}", actor.ws_id.0);
+                ctx.close(Some(ws::CloseReason {
+                    code: ws::CloseCode::Away,
+                    description: Some("Heartbeat timed out".to_string()),
+                }));
+                ctx.stop();
+                return;
+            }
+            ctx.ping(b"");
+        });
     }
-    
-    fn check_and_update_rate_limit(&mut self) -> bool {
-        let elapsed = self.start_time.elapsed();
-        
-        // Check if the current period has exceeded the time frame
-        if elapsed > TIME_FRAME {
-            // Time frame exceeded, reset rate limiting counters
-            self.reset_rate_limit();
-            true
-        } else {
-            false
+
+    fn send_frame(&self, ctx: &mut ws::WebsocketContext<Self>, message: &UserMessage) {
+        // Legacy clients have no way to decode newer frames. Errors and
+        // shutdown notices reach them as a message from the nil sender, the
+        // rest are dropped.
+        let legacy;
+        let message = match message.min_protocol_version() > self.protocol.protocol_version {
+            true => match message.legacy_text() {
+                Some(content) => {
+                    legacy = UserMessage::Basic(BasicMessage {
+                        content,
+                        sender_id: black_signal_protocol::Uuid::nil(),
+                        timestamp: Utc::now().timestamp() as u64,
+                        message_id: Uuid::new_v4().into(),
+                        room_id: self.current_room.into(),
+                        ws_id: self.ws_id.into(),
+                    });
+                    &legacy
+                }
+                None => return,
+            },
+            false => message,
+        };
+        match serde_json::to_string(message) {
+            Ok(serialized) => ctx.text(serialized),
+            Err(e) => log::error!("Failed to serialize frame: fn send_frame, error: {:?}", e),
         }
     }
+
+    fn send_error(
+        &self,
+        ctx: &mut ws::WebsocketContext<Self>,
+        code: ErrorCode,
+        message: String,
+        request_id: Option<String>,
+    ) {
+        self.send_frame(ctx, &UserMessage::Error(ErrorMessage::new(code, message, request_id)));
+    }
+
+    // Charges the action against the user's and address's shared buckets,
+    // telling the client how long to back off when refused
+    fn check_rate_limit(
+        &self,
+        ctx: &mut ws::WebsocketContext<Self>,
+        action: RateAction,
+        request_id: Option<String>,
+    ) -> bool {
+        match self.state.rate_limiter.check(Some(self.user_id), self.ip, action) {
+            Ok(()) => true,
+            Err(retry_after) => {
+                self.send_frame(
+                    ctx,
+                    &UserMessage::Error(ErrorMessage::rate_limited(retry_after_ms(retry_after), request_id)),
+                );
+                false
+            }
+        }
+    }
+}
+
+// Frames that post or change rooms rather than just read
+fn is_write(message: &UserMessage) -> bool {
+    matches!(
+        message,
+        UserMessage::TSBasic(_)
+            | UserMessage::Deletion(_)
+            | UserMessage::CreateRoomChange(_)
+            | UserMessage::UserRemoval(_)
+    )
+}
+
+fn rate_action(message: &UserMessage) -> RateAction {
+    match message {
+        UserMessage::Deletion(_) => RateAction::Deletion,
+        UserMessage::CreateRoomChange(_) => RateAction::CreateRoom,
+        UserMessage::ChangeRoom(_) => RateAction::ChangeRoom,
+        UserMessage::UserRemoval(_) => RateAction::UserRemoval,
+        _ => RateAction::Message,
+    }
 }
 
 impl Actor for WsActor {
     type Context = ws::WebsocketContext<Self>;
 
     fn started(&mut self, ctx: &mut Self::Context) {
+        self.heartbeat(ctx);
+        self.state.metrics.sockets_connected.inc();
         //registers ws actor
         let mut actor_registry = self.state.actor_registry.lock().unwrap();
+        let connected = ConnectedActor {
+            addr: ctx.address(),
+            session_id: self.session_id,
+            presence: self.presence.clone(),
+        };
         match actor_registry.get_mut(&self.user_id) {
             Some(hashmap) => {
-                hashmap.insert(self.ws_id, ctx.address());
+                hashmap.insert(self.ws_id, connected);
             }
             None => {
-                let mut hashmap: HashMap<Uuid, Addr<WsActor>> = HashMap::new();
-                hashmap.insert(self.ws_id, ctx.address());
+                let mut hashmap: WsActorMap = HashMap::new();
+                hashmap.insert(self.ws_id, connected);
                 actor_registry.insert(self.user_id, hashmap);
             }
         }
-        let db = self.state.db.clone();
+        let storage = self.state.storage.clone();
         let app_state = self.state.clone();
         let room_id = self.current_room;
         let user_id = self.user_id;
         let user_info = UserInfo::new(
-            self.user_id,
-            self.ws_id,
+            self.user_id.into(),
+            self.ws_id.into(),
             self.username.clone(),
         );
         ctx.spawn(actix::fut::wrap_future(get_users(
-            db.clone(),
+            storage.clone(),
             ctx.address(),
             self.current_room,
             user_info,
+            self.protocol.clone(),
         )));
         ctx.spawn(actix::fut::wrap_future(get_messages(
             app_state,
             ctx.address(),
             room_id,
+            None,
         )));
-        ctx.spawn(actix::fut::wrap_future(change_to_online(db, user_id)));
+        let (ws_id, session_id) = (self.ws_id, self.session_id);
+        let state = self.state.clone();
+        let presence = self.presence.clone();
+        // Not tied to the actor, so it still runs, and is then skipped, when
+        // the socket stops first
+        self.state.drain.spawn(async move {
+            let online = change_to_online(storage, user_id);
+            state.cluster.socket_opened(&presence, &user_id, ws_id, session_id, online).await;
+        });
+        ctx.run_interval(PRESENCE_REFRESH, move |actor, ctx| {
+            let state = actor.state.clone();
+            let presence = actor.presence.clone();
+            ctx.spawn(actix::fut::wrap_future(async move {
+                state.cluster.socket_opened(&presence, &user_id, ws_id, session_id, async {}).await;
+            }));
+        });
     }
 
     fn stopped(&mut self, _ctx: &mut Self::Context) {
+        self.state.metrics.sockets_connected.dec();
         let user_id = self.user_id;
-        let db = self.state.db.clone();
+        let (ws_id, session_id) = (self.ws_id, self.session_id);
+        let state = self.state.clone();
+        let presence = self.presence.clone();
         let mut actor_registry = self.state.actor_registry.lock().unwrap();
         if let Some(hashmap) = actor_registry.get_mut(&self.user_id.clone()) {
             hashmap.remove(&self.ws_id.clone());
+            if hashmap.is_empty() {
+                actor_registry.remove(&self.user_id);
+            }
         }
-        actix::spawn(async move { change_to_offline(db, user_id).await });
+        // Sockets on other instances keep the user online
+        self.state.drain.spawn(async move {
+            if state.cluster.socket_closed(&presence, &user_id, ws_id, session_id).await {
+                change_to_offline(state.storage.clone(), user_id).await;
+            }
+        });
     }
 
 }
 
 pub async fn _add_user_to_room(
-    user_id: String, 
-    room_id: String, 
-    db: Arc<Surreal<Client>>) {
-    let query = "UPDATE rooms SET users += $user_id WHERE room_id = $room_id;";
-    if let Err(e) = db
-        .query(query)
-        .bind(("user_id", user_id))
-        .bind(("room_id", room_id))
-        .await
-    {
+    user_id: Uuid,
+    room_id: Uuid,
+    storage: Arc<dyn Storage>) {
+    if let Err(e) = storage.add_room_member(&room_id, &user_id).await {
         log::error!(
             "Failed to add user to room: fn add_user_to_room, error: {:?}",
             e
@@ -149,6 +316,35 @@ pub async fn _add_user_to_room(
     }
 }
 
+// Sends the reason a handshake was refused and closes the socket without
+// registering the connection.
+pub struct HandshakeRejection(pub ProtocolErrorMessage);
+
+impl Actor for HandshakeRejection {
+    type Context = ws::WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        match serde_json::to_string(&UserMessage::ProtocolError(self.0.clone())) {
+            Ok(serialized) => ctx.text(serialized),
+            Err(e) => log::error!("Failed to serialize handshake rejection: fn started, error: {:?}", e),
+        }
+        ctx.close(Some(ws::CloseReason {
+            code: ws::CloseCode::Protocol,
+            description: Some(self.0.message.clone()),
+        }));
+        ctx.stop();
+    }
+}
+
+impl StreamHandler<std::result::Result<ws::Message, ws::ProtocolError>> for HandshakeRejection {
+    fn handle(
+        &mut self,
+        _msg: std::result::Result<ws::Message, ws::ProtocolError>,
+        _ctx: &mut Self::Context,
+    ) {
+    }
+}
+
 pub struct WsMessage(pub String);
 
 impl actix::Message for WsMessage {
@@ -164,33 +360,149 @@ impl Handler<WsMessage> for WsActor {
     }
 }
 
-pub async fn delete_message(message: DeletionMessage, sender_id: Uuid, room_id: Uuid, state: Arc<AppState>) {
-    let query = "SELECT * FROM messages WHERE sender_id = $sender_id AND message_id = $message_id;";
-    let mut response = match state.db.query(query).bind(("sender_id", sender_id)).bind(("message_id", message.message_id.clone())).await {
-        Ok(x) => x,
-        Err(e) => {
-            log::error!(
-                "Failed to delete message: fn delete_message, error: {:?}",
-                e
-            );
-            return
+// Closes the socket unless it belongs to the session being kept, or only if
+// it belongs to the one session being ended
+pub struct Disconnect {
+    pub keep_session: Option<Uuid>,
+    pub only_session: Option<Uuid>,
+    pub reason: String,
+}
+
+impl actix::Message for Disconnect {
+    type Result = ();
+}
+
+impl Handler<Disconnect> for WsActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: Disconnect, ctx: &mut Self::Context) {
+        if msg.keep_session.is_some() && msg.keep_session == self.session_id {
+            return;
         }
+        if msg.only_session.is_some() && msg.only_session != self.session_id {
+            return;
+        }
+        ctx.close(Some(ws::CloseReason {
+            code: ws::CloseCode::Policy,
+            description: Some(msg.reason),
+        }));
+        ctx.stop();
+    }
+}
+
+// Tells the client the server is going away and when to reconnect, then
+// closes the socket
+pub struct ServerGoingAway {
+    pub reconnect_after_ms: u64,
+}
+
+impl actix::Message for ServerGoingAway {
+    type Result = ();
+}
+
+impl Handler<ServerGoingAway> for WsActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: ServerGoingAway, ctx: &mut Self::Context) {
+        let notice = ServerShutdownMessage::new(msg.reconnect_after_ms);
+        let description = notice.message.clone();
+        self.send_frame(ctx, &UserMessage::ServerShutdown(notice));
+        ctx.close(Some(ws::CloseReason {
+            code: ws::CloseCode::Restart,
+            description: Some(description),
+        }));
+        ctx.stop();
+    }
+}
+
+// Lifts the restrictions on unverified accounts for already open sockets
+pub struct AccountActivated;
+
+impl actix::Message for AccountActivated {
+    type Result = ();
+}
+
+impl Handler<AccountActivated> for WsActor {
+    type Result = ();
+
+    fn handle(&mut self, _msg: AccountActivated, _ctx: &mut Self::Context) {
+        self.verified = true;
+    }
+}
+
+// Error raised by a task spawned on behalf of the actor's client
+pub struct WsError(pub ErrorMessage);
+
+impl actix::Message for WsError {
+    type Result = ();
+}
+
+impl Handler<WsError> for WsActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: WsError, ctx: &mut Self::Context) {
+        self.send_frame(ctx, &UserMessage::Error(msg.0));
+    }
+}
+
+pub async fn delete_message(
+    mut message: DeletionMessage,
+    sender_id: Uuid,
+    room_id: Uuid,
+    ip: Option<IpAddr>,
+    state: Arc<AppState>,
+    actor_addr: Addr<WsActor>,
+) {
+    let request_id = message.request_id.take();
+    let database_error = |request_id: Option<String>| {
+        WsError(ErrorMessage::new(
+            ErrorCode::DatabaseError,
+            "Failed to delete message".to_string(),
+            request_id,
+        ))
     };
-    let _: Option<BasicMessage> = match response.take(0) {
-        Ok(x) => x,
-        Err(e) => {log::error!("Failed to delete message: fn delete_message, error: {:?}", e);
-            return}
+    let message_id = match Uuid::try_from(message.message_id.as_str()) {
+        Ok(message_id) => message_id,
+        Err(_) => {
+            actor_addr.do_send(WsError(ErrorMessage::new(
+                ErrorCode::InvalidMessage,
+                "Message id is not a valid uuid".to_string(),
+                request_id,
+            )));
+            return;
+        }
     };
-    let _: Option<BasicMessage> = match state.db.delete(("messages", message.message_id.clone())).await {
+    let stored = match state.storage.get_message(&message_id).await {
         Ok(x) => x,
         Err(e) => {
             log::error!(
                 "Failed to delete message: fn delete_message, error: {:?}",
                 e
             );
-            None
+            actor_addr.do_send(database_error(request_id));
+            return
         }
     };
+    if stored.is_none_or(|stored| Uuid::from(stored.sender_id) != sender_id) {
+        actor_addr.do_send(WsError(ErrorMessage::new(
+            ErrorCode::PermissionDenied,
+            "Only the sender of a message can delete it".to_string(),
+            request_id,
+        )));
+        return;
+    }
+    if let Err(e) = state.storage.delete_message(&message_id).await {
+        log::error!(
+            "Failed to delete message: fn delete_message, error: {:?}",
+            e
+        );
+        actor_addr.do_send(database_error(request_id));
+        return
+    }
+    state
+        .audit
+        .record(AuditEvent::new(AuditKind::MessageDeleted).actor(sender_id).room(room_id).ip(ip).detail("message_id", message.message_id.clone()))
+        .await;
     let serialized_message = match serde_json::to_string(&UserMessage::Deletion(message)){
         Ok(x) => x,
         Err(e) => {log::error!("Failed to delete message: fn delete_message, error: {:?}", e);
@@ -200,97 +512,78 @@ pub async fn delete_message(message: DeletionMessage, sender_id: Uuid, room_id:
 }
 
 pub async fn get_users(
-    db: Arc<Surreal<Client>>,
+    storage: Arc<dyn Storage>,
     actor_addr: Addr<WsActor>,
     room_id: Uuid,
     user_info: UserInfo,
+    protocol: NegotiatedProtocol,
 ) {
-    let query = "SELECT user_id, username FROM users WHERE $room_id IN rooms;";
-    let mut response = match db.query(query).bind(("room_id", room_id)).await {
-        Ok(retrieved) => retrieved,
-        Err(e) => {
-            log::error!(
-                "Failed to query users that are in requested room: fn get_users, error: {:?}",
-                e
-            );
-            return;
-        }
+    let database_error = || {
+        WsError(ErrorMessage::new(
+            ErrorCode::DatabaseError,
+            "Failed to load users in room".to_string(),
+            None,
+        ))
     };
-    let users: Vec<User> = match response.take(0) {
-        Ok(user) => user,
+    let users = match storage.users_in_room(&room_id).await {
+        Ok(users) => users,
         Err(e) => {
             log::error!(
-                "Failed to get users that are in requested room: fn get_users, error: {:?}",
+                "Failed to query users that are in requested room: fn get_users, error: {:?}",
                 e
             );
+            actor_addr.do_send(database_error());
             return;
         }
     };
-    let user_map: HashMap<Uuid, String> = users
+    let bots: HashSet<_> = users
+        .iter()
+        .filter(|user| user.kind == UserKind::Bot)
+        .map(|user| user.user_id.into())
+        .collect();
+    let user_map: HashMap<_, String> = users
         .into_iter()
-        .map(|user| (user.user_id, user.username))
+        .map(|user| (user.user_id.into(), user.username))
         .collect();
     let init_message = UserMessage::Initialization(InitMessage::new(
         user_info.user_id,
         user_info.ws_id,
         user_info.username,
         user_map,
-    ));
+        protocol,
+    ).with_bots(bots));
     let serialized = serde_json::to_string(&init_message).unwrap();
     actor_addr.do_send(WsMessage(serialized));
 }
 
 pub async fn check_and_update_username(
     user_id: Uuid,
-    current_username: String,
     new_username: String,
+    ip: Option<IpAddr>,
     state: Arc<AppState>,
     message: UserMessage,
 ) -> Result<HttpResponse, Error> {
-    let query = "SELECT username FROM users WHERE username = $username;";
-    if let Ok(mut response) = state
-        .db
-        .query(query)
-        .bind(("username", new_username.clone()))
-        .await
-    {
-        let result: Option<String> = match response.take((0, "username")) {
-            Ok(retrieved) => retrieved,
-            Err(e) => {
-                log::error!(
-                    "Failed to get user: fn check_and_update_username, error: {:?}",
-                    e
-                );
-                return Ok(HttpResponse::InternalServerError().json(json!({"error": "Internal DB Error"})));
-            }
-        };
-        match result {
-            Some(_) => Ok(HttpResponse::BadRequest().json(json!({"error": "Username Already In Use"}))),
-            None => {
-                let query = "UPDATE users SET username = $new_username WHERE username = $username;";
-                if let Err(e) = state
-                    .db
-                    .query(query)
-                    .bind(("new_username", new_username.clone()))
-                    .bind(("username", current_username)).await{
-                        log::error!(
-                            "Failed to update username: fn check_and_update_username, error: {:?}",
-                            e
-                        );
-                        return Ok(HttpResponse::InternalServerError().json(json!({"error": "Internal DB Error"})));
-                    };
-
-                let serialized_msg = serde_json::to_string(&message).unwrap();
-                state
-                    .broadcast_message(serialized_msg, &state.main_room_id, &user_id)
-                    .await;
-                Ok(HttpResponse::Ok().json(json!({"message": "Username updated successfully"}))
-            )
-            }
+    // The unique index on usernames settles races between two renames
+    if let Err(e) = state.storage.set_username(&user_id, &new_username).await {
+        if unique_violation(&e).is_some() {
+            return Ok(HttpResponse::BadRequest().json(json!({"error": "Username Already In Use"})));
         }
-    } else {
-        Ok(HttpResponse::BadRequest().json(json!({"error": "Failed to query database"})))
+        log::error!(
+            "Failed to update username: fn check_and_update_username, error: {:?}",
+            e
+        );
+        return Ok(HttpResponse::InternalServerError().json(json!({"error": "Internal DB Error"})));
     }
+    state
+        .audit
+        .record(AuditEvent::new(AuditKind::UsernameChanged).actor(user_id).ip(ip).detail("username", new_username))
+        .await;
+
+    let serialized_msg = serde_json::to_string(&message).unwrap();
+    state
+        .broadcast_message(serialized_msg, &state.main_room_id, &user_id)
+        .await;
+    Ok(HttpResponse::Ok().json(json!({"message": "Username updated successfully"})))
 }
 
 impl StreamHandler<std::result::Result<ws::Message, ws::ProtocolError>> for WsActor {
@@ -299,168 +592,282 @@ impl StreamHandler<std::result::Result<ws::Message, ws::ProtocolError>> for WsAc
         msg: std::result::Result<ws::Message, ws::ProtocolError>,
         ctx: &mut Self::Context,
     ) {
-        if !self.check_and_update_rate_limit() && self.request_token_count == 0  {
-            return;
-        }
-        match self.request_token_count.checked_sub(1){
-            Some(result) => self.request_token_count = result,
-            None => println!("Underflow occurred"),
+        let msg = match msg {
+            Ok(msg) => msg,
+            Err(e) => {
+                log::warn!("Closing websocket after protocol error: fn handle, error: {:?}", e);
+                ctx.stop();
+                return;
+            }
+        };
+        self.last_heartbeat = Instant::now();
+        match msg {
+            ws::Message::Ping(bytes) => {
+                ctx.pong(&bytes);
+                return;
+            }
+            ws::Message::Close(reason) => {
+                ctx.close(reason);
+                ctx.stop();
+                return;
+            }
+            _ => {}
         }
-        if let Ok(ws::Message::Text(text)) = msg {
-            match serde_json::from_str::<UserMessage>(&text) {
-                Ok(message) => match message {
-                    UserMessage::TSBasic(ts_basic_message) => {
-                        let app_state = self.state.clone();
-                        let now = Utc::now();
-                        let basic_message = BasicMessage {
-                            content: ts_basic_message.content,
-                            sender_id: self.user_id,
-                            timestamp: now.timestamp() as u64,
-                            message_id: Uuid::new_v4(),
-                            room_id: self.current_room,
-                            ws_id: self.ws_id,
-                        };
-                        actix::spawn(async move {
-                            let _: Option<BasicMessage> = match app_state
-                                .db
-                                .create(("messages", basic_message.message_id))
-                                .content(basic_message.clone())
-                                .await {
-                                    Ok(retrieved) => retrieved,
-                                    Err(e) => {log::error!("Failed to create message in db: fn handle, error: {:?}", e);
-                                    return}
-                                };
-                            let serialized_msg = match serde_json::to_string(&UserMessage::Basic(basic_message.clone(),)){
-                                Ok(serialized) => serialized,
-                                Err(e) => {log::error!("Failed to create message in db: fn handle, error: {:?}", e);
-                                return}
+        if let ws::Message::Text(text) = msg {
+            if !self.state.storage.is_available() {
+                self.send_error(
+                    ctx,
+                    ErrorCode::Unavailable,
+                    "Service temporarily unavailable, try again shortly".to_string(),
+                    request_id_from_raw(&text),
+                );
+                return;
+            }
+            let parsed = serde_json::from_str::<UserMessage>(&text);
+            let (action, request_id) = match &parsed {
+                Ok(message) => (rate_action(message), message.request_id().map(String::from)),
+                // Malformed frames still cost the client a message
+                Err(_) => (RateAction::Message, request_id_from_raw(&text)),
+            };
+            if !self.check_rate_limit(ctx, action, request_id.clone()) {
+                return;
+            }
+            if let Ok(message) = &parsed {
+                if !self.verified && is_write(message) {
+                    self.send_error(
+                        ctx,
+                        ErrorCode::EmailNotVerified,
+                        "Verify your email address first".to_string(),
+                        request_id,
+                    );
+                    return;
+                }
+                if self.read_only && is_write(message) {
+                    self.send_error(
+                        ctx,
+                        ErrorCode::PermissionDenied,
+                        "Token is not allowed to post".to_string(),
+                        request_id,
+                    );
+                    return;
+                }
+            }
+            match parsed {
+                Ok(message) => {
+                    match message {
+                        UserMessage::TSBasic(ts_basic_message) => {
+                            let app_state = self.state.clone();
+                            let actor_addr = ctx.address();
+                            let now = Utc::now();
+                            let basic_message = BasicMessage {
+                                content: ts_basic_message.content,
+                                sender_id: self.user_id.into(),
+                                timestamp: now.timestamp() as u64,
+                                message_id: Uuid::new_v4().into(),
+                                room_id: self.current_room.into(),
+                                ws_id: self.ws_id.into(),
                             };
-                            app_state
-                                .broadcast_message(
-                                    serialized_msg,
-                                    &basic_message.room_id,
-                                    &basic_message.sender_id,
-                                )
-                                .await;
-                        });
-                    }
-                    UserMessage::Deletion(message) => {
-                        let sender_id = self.user_id;
-                        let state = self.state.clone();
-                        let room_id = self.current_room;
-                        ctx.spawn(actix::fut::wrap_future(delete_message(message, sender_id, room_id, state)));
-                        
-                    }
-                    UserMessage::CreateRoomChange(create_room_change_message) => {
-                        let room_id = Uuid::new_v4();
-                        let room_name = create_room_change_message.room_name;
-                        let app_state = self.state.clone();
-                        self.rooms.push(room_id);
-                        let mut users = HashSet::new();
-                        users.insert(self.user_id);
-                        actix::spawn(async move {
-                            let _: Vec<Room> = match app_state
-                                .db
-                                .create("rooms")
-                                .content(Room {
+                            self.state.drain.spawn(async move {
+                                if let Err(code) = app_state.post_message(&basic_message).await {
+                                    let description = match code {
+                                        ErrorCode::DatabaseError => "Failed to save message",
+                                        _ => "Failed to send message",
+                                    };
+                                    actor_addr.do_send(WsError(ErrorMessage::new(code, description.to_string(), request_id)));
+                                }
+                            });
+                        }
+                        UserMessage::Deletion(message) => {
+                            let sender_id = self.user_id;
+                            let state = self.state.clone();
+                            let room_id = self.current_room;
+                            self.state.drain.spawn(delete_message(message, sender_id, room_id, self.ip, state, ctx.address()));
+                        }
+                        UserMessage::CreateRoomChange(create_room_change_message) => {
+                            let room_id = Uuid::new_v4();
+                            let room_name = create_room_change_message.room_name;
+                            let app_state = self.state.clone();
+                            let actor_addr = ctx.address();
+                            self.rooms.push(room_id);
+                            let mut users = HashSet::new();
+                            users.insert(self.user_id);
+                            let event = AuditEvent::new(AuditKind::RoomCreated)
+                                .actor(self.user_id)
+                                .room(room_id)
+                                .ip(self.ip)
+                                .detail("name", room_name.clone());
+                            self.state.drain.spawn(async move {
+                                let room = Room {
                                     name: room_name,
                                     room_id,
                                     users,
-                                })
-                                .await {
-                                    Ok(retrieved) => retrieved,
-                                    Err(e) => {log::error!("Failed to create room in db: fn handle, error: {:?}", e);
-                                    return}
                                 };
-                        });
-                    }
-                    UserMessage::ChangeRoom(change_room_message) => {
-                        let room_id = change_room_message.room_id;
-                        let app_state = self.state.clone();
-                        let actor_addr = ctx.address().clone();
-                        ctx.spawn(actix::fut::wrap_future(get_messages(
-                            app_state, actor_addr, room_id,
-                        )));
-                    }
-                    UserMessage::UserRemoval(user_removal_message) => {
-                        let app_state = self.state.clone();
-                        actix::spawn(async move {
-                            let query =
-                                "UPDATE rooms SET users -= $removed_user WHERE room_id = $room_id;";
-                            if let Err(e) = app_state
-                                .db
-                                .query(query)
-                                .bind(("removed_user", user_removal_message.removed_user))
-                                .bind(("room_id", user_removal_message.room_id))
-                                .await
-                            {
-                                log::error!("Error removing from room: {:?}", e);
+                                if let Err(e) = app_state.storage.create_room(&room).await {
+                                    log::error!("Failed to create room in db: fn handle, error: {:?}", e);
+                                    actor_addr.do_send(WsError(ErrorMessage::new(
+                                        ErrorCode::DatabaseError,
+                                        "Failed to create room".to_string(),
+                                        request_id,
+                                    )));
+                                    return;
+                                }
+                                app_state.audit.record(event).await;
+                            });
+                        }
+                        UserMessage::ChangeRoom(change_room_message) => {
+                            let room_id = Uuid::from(change_room_message.room_id);
+                            if !self.rooms.contains(&room_id) {
+                                self.send_error(
+                                    ctx,
+                                    ErrorCode::PermissionDenied,
+                                    "Not a member of the requested room".to_string(),
+                                    request_id,
+                                );
+                                return;
+                            }
+                            let app_state = self.state.clone();
+                            let actor_addr = ctx.address().clone();
+                            ctx.spawn(actix::fut::wrap_future(get_messages(
+                                app_state, actor_addr, room_id, request_id,
+                            )));
+                        }
+                        UserMessage::UserRemoval(user_removal_message) => {
+                            let room_id = Uuid::from(user_removal_message.room_id);
+                            if !self.rooms.contains(&room_id) {
+                                self.send_error(
+                                    ctx,
+                                    ErrorCode::PermissionDenied,
+                                    "Not a member of the requested room".to_string(),
+                                    request_id,
+                                );
+                                return;
                             }
-                        });
+                            let removed_user = match Uuid::try_from(user_removal_message.removed_user.as_str()) {
+                                Ok(removed_user) => removed_user,
+                                Err(_) => {
+                                    self.send_error(
+                                        ctx,
+                                        ErrorCode::InvalidMessage,
+                                        "Removed user is not a valid uuid".to_string(),
+                                        request_id,
+                                    );
+                                    return;
+                                }
+                            };
+                            let app_state = self.state.clone();
+                            let actor_addr = ctx.address();
+                            let event = AuditEvent::new(AuditKind::RoomMemberRemoved)
+                                .actor(self.user_id)
+                                .target(removed_user)
+                                .room(room_id)
+                                .ip(self.ip);
+                            self.state.drain.spawn(async move {
+                                if let Err(e) = app_state
+                                    .storage
+                                    .remove_room_member(&room_id, &removed_user)
+                                    .await
+                                {
+                                    log::error!("Error removing from room: {:?}", e);
+                                    actor_addr.do_send(WsError(ErrorMessage::new(
+                                        ErrorCode::DatabaseError,
+                                        "Failed to remove user from room".to_string(),
+                                        request_id,
+                                    )));
+                                    return;
+                                }
+                                app_state.audit.record(event).await;
+                            });
+                        }
+                        _ => self.send_error(
+                            ctx,
+                            ErrorCode::InvalidMessage,
+                            "Message type cannot be sent by clients".to_string(),
+                            request_id,
+                        ),
                     }
-                    _ => {}
-                },
-                Err(e) => log::error!("Error processing message: {:?}", e),
+                }
+                Err(e) => {
+                    log::error!("Error processing message: {:?}", e);
+                    self.send_error(
+                        ctx,
+                        ErrorCode::InvalidMessage,
+                        format!(
+                            "Message not understood by protocol version {}: {}",
+                            self.protocol.protocol_version, e
+                        ),
+                        request_id,
+                    );
+                }
             }
         }
     }
 }
 
+// Echoed back when a client authenticates with the bearer subprotocol
+const WS_PROTOCOLS: &[&str] = &["bearer"];
+
 pub async fn ws_index(
     req: actix_web::HttpRequest,
     stream: web::Payload,
     state: web::Data<AppState>,
     session: Session,
+    handshake: web::Query<HandshakeQuery>,
 ) -> std::result::Result<HttpResponse, actix_web::Error> {
+    // Clients are told where to go by the frame sent to open sockets
+    if state.drain.is_draining() {
+        return Ok(HttpResponse::ServiceUnavailable()
+            .append_header(("Retry-After", state.shutdown.reconnect_after_secs.to_string()))
+            .json(json!({"error": "Server is shutting down"})));
+    }
     let main_room_id = state.main_room_id;
-    if let Some(user_id) = session.get::<Uuid>("key").unwrap() {
-        let query = "SELECT * FROM users WHERE user_id = $user_id;";
-        let mut response = match state
-            .db
-            .query(query)
-            .bind(("user_id", user_id))
-            .await {
-                Ok(retrieved) => retrieved,
-                Err(e) => {log::error!("Failed to query user data: fn ws_index, error: {:?}", e);
-                    session.purge();
-                    return Ok(HttpResponse::Found()
-                        .append_header(("LOCATION", "/login"))
-                        .finish());
-                }
-            };
-        let user_query: Option<UserData> = match response
-            .take(0){
-                Ok(retrieved) => retrieved,
-                Err(e) => {log::error!("Failed to get user data: fn ws_index, error: {:?}", e);
-                    session.purge();
-                    return Ok(HttpResponse::Found()
-                        .append_header(("LOCATION", "/login"))
-                        .finish());
-                }
-            };
-        match user_query {
-            Some(user) => {
-                let ws_actor = WsActor {
-                    user_id,
-                    ws_id: Uuid::new_v4(),
-                    username: user.username,
-                    current_room: main_room_id,
-                    rooms: user.rooms,
-                    state: state.into_inner().clone(),
-                    request_token_count: MESSAGE_TOKENS,
-                    start_time: Instant::now(),
-                };
-                return ws::start(ws_actor, &req, stream);
-            }
+    let ip = req.peer_addr().map(|addr| addr.ip());
+    let auth = match subprotocol_token(&req).or_else(|| bearer_token(&req)) {
+        Some(token) => match room_token_user(&token, &state).await {
+            Some(auth) => auth,
+            None => return Ok(HttpResponse::Unauthorized().json(json!({"error": "Invalid or expired access token"}))),
+        },
+        None => match session_user(&session, &state).await {
+            Some(user) => Authenticated { user, session_id: session_id(&session), bearer: false, scopes: None },
             None => {
-                session.purge();
                 return Ok(HttpResponse::Found()
                     .append_header(("LOCATION", "/login"))
-                    .finish());
+                    .finish())
             }
-        }
+        },
+    };
+    auth.touch(&req, &state).await;
+    if !auth.allows(ApiScope::Read) {
+        return Ok(HttpResponse::Forbidden().json(json!({"error": "Token is not allowed to read rooms"})));
+    }
+    let read_only = !auth.allows(ApiScope::Post);
+    let user = auth.user;
+    if let Err(retry_after) = state.rate_limiter.check(Some(user.user_id), ip, RateAction::Connect) {
+        return Ok(too_many_requests(retry_after));
     }
-    return Ok(HttpResponse::Found()
-        .append_header(("LOCATION", "/login"))
-        .finish());
-}
\ No newline at end of file
+    if state.lacks_required_two_factor(&user) {
+        return Ok(HttpResponse::Forbidden().json(json!({"error": "Enable two-factor authentication to use chat"})));
+    }
+    let protocol = match handshake.negotiate() {
+        Ok(negotiated) => negotiated,
+        Err(rejection) => {
+            log::warn!("Rejected websocket handshake: fn ws_index, reason: {}", rejection.message);
+            return ws::WsResponseBuilder::new(HandshakeRejection(rejection), &req, stream).protocols(WS_PROTOCOLS).start();
+        }
+    };
+    let ws_actor = WsActor {
+        user_id: user.user_id,
+        ws_id: Uuid::new_v4(),
+        session_id: auth.session_id,
+        username: user.username,
+        current_room: main_room_id,
+        rooms: user.rooms,
+        state: state.into_inner().clone(),
+        ip,
+        protocol,
+        verified: user.account_state == AccountState::Active,
+        read_only,
+        last_heartbeat: Instant::now(),
+        presence: PresenceLock::default(),
+    };
+    ws::WsResponseBuilder::new(ws_actor, &req, stream).protocols(WS_PROTOCOLS).start()
+}
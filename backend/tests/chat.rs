@@ -0,0 +1,831 @@
+mod common;
+
+use actix_web::http::StatusCode;
+use awc::ws::{CloseCode, CloseReason};
+use black_signal::audit::{AuditEvent, AuditKind};
+use black_signal::cluster::SocketPresence;
+use black_signal::config::Config;
+use black_signal::kv::KvBackend;
+use black_signal::session_store::SessionStoreKind;
+use black_signal::mailer::MailerConfig;
+use black_signal::metrics::{Metrics, MetricsConfig};
+use black_signal::rate_limit::{too_many_requests, RateAction, RateLimitConfig, RateLimiter};
+use black_signal::storage::{RoomRepository, UserRepository};
+use black_signal::structs::{ConnectionState, UserData};
+use black_signal::two_factor::TwoFactor;
+use black_signal_protocol::*;
+use common::{TestApp, ADMIN_LOGIN, PASSWORD, TEST_LOGIN};
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+fn post(content: &str, request_id: &str) -> UserMessage {
+    UserMessage::TSBasic(TSBasicMessage {
+        content: content.to_string(),
+        request_id: Some(request_id.to_string()),
+    })
+}
+
+async fn recv_basic(socket: &mut common::TestSocket) -> BasicMessage {
+    match socket.recv().await {
+        UserMessage::Basic(message) => message,
+        other => panic!("expected Basic, got {:?}", other),
+    }
+}
+
+#[actix_web::test]
+async fn signup_logs_in_and_login_checks_the_password() {
+    let app = TestApp::start().await;
+    let alice = app.sign_up("alice@example.com").await;
+
+    let signup = serde_json::json!({"username": alice.login, "password": PASSWORD});
+    let (status, _, body) = app.post("/create_login", None, signup).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, serde_json::json!({"message": "Invalid Please enter an email and a password"}));
+
+    let (status, cookie, _) = app.log_in(&alice.login, PASSWORD).await;
+    assert_eq!(status, StatusCode::FOUND);
+    assert!(cookie.is_some());
+
+    let (status, cookie, body) = app.log_in(&alice.login, "not the password").await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(cookie.is_none());
+    assert_eq!(body, serde_json::json!({"message": "Invalid Please enter an email and a password"}));
+}
+
+#[actix_web::test]
+async fn connecting_sends_the_room_members() {
+    let app = TestApp::start().await;
+    let test_user = app.state.storage.get_user_by_login(TEST_LOGIN).await.unwrap().unwrap();
+    let alice = app.sign_up_verified("alice@example.com").await;
+
+    let (mut socket, init) = app.connect_initialized(&alice).await;
+    let expected = InitMessage::new(
+        alice.user_id,
+        init.ws_id,
+        alice.username.clone(),
+        HashMap::from([(test_user.user_id.into(), test_user.username), (alice.user_id, alice.username.clone())]),
+        NegotiatedProtocol::negotiate(PROTOCOL_VERSION, &[]).unwrap(),
+    )
+    .with_bots(HashSet::new());
+    assert_eq!(init, expected);
+    socket.assert_silent().await;
+}
+
+#[actix_web::test]
+async fn signup_is_announced_to_the_main_room() {
+    let app = TestApp::start().await;
+    let alice = app.sign_up_verified("alice@example.com").await;
+    let (mut socket, _) = app.connect_initialized(&alice).await;
+
+    let bob = app.sign_up("bob@example.com").await;
+    assert_eq!(
+        socket.recv().await,
+        UserMessage::NewUser(NewUserMessage::new(bob.user_id, bob.username.clone()))
+    );
+    assert_eq!(app.emails_to(&bob.login).len(), 1);
+}
+
+#[actix_web::test]
+async fn signup_reports_a_verification_email_that_could_not_be_sent() {
+    let outbox = std::env::temp_dir().join(format!("black_signal_outbox_{}", Uuid::new_v4()));
+    let app = TestApp::start_with(|config| {
+        config.mail = MailerConfig::Outbox { dir: outbox.to_string_lossy().into_owned() };
+    })
+    .await;
+    std::fs::remove_dir_all(&outbox).unwrap();
+
+    let signup = serde_json::json!({"username": "bob@example.com", "password": PASSWORD});
+    let (status, cookie, location) = app.post_redirect("/create_login", signup).await;
+    assert_eq!(status, StatusCode::FOUND);
+    assert_eq!(location.as_deref(), Some("/?verification_email=failed"));
+
+    std::fs::create_dir_all(&outbox).unwrap();
+    let (status, _, _) = app.post("/resend_verification", cookie.as_ref(), serde_json::json!({})).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(std::fs::read_dir(&outbox).unwrap().count(), 1);
+    std::fs::remove_dir_all(&outbox).unwrap();
+}
+
+#[actix_web::test]
+async fn rejected_reset_passwords_keep_the_link_usable() {
+    let app = TestApp::start().await;
+    let alice = app.sign_up_verified("alice@example.com").await;
+    for login in [alice.login.as_str(), "nobody@example.com"] {
+        let (status, _, _) = app.post("/forgot_password", None, serde_json::json!({"login": login})).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+    let email = app.wait_for_emails(&alice.login, 2).await.pop().unwrap();
+    let token = email.body.split("token=").nth(1).unwrap().lines().next().unwrap();
+
+    let reset = |password: &str| serde_json::json!({"token": token, "new_password": password});
+    let (status, _, body) = app.post("/reset_password", None, reset("short")).await;
+    assert_eq!((status, &body["error"]), (StatusCode::BAD_REQUEST, &serde_json::json!("Password rejected")));
+    let (status, _, _) = app.post("/reset_password", None, reset("a different horse battery staple")).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _, body) = app.post("/reset_password", None, reset("yet another horse battery staple")).await;
+    assert_eq!((status, &body["error"]), (StatusCode::BAD_REQUEST, &serde_json::json!("Reset link is invalid or has expired")));
+    let (status, _, _) = app.log_in(&alice.login, "a different horse battery staple").await;
+    assert_eq!(status, StatusCode::FOUND);
+}
+
+#[actix_web::test]
+async fn resetting_the_password_invalidates_older_reset_links() {
+    let app = TestApp::start().await;
+    let alice = app.sign_up_verified("alice@example.com").await;
+    for _ in 0..2 {
+        let (status, _, _) = app.post("/forgot_password", None, serde_json::json!({"login": alice.login})).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+    // The verification email comes first
+    let emails = app.wait_for_emails(&alice.login, 3).await;
+    let tokens: Vec<&str> = emails[1..].iter().map(|email| email.body.split("token=").nth(1).unwrap().lines().next().unwrap()).collect();
+
+    let reset = |token: &str, password: &str| serde_json::json!({"token": token, "new_password": password});
+    let (status, _, _) = app.post("/reset_password", None, reset(tokens[1], "a different horse battery staple")).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _, body) = app.post("/reset_password", None, reset(tokens[0], "an attacker's horse battery staple")).await;
+    assert_eq!((status, &body["error"]), (StatusCode::BAD_REQUEST, &serde_json::json!("Reset link is invalid or has expired")));
+    let (status, _, _) = app.log_in(&alice.login, "a different horse battery staple").await;
+    assert_eq!(status, StatusCode::FOUND);
+}
+
+#[actix_web::test]
+async fn unverified_accounts_cannot_post() {
+    let app = TestApp::start().await;
+    let bob = app.sign_up("bob@example.com").await;
+    let (mut socket, _) = app.connect_initialized(&bob).await;
+
+    socket.send(&post("hello", "1")).await;
+    assert_eq!(
+        socket.recv().await,
+        UserMessage::Error(ErrorMessage::new(
+            ErrorCode::EmailNotVerified,
+            "Verify your email address first".to_string(),
+            Some("1".to_string()),
+        ))
+    );
+
+    // Verifying lifts the restriction on the open socket
+    app.verify_email(&bob).await;
+    socket.send(&post("hello", "2")).await;
+    assert_eq!(recv_basic(&mut socket).await.content, "hello");
+}
+
+#[actix_web::test]
+async fn messages_reach_everyone_in_the_room() {
+    let app = TestApp::start().await;
+    let alice = app.sign_up_verified("alice@example.com").await;
+    let bob = app.sign_up_verified("bob@example.com").await;
+    let (mut alice_socket, alice_init) = app.connect_initialized(&alice).await;
+    let (mut bob_socket, _) = app.connect_initialized(&bob).await;
+
+    alice_socket.send(&post("hello bob", "1")).await;
+    let received = recv_basic(&mut alice_socket).await;
+    let expected = BasicMessage {
+        content: "hello bob".to_string(),
+        sender_id: alice.user_id,
+        timestamp: received.timestamp,
+        message_id: received.message_id,
+        room_id: app.state.main_room_id.into(),
+        ws_id: alice_init.ws_id,
+    };
+    assert_eq!(received, expected);
+    assert_eq!(recv_basic(&mut bob_socket).await, expected);
+
+    // A socket opened later catches up on the room's history
+    let (mut late_socket, _) = app.connect_initialized(&bob).await;
+    assert_eq!(recv_basic(&mut late_socket).await, expected);
+    late_socket.assert_silent().await;
+}
+
+#[actix_web::test]
+async fn only_the_sender_can_delete_a_message() {
+    let app = TestApp::start().await;
+    let alice = app.sign_up_verified("alice@example.com").await;
+    let bob = app.sign_up_verified("bob@example.com").await;
+    let (mut alice_socket, _) = app.connect_initialized(&alice).await;
+    let (mut bob_socket, _) = app.connect_initialized(&bob).await;
+
+    alice_socket.send(&post("delete me", "1")).await;
+    let message = recv_basic(&mut alice_socket).await;
+    recv_basic(&mut bob_socket).await;
+
+    let deletion = |sender_id: Uuid, request_id: &str| {
+        UserMessage::Deletion(DeletionMessage {
+            sender_id: sender_id.to_string(),
+            message_id: message.message_id.to_string(),
+            request_id: Some(request_id.to_string()),
+        })
+    };
+    bob_socket.send(&deletion(bob.user_id, "2")).await;
+    assert_eq!(
+        bob_socket.recv().await,
+        UserMessage::Error(ErrorMessage::new(
+            ErrorCode::PermissionDenied,
+            "Only the sender of a message can delete it".to_string(),
+            Some("2".to_string()),
+        ))
+    );
+    alice_socket.assert_silent().await;
+
+    alice_socket.send(&deletion(alice.user_id, "3")).await;
+    // The request id is only meant for the sender's error frames
+    let announced = UserMessage::Deletion(DeletionMessage {
+        sender_id: alice.user_id.to_string(),
+        message_id: message.message_id.to_string(),
+        request_id: None,
+    });
+    assert_eq!(alice_socket.recv().await, announced);
+    assert_eq!(bob_socket.recv().await, announced);
+
+    let (mut late_socket, _) = app.connect_initialized(&bob).await;
+    late_socket.assert_silent().await;
+}
+
+#[actix_web::test]
+async fn changing_rooms_requires_membership() {
+    let app = TestApp::start().await;
+    let alice = app.sign_up_verified("alice@example.com").await;
+    let (mut socket, _) = app.connect_initialized(&alice).await;
+
+    socket.send(&post("history", "1")).await;
+    let message = recv_basic(&mut socket).await;
+
+    socket
+        .send(&UserMessage::ChangeRoom(ChangeRoomMessage {
+            room_id: app.state.main_room_id.into(),
+            sender_id: alice.user_id,
+            request_id: Some("2".to_string()),
+        }))
+        .await;
+    assert_eq!(recv_basic(&mut socket).await, message);
+
+    let elsewhere = Uuid::new_v4();
+    socket
+        .send(&UserMessage::ChangeRoom(ChangeRoomMessage {
+            room_id: elsewhere,
+            sender_id: alice.user_id,
+            request_id: Some("3".to_string()),
+        }))
+        .await;
+    assert_eq!(
+        socket.recv().await,
+        UserMessage::Error(ErrorMessage::new(
+            ErrorCode::PermissionDenied,
+            "Not a member of the requested room".to_string(),
+            Some("3".to_string()),
+        ))
+    );
+}
+
+#[actix_web::test]
+async fn removing_a_member_keeps_them_out_of_broadcasts() {
+    let app = TestApp::start().await;
+    let alice = app.sign_up_verified("alice@example.com").await;
+    let bob = app.sign_up_verified("bob@example.com").await;
+    let (mut alice_socket, _) = app.connect_initialized(&alice).await;
+    let (mut bob_socket, _) = app.connect_initialized(&bob).await;
+
+    alice_socket
+        .send(&UserMessage::UserRemoval(UserRemovalMessage {
+            removed_user: bob.user_id.to_string(),
+            room_id: app.state.main_room_id.into(),
+            sender_id: alice.user_id,
+            request_id: Some("1".to_string()),
+        }))
+        .await;
+    alice_socket.assert_silent().await;
+
+    alice_socket.send(&post("bob is gone", "2")).await;
+    assert_eq!(recv_basic(&mut alice_socket).await.content, "bob is gone");
+    bob_socket.assert_silent().await;
+}
+
+#[actix_web::test]
+async fn creating_a_room_sends_nothing_back() {
+    let app = TestApp::start().await;
+    let alice = app.sign_up_verified("alice@example.com").await;
+    let (mut socket, _) = app.connect_initialized(&alice).await;
+
+    socket
+        .send(&UserMessage::CreateRoomChange(CreateRoomChangeMessage::new(alice.user_id, "side room".to_string())))
+        .await;
+    socket.assert_silent().await;
+}
+
+#[actix_web::test]
+async fn renames_are_announced_to_the_main_room() {
+    let app = TestApp::start().await;
+    let alice = app.sign_up_verified("alice@example.com").await;
+    let bob = app.sign_up_verified("bob@example.com").await;
+    let (mut bob_socket, _) = app.connect_initialized(&bob).await;
+
+    let rename = UserMessage::UsernameChange(UsernameChangeMessage::new(alice.user_id, "alice".to_string()));
+    let (status, _, _) = app.post("/change_username", Some(&alice.cookie), serde_json::to_value(&rename).unwrap()).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(bob_socket.recv().await, rename);
+
+    let taken = UserMessage::UsernameChange(UsernameChangeMessage::new(bob.user_id, "alice".to_string()));
+    let (status, _, body) = app.post("/change_username", Some(&bob.cookie), serde_json::to_value(&taken).unwrap()).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body, serde_json::json!({"error": "Username Already In Use"}));
+    bob_socket.assert_silent().await;
+}
+
+#[actix_web::test]
+async fn messages_reach_sockets_on_other_nodes() {
+    let node_a = TestApp::start().await;
+    let node_b = node_a.start_node().await;
+    let alice = node_a.sign_up_verified("alice@example.com").await;
+    let bob = node_b.sign_up_verified("bob@example.com").await;
+    let (mut alice_socket, _) = node_a.connect_initialized(&alice).await;
+    let (mut bob_socket, _) = node_b.connect_initialized(&bob).await;
+
+    alice_socket.send(&post("hello from a", "1")).await;
+    let sent = recv_basic(&mut alice_socket).await;
+    assert_eq!(recv_basic(&mut bob_socket).await, sent);
+
+    bob_socket.send(&post("hello from b", "2")).await;
+    let sent = recv_basic(&mut bob_socket).await;
+    assert_eq!(recv_basic(&mut alice_socket).await, sent);
+    alice_socket.assert_silent().await;
+}
+
+#[actix_web::test]
+async fn disconnects_reach_sockets_on_other_nodes() {
+    let node_a = TestApp::start().await;
+    let node_b = node_a.start_node().await;
+    let alice = node_a.sign_up_verified("alice@example.com").await;
+    let (mut socket, _) = node_b.connect_initialized(&alice).await;
+
+    let (status, _) = node_a.get("/logout", &alice.cookie).await;
+    assert_eq!(status, StatusCode::FOUND);
+    assert_eq!(
+        socket.recv_close().await,
+        Some(CloseReason { code: CloseCode::Policy, description: Some("Logged out".to_string()) })
+    );
+}
+
+#[actix_web::test]
+async fn presence_counts_sockets_on_every_node() {
+    let node_a = TestApp::start().await;
+    let node_b = node_a.start_node().await;
+    let alice = node_a.sign_up_verified("alice@example.com").await;
+    let (socket_a, init_a) = node_a.connect_initialized(&alice).await;
+    let (socket_b, init_b) = node_b.connect_initialized(&alice).await;
+
+    let mut open = node_a.wait_for_sockets(&alice, 2).await;
+    open.sort();
+    let mut expected = vec![init_a.ws_id, init_b.ws_id];
+    expected.sort();
+    assert_eq!(open, expected);
+    assert!(node_a.is_online(&alice).await);
+
+    socket_a.close().await;
+    node_a.wait_for_sockets(&alice, 1).await;
+    assert!(node_a.is_online(&alice).await);
+
+    socket_b.close().await;
+    node_a.wait_for_sockets(&alice, 0).await;
+    assert!(!node_a.is_online(&alice).await);
+}
+
+#[actix_web::test]
+async fn presence_left_by_a_crashed_node_is_reaped() {
+    let app = TestApp::start().await;
+    let alice = app.sign_up_verified("alice@example.com").await;
+    let bob = app.sign_up_verified("bob@example.com").await;
+    let (_bob_socket, _) = app.connect_initialized(&bob).await;
+    app.wait_for_sockets(&bob, 1).await;
+
+    // Entries an instance that crashed never removed, already expired
+    let expired = (chrono::Utc::now().timestamp_millis() - 1000) as u64;
+    for user in [&alice, &bob] {
+        let stale = SocketPresence { ws_id: Uuid::new_v4().into(), session_id: None, node_id: Uuid::new_v4().into() };
+        let key = format!("presence:{}", user.user_id);
+        app.state.kv.scored_add(&key, &serde_json::to_string(&stale).unwrap(), expired).await.unwrap();
+    }
+    app.state.kv.scored_add("presence_users", &alice.user_id.to_string(), expired).await.unwrap();
+    app.state.storage.set_status(&alice.user_id.into(), ConnectionState::Online).await.unwrap();
+    app.wait_for_sockets(&alice, 0).await;
+    app.wait_for_sockets(&bob, 1).await;
+
+    app.state.cluster.reap(app.state.storage.clone()).await;
+    assert!(!app.is_online(&alice).await);
+    assert!(app.is_online(&bob).await);
+    app.wait_for_sockets(&bob, 1).await;
+}
+
+#[actix_web::test]
+async fn pings_are_answered_with_pongs() {
+    let app = TestApp::start().await;
+    let alice = app.sign_up_verified("alice@example.com").await;
+    let (mut socket, _) = app.connect_initialized(&alice).await;
+
+    socket.ping(b"are you there").await;
+    assert_eq!(socket.recv_pong().await, b"are you there".to_vec());
+}
+
+#[actix_web::test]
+async fn silent_sockets_are_closed_and_go_offline() {
+    let app = TestApp::start_with(|config| {
+        config.websocket.heartbeat_interval_secs = 1;
+        config.websocket.client_timeout_secs = 2;
+    })
+    .await;
+    let alice = app.sign_up_verified("alice@example.com").await;
+    let (mut socket, _) = app.connect_initialized(&alice).await;
+    app.wait_for_sockets(&alice, 1).await;
+
+    socket.recv_ping().await;
+    assert_eq!(
+        socket.recv_close_within(Duration::from_secs(5)).await,
+        Some(CloseReason { code: CloseCode::Away, description: Some("Heartbeat timed out".to_string()) })
+    );
+    app.wait_for_sockets(&alice, 0).await;
+    assert!(!app.is_online(&alice).await);
+    assert!(!app.state.actor_registry.lock().unwrap().contains_key(&alice.user_id.into()));
+}
+
+#[actix_web::test]
+async fn shutdown_tells_sockets_to_reconnect_and_refuses_new_ones() {
+    let app = TestApp::start_with(|config| config.shutdown.reconnect_after_secs = 3).await;
+    let alice = app.sign_up_verified("alice@example.com").await;
+    let (mut socket, _) = app.connect_initialized(&alice).await;
+    app.wait_for_sockets(&alice, 1).await;
+
+    black_signal::shutdown::drain(&app.state).await;
+
+    assert_eq!(socket.recv().await, UserMessage::ServerShutdown(ServerShutdownMessage::new(3000)));
+    assert_eq!(
+        socket.recv_close().await,
+        Some(CloseReason {
+            code: CloseCode::Restart,
+            description: Some("Server going away, reconnect in 3 seconds".to_string()),
+        })
+    );
+    assert!(app.state.actor_registry.lock().unwrap().is_empty());
+    assert_eq!(app.state.drain.in_flight(), 0);
+    assert!(!app.is_online(&alice).await);
+
+    let (status, _) = app.get(&format!("/ws/?protocol_version={}", PROTOCOL_VERSION), &alice.cookie).await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+}
+
+#[actix_web::test]
+async fn legacy_clients_are_told_about_errors_in_a_message_they_can_read() {
+    let app = TestApp::start().await;
+    let alice = app.sign_up_verified("alice@example.com").await;
+    let mut socket = app.connect_legacy(&alice).await;
+    assert!(matches!(socket.recv().await, UserMessage::Initialization(_)));
+
+    socket.send(&UserMessage::Notification(NotificationMessage { sender_id: alice.user_id.to_string() })).await;
+    let notice = recv_basic(&mut socket).await;
+    assert_eq!(notice.content, "Error: Message type cannot be sent by clients");
+    assert_eq!((notice.sender_id, notice.room_id), (Uuid::nil(), app.state.main_room_id.0));
+}
+
+#[actix_web::test]
+async fn metrics_count_sockets_messages_and_failed_logins() {
+    let app = TestApp::start_with(|config| config.metrics.token = Some("scrape-secret".to_string())).await;
+    let alice = app.sign_up_verified("alice@example.com").await;
+    let (mut socket, _) = app.connect_initialized(&alice).await;
+    socket.send(&post("hello", "1")).await;
+    recv_basic(&mut socket).await;
+    let (status, _, _) = app.log_in("alice@example.com", "wrong password").await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (status, _) = app.get_text("/metrics").await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = app.get_text_with_token("/metrics", "wrong").await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, metrics) = app.get_text_with_token("/metrics", "scrape-secret").await;
+    assert_eq!(status, StatusCode::OK);
+    for line in [
+        "black_signal_websockets_connected 1",
+        "black_signal_messages_sent_total 1",
+        "black_signal_login_failures_total 1",
+    ] {
+        assert!(metrics.lines().any(|metric| metric == line), "missing {} in\n{}", line, metrics);
+    }
+    for prefix in [
+        "black_signal_broadcast_duration_seconds_count ",
+        "black_signal_db_query_duration_seconds_count{operation=\"create_message\"}",
+    ] {
+        assert!(metrics.lines().any(|metric| metric.starts_with(prefix)), "missing {} in\n{}", prefix, metrics);
+    }
+    assert!(!metrics.contains(&app.state.main_room_id.0.to_string()), "room ids leak into\n{}", metrics);
+}
+
+#[actix_web::test]
+async fn metrics_are_not_served_without_a_token() {
+    let app = TestApp::start().await;
+    let (status, _) = app.get_text("/metrics").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn readiness_reports_dependencies_and_fails_while_draining() {
+    let app = TestApp::start().await;
+    let (status, body) = app.get_text("/healthz").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(serde_json::from_str::<serde_json::Value>(&body).unwrap(), serde_json::json!({"status": "ok"}));
+
+    let (status, body) = app.get_text("/readyz").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        serde_json::from_str::<serde_json::Value>(&body).unwrap(),
+        serde_json::json!({"ready": true, "draining": false, "storage": {"ok": true}, "kv": {"ok": true}})
+    );
+
+    app.state.drain.start();
+    let (status, body) = app.get_text("/readyz").await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!((&body["ready"], &body["draining"]), (&serde_json::json!(false), &serde_json::json!(true)));
+}
+
+#[actix_web::test]
+async fn storage_outages_turn_clients_away_until_reconnected() {
+    let app = TestApp::start_with(|config| {
+        config.database.health_check_interval_secs = 1;
+        config.database.retry_initial_ms = 50;
+        config.database.retry_max_ms = 100;
+    })
+    .await;
+    let alice = app.sign_up_verified("alice@example.com").await;
+    let (mut socket, _) = app.connect_initialized(&alice).await;
+
+    app.memory.set_unreachable(true);
+    app.wait_for_storage(false).await;
+    let (status, body) = app.get("/sessions", &alice.cookie).await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(body, serde_json::json!({"error": "Service temporarily unavailable, try again shortly"}));
+    let (status, _) = app.get_text("/readyz").await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    socket.send(&post("hello", "1")).await;
+    match socket.recv().await {
+        UserMessage::Error(error) => {
+            assert_eq!((error.code, error.request_id.as_deref()), (ErrorCode::Unavailable, Some("1")));
+        }
+        other => panic!("expected an Error frame, got {:?}", other),
+    }
+
+    app.memory.set_unreachable(false);
+    app.wait_for_storage(true).await;
+    socket.send(&post("hello", "2")).await;
+    assert_eq!(recv_basic(&mut socket).await.content, "hello");
+    let (status, _) = app.get_text("/readyz").await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn audit_log_records_account_activity_for_admins() {
+    let file = std::env::temp_dir().join(format!("black_signal_audit_{}.jsonl", Uuid::new_v4()));
+    let app = TestApp::start_with(|config| config.audit.file = Some(file.to_string_lossy().into_owned())).await;
+    let alice = app.sign_up_verified("alice@example.com").await;
+    let (status, _, _) = app.log_in("alice@example.com", "wrong password").await;
+    assert_eq!(status, StatusCode::OK);
+    let (mut socket, _) = app.connect_initialized(&alice).await;
+    socket.send(&post("delete me", "1")).await;
+    let message = recv_basic(&mut socket).await;
+    socket
+        .send(&UserMessage::Deletion(DeletionMessage {
+            sender_id: alice.user_id.to_string(),
+            message_id: message.message_id.to_string(),
+            request_id: None,
+        }))
+        .await;
+    socket.recv().await;
+
+    let (status, _) = app.get("/admin/audit", &alice.cookie).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    // The seeded test user has a well known password and no admin rights
+    let (status, test_user, _) = app.log_in(TEST_LOGIN, "password").await;
+    assert_eq!(status, StatusCode::FOUND);
+    let (status, _) = app.get("/admin/audit", &test_user.unwrap()).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let admin = app.sign_up_verified(ADMIN_LOGIN).await.cookie;
+
+    let kinds = |events: &serde_json::Value| -> Vec<String> {
+        events.as_array().unwrap().iter().map(|event| event["kind"].as_str().unwrap().to_string()).collect()
+    };
+    let (status, events) = app.get(&format!("/admin/audit?user_id={}", alice.user_id), &admin).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(kinds(&events), ["MessageDeleted", "LoginFailed", "RoomMemberAdded", "Signup"]);
+    assert_eq!(events[1]["target_id"], serde_json::json!(alice.user_id));
+    assert_eq!(events[1]["details"], serde_json::json!({"login": "alice@example.com", "method": "password"}));
+
+    let room = app.state.main_room_id.0;
+    let (_, events) = app.get(&format!("/admin/audit?room_id={}&kind=MessageDeleted", room), &admin).await;
+    assert_eq!(kinds(&events), ["MessageDeleted"]);
+    assert_eq!(events[0]["details"]["message_id"], serde_json::json!(message.message_id.to_string()));
+    let (_, events) = app.get(&format!("/admin/audit?since={}", message.timestamp + 3600), &admin).await;
+    assert_eq!(events, serde_json::json!([]));
+    let (status, _) = app.get("/admin/audit?user_id=nobody", &admin).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    // Admins reading the log are audited too
+    let (_, events) = app.get("/admin/audit?kind=AdminAction&limit=1", &admin).await;
+    assert_eq!(events[0]["details"], serde_json::json!({"action": "view_audit_log"}));
+    // The file is appended to in the background
+    let signup_logged = || {
+        let lines = std::fs::read_to_string(&file).unwrap();
+        let logged: Vec<serde_json::Value> = lines.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        logged.iter().any(|event| event["kind"] == "Signup" && event["actor_id"] == serde_json::json!(alice.user_id))
+    };
+    let deadline = std::time::Instant::now() + Duration::from_secs(5);
+    while !signup_logged() {
+        assert!(std::time::Instant::now() < deadline, "signup never reached the audit file");
+        actix_web::rt::time::sleep(Duration::from_millis(20)).await;
+    }
+    std::fs::remove_file(&file).unwrap();
+}
+
+#[actix_web::test]
+async fn audit_events_from_a_storage_outage_are_stored_afterwards() {
+    let app = TestApp::start_with(|config| {
+        config.database.health_check_interval_secs = 1;
+        config.database.retry_initial_ms = 50;
+        config.database.retry_max_ms = 100;
+    })
+    .await;
+    let admin = app.sign_up_verified(ADMIN_LOGIN).await;
+    app.memory.set_unreachable(true);
+    app.wait_for_storage(false).await;
+    // Requests are turned away by now, but ones already in flight still audit
+    app.state.audit.record(AuditEvent::new(AuditKind::LoginFailed).detail("login", "mallory@example.com")).await;
+
+    app.memory.set_unreachable(false);
+    app.wait_for_storage(true).await;
+    let (status, events) = app.get("/admin/audit?kind=LoginFailed", &admin.cookie).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(events[0]["details"], serde_json::json!({"login": "mallory@example.com"}));
+}
+
+#[actix_web::test]
+async fn revoked_sessions_stay_dead_without_their_marker() {
+    let app = TestApp::start().await;
+    let alice = app.sign_up_verified("alice@example.com").await;
+    let (status, other_cookie, _) = app.log_in("alice@example.com", PASSWORD).await;
+    assert_eq!(status, StatusCode::FOUND);
+    let other_cookie = other_cookie.unwrap();
+    let (_, _, tokens) = app.post("/token", None, serde_json::json!({"username": "alice@example.com", "password": PASSWORD})).await;
+    let refresh_token = tokens["refresh_token"].as_str().unwrap().to_string();
+
+    let (status, sessions) = app.get("/sessions", &other_cookie).await;
+    assert_eq!(status, StatusCode::OK);
+    let other = sessions.as_array().unwrap().iter().find(|session| session["current"] == true).unwrap();
+    let other_id = other["session_id"].as_str().unwrap().to_string();
+    assert_eq!(app.delete(&format!("/sessions/{}", other_id), &alice.cookie).await, StatusCode::OK);
+    let (status, _, _) = app.post("/token/revoke", None, serde_json::json!({"refresh_token": refresh_token})).await;
+    assert_eq!(status, StatusCode::OK);
+
+    // The token session is gone from the list as well
+    let (_, sessions) = app.get("/sessions", &alice.cookie).await;
+    assert_eq!(sessions.as_array().unwrap().len(), 1);
+    // Losing the revocation markers must not bring either session back
+    for session_id in [other_id.as_str(), tokens["session_id"].as_str().unwrap()] {
+        app.state.kv.del(&[&format!("session_revoked:{}", session_id)]).await.unwrap();
+    }
+    let (status, _) = app.get("/sessions", &other_cookie).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _, _) = app.post("/token/refresh", None, serde_json::json!({"refresh_token": refresh_token})).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+async fn reusing_a_rotated_refresh_token_ends_the_token_session() {
+    let app = TestApp::start().await;
+    app.sign_up_verified("alice@example.com").await;
+    let (_, _, first) = app.post("/token", None, serde_json::json!({"username": "alice@example.com", "password": PASSWORD})).await;
+    let (status, _, second) = app.post("/token/refresh", None, serde_json::json!({"refresh_token": first["refresh_token"]})).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (status, _, _) = app.post("/token/refresh", None, serde_json::json!({"refresh_token": first["refresh_token"]})).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    // The token it was rotated into goes with it
+    let (status, _, _) = app.post("/token/refresh", None, serde_json::json!({"refresh_token": second["refresh_token"]})).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let session_id = second["session_id"].as_str().unwrap();
+    assert!(app.state.sessions.is_revoked(&Uuid::parse_str(session_id).unwrap().into()).await);
+}
+
+#[actix_web::test]
+async fn revoking_tokens_shares_the_login_rate_limit() {
+    let app = TestApp::start_with(|config| {
+        config.rate_limit.ip.capacity = 100;
+        config.rate_limit.costs.insert(RateAction::Login, 100);
+    })
+    .await;
+    let (status, _, _) = app.post("/token/revoke", None, serde_json::json!({"refresh_token": "guess"})).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _, _) = app.post("/token/revoke", None, serde_json::json!({"refresh_token": "another guess"})).await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    let (status, _, _) = app.post("/token", None, serde_json::json!({"username": "alice@example.com", "password": PASSWORD})).await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[test]
+fn redis_deployments_need_a_shared_token_secret() {
+    let mut config = Config::default();
+    config.kv.backend = KvBackend::Redis;
+    let error = config.validate().unwrap_err().to_string();
+    assert!(error.contains("tokens.secret (JWT_SECRET) must be set"), "{}", error);
+    config.tokens.secret = Some("shared secret".to_string());
+    assert!(config.validate().is_ok());
+
+    let mut config = Config::default();
+    config.kv.backend = KvBackend::Memory;
+    config.session.store = SessionStoreKind::Memory;
+    assert!(config.validate().is_ok());
+}
+
+#[test]
+fn rate_limit_capacities_from_the_environment_must_be_whole_numbers() {
+    // No other test reads these variables
+    std::env::set_var("RATE_LIMIT_USER_CAPACITY", "1.5");
+    std::env::set_var("RATE_LIMIT_IP_CAPACITY", "-5");
+    let mut config = RateLimitConfig::default();
+    config.apply_env();
+    std::env::remove_var("RATE_LIMIT_USER_CAPACITY");
+    std::env::remove_var("RATE_LIMIT_IP_CAPACITY");
+    assert_eq!((config.user.capacity, config.ip.capacity), (100, 300));
+    assert_eq!(
+        config.problems(),
+        [
+            "RATE_LIMIT_USER_CAPACITY must be a whole number, got \"1.5\"",
+            "RATE_LIMIT_IP_CAPACITY must be a whole number, got \"-5\"",
+        ]
+    );
+}
+
+#[test]
+fn unpayable_rate_limits_are_refused_and_retry_after_saturates() {
+    let metrics = Metrics::new(&MetricsConfig::default());
+    let mut config = RateLimitConfig::default();
+    config.user.refill_per_second = 0.0;
+    assert!(RateLimiter::new(config, metrics.clone()).is_err());
+    let mut config = RateLimitConfig::default();
+    config.costs.insert(RateAction::Login, config.user.capacity + 1);
+    assert!(RateLimiter::new(config, metrics.clone()).is_err());
+    assert!(RateLimiter::new(RateLimitConfig::default(), metrics).is_ok());
+
+    let response = too_many_requests(Duration::MAX);
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(response.headers().get("Retry-After").unwrap(), u64::MAX.to_string().as_str());
+}
+
+#[actix_web::test]
+async fn restarting_keeps_a_single_test_user_even_without_rooms() {
+    let app = TestApp::start().await;
+    let test_user = app.memory.get_user_by_login(TEST_LOGIN).await.unwrap().unwrap();
+    app.memory.delete_user(&test_user.user_id).await.unwrap();
+    app.memory.create_user(&UserData { rooms: Vec::new(), ..test_user.clone() }).await.unwrap();
+
+    let node = app.start_node().await;
+    let seeded = app.memory.get_user_by_login(TEST_LOGIN).await.unwrap().unwrap();
+    assert_eq!(seeded.user_id, test_user.user_id);
+    assert_eq!(seeded.rooms, vec![node.state.main_room_id]);
+    assert!(app.memory.get_room(&node.state.main_room_id).await.unwrap().is_some());
+}
+
+#[actix_web::test]
+async fn only_configured_deployments_seed_a_test_user_and_it_is_never_an_admin() {
+    let app = TestApp::start_with(|config| config.accounts.seed_test_user = false).await;
+    assert!(app.memory.get_user_by_login(TEST_LOGIN).await.unwrap().is_none());
+    assert!(app.memory.get_room(&app.state.main_room_id).await.unwrap().is_some());
+    let alice = app.sign_up_verified("alice@example.com").await;
+    let (_, init) = app.connect_initialized(&alice).await;
+    assert_eq!(init.user_map.len(), 1);
+
+    // A test user left over from an older version loses its admin rights
+    let app = TestApp::start().await;
+    let test_user = app.memory.get_user_by_login(TEST_LOGIN).await.unwrap().unwrap();
+    app.memory.delete_user(&test_user.user_id).await.unwrap();
+    app.memory.create_user(&UserData { is_admin: true, ..test_user }).await.unwrap();
+    let _node = app.start_node().await;
+    assert!(!app.memory.get_user_by_login(TEST_LOGIN).await.unwrap().unwrap().is_admin);
+}
+
+#[actix_web::test]
+async fn required_two_factor_binds_humans_but_not_their_bots() {
+    let app = TestApp::start_with(|config| config.two_factor.required = true).await;
+    let alice = app.sign_up_verified("alice@example.com").await;
+    let bob = app.sign_up_verified("bob@example.com").await;
+    let two_factor = TwoFactor { enabled: true, ..TwoFactor::pending() };
+    app.state.storage.set_two_factor(&alice.user_id.into(), Some(two_factor)).await.unwrap();
+
+    let room = app.state.main_room_id.0;
+    let (status, _, _) = app.post(&format!("/rooms/{}/messages", room), Some(&bob.cookie), serde_json::json!({"content": "hi"})).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (status, _, _) = app.post("/bots", Some(&bob.cookie), serde_json::json!({"username": "sneaky"})).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    let (status, _, bot) = app.post("/bots", Some(&alice.cookie), serde_json::json!({"username": "helper"})).await;
+    assert_eq!(status, StatusCode::OK);
+    let path = format!("/bots/{}/tokens", bot["user_id"].as_str().unwrap());
+    let (_, _, token) = app.post(&path, Some(&alice.cookie), serde_json::json!({"name": "ci", "scopes": ["Read", "Post"]})).await;
+    let mut socket = app.connect_with_token(token["token"].as_str().unwrap()).await;
+    assert!(matches!(socket.recv().await, UserMessage::Initialization(_)));
+}
@@ -0,0 +1,365 @@
+//! Starts the whole application in process, with in-memory storage, kv and
+//! session stores and an outbox mailer, and drives it like the frontend does.
+
+use actix_codec::Framed;
+use actix_web::cookie::Cookie;
+use actix_web::http::StatusCode;
+use actix_web::web;
+use awc::ws;
+use awc::BoxedSocket;
+use black_signal::appstate::AppState;
+use black_signal::config::Config;
+use black_signal::kv::{KvBackend, KvStore};
+use black_signal::mailer::{Email, MailerConfig};
+use black_signal::session_key::{SessionKeys, SESSION_COOKIE_NAME};
+use black_signal::session_store::{SessionBackend, SessionStoreKind};
+use black_signal::storage::{MemoryStorage, ResilientStorage, Storage, StorageBackend};
+use black_signal::structs::ConnectionState;
+use black_signal::{app, build_state};
+use black_signal_protocol::*;
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+pub const PASSWORD: &str = "correct horse battery staple";
+pub const TEST_LOGIN: &str = "test@gmail.com";
+// Given admin rights by the test config
+pub const ADMIN_LOGIN: &str = "admin@example.com";
+const CORS_ORIGIN: &str = "http://localhost:8080";
+// Long enough for a slow CI machine, short enough to fail a hung test quickly
+const FRAME_TIMEOUT: Duration = Duration::from_secs(5);
+// How long a socket has to stay quiet to count as having received nothing
+const SILENCE: Duration = Duration::from_millis(200);
+
+pub struct TestApp {
+    pub server: actix_test::TestServer,
+    pub state: web::Data<AppState>,
+    // Shared by every node, can be made unreachable to simulate an outage
+    pub memory: Arc<MemoryStorage>,
+    client: awc::Client,
+    dir: PathBuf,
+    // Nodes started from another app share its directory
+    owns_dir: bool,
+}
+
+// A signed up account and the session cookie it is logged in with
+pub struct TestUser {
+    pub login: String,
+    pub user_id: Uuid,
+    pub username: String,
+    pub cookie: Cookie<'static>,
+}
+
+pub struct TestSocket {
+    framed: Framed<BoxedSocket, ws::Codec>,
+}
+
+impl TestApp {
+    pub async fn start() -> Self {
+        TestApp::start_with(|_| {}).await
+    }
+
+    // Starts with settings adjusted on top of the test defaults
+    pub async fn start_with(configure: impl FnOnce(&mut Config)) -> Self {
+        let dir = std::env::temp_dir().join(format!("black_signal_test_{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut config = test_config(&dir);
+        configure(&mut config);
+        let memory = Arc::new(MemoryStorage::default());
+        TestApp::start_on(config, memory, KvStore::memory(), dir, true).await
+    }
+
+    // Another backend instance on the same storage, kv store and session key
+    pub async fn start_node(&self) -> Self {
+        let config = test_config(&self.dir);
+        TestApp::start_on(config, self.memory.clone(), self.state.kv.clone(), self.dir.clone(), false).await
+    }
+
+    async fn start_on(config: Config, memory: Arc<MemoryStorage>, kv: KvStore, dir: PathBuf, owns_dir: bool) -> Self {
+        let storage: Arc<dyn Storage> = Arc::new(ResilientStorage::new(memory.clone(), &config.database));
+        let session_store = SessionBackend::Memory(kv.clone());
+        let state = build_state(&config, storage, kv, session_store.clone()).await.expect("failed to build app state");
+        let session_keys = web::Data::new(SessionKeys::load(&config.session_key).unwrap());
+        let server = actix_test::start({
+            let state = state.clone();
+            move || app(state.clone(), session_keys.clone(), session_store.clone(), CORS_ORIGIN)
+        });
+        // Redirects are asserted on, not followed
+        let client = awc::Client::builder().disable_redirects().finish();
+        TestApp { server, state, memory, client, dir, owns_dir }
+    }
+
+    pub async fn post(
+        &self,
+        path: &str,
+        cookie: Option<&Cookie<'static>>,
+        body: Value,
+    ) -> (StatusCode, Option<Cookie<'static>>, Value) {
+        let mut request = self.client.post(self.server.url(path));
+        if let Some(cookie) = cookie {
+            request = request.cookie(cookie.clone());
+        }
+        let mut response = request.send_json(&body).await.unwrap();
+        let cookie = response.cookie(SESSION_COOKIE_NAME);
+        let body = response.body().await.unwrap();
+        let json = serde_json::from_slice(&body).unwrap_or(Value::Null);
+        (response.status(), cookie, json)
+    }
+
+    // For routes that answer with a redirect, returns where it points
+    pub async fn post_redirect(&self, path: &str, body: Value) -> (StatusCode, Option<Cookie<'static>>, Option<String>) {
+        let response = self.client.post(self.server.url(path)).send_json(&body).await.unwrap();
+        let location = response
+            .headers()
+            .get("location")
+            .map(|location| location.to_str().unwrap().to_string());
+        (response.status(), response.cookie(SESSION_COOKIE_NAME), location)
+    }
+
+    pub async fn get(&self, path: &str, cookie: &Cookie<'static>) -> (StatusCode, Value) {
+        let mut response = self.client.get(self.server.url(path)).cookie(cookie.clone()).send().await.unwrap();
+        let body = response.body().await.unwrap();
+        (response.status(), serde_json::from_slice(&body).unwrap_or(Value::Null))
+    }
+
+    pub async fn delete(&self, path: &str, cookie: &Cookie<'static>) -> StatusCode {
+        let response = self.client.delete(self.server.url(path)).cookie(cookie.clone()).send().await.unwrap();
+        response.status()
+    }
+
+    pub async fn get_text(&self, path: &str) -> (StatusCode, String) {
+        let mut response = self.client.get(self.server.url(path)).send().await.unwrap();
+        let body = response.body().await.unwrap();
+        (response.status(), String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    pub async fn get_text_with_token(&self, path: &str, token: &str) -> (StatusCode, String) {
+        let request = self.client.get(self.server.url(path)).bearer_auth(token);
+        let mut response = request.send().await.unwrap();
+        let body = response.body().await.unwrap();
+        (response.status(), String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    pub async fn sign_up(&self, login: &str) -> TestUser {
+        let (status, cookie, body) = self.post("/create_login", None, json!({"username": login, "password": PASSWORD})).await;
+        assert_eq!(status, StatusCode::FOUND, "signup of {} failed: {}", login, body);
+        let user = self.state.storage.get_user_by_login(login).await.unwrap().expect("signed up user is stored");
+        TestUser {
+            login: login.to_string(),
+            user_id: user.user_id.into(),
+            username: user.username,
+            cookie: cookie.expect("signup sets the session cookie"),
+        }
+    }
+
+    pub async fn log_in(&self, login: &str, password: &str) -> (StatusCode, Option<Cookie<'static>>, Value) {
+        self.post("/login", None, json!({"username": login, "password": password})).await
+    }
+
+    // Every email sent to the address so far, oldest first
+    pub fn emails_to(&self, login: &str) -> Vec<Email> {
+        let mut paths: Vec<PathBuf> = std::fs::read_dir(self.dir.join("outbox"))
+            .map(|entries| entries.map(|entry| entry.unwrap().path()).collect())
+            .unwrap_or_default();
+        paths.sort();
+        paths
+            .into_iter()
+            .map(|path| serde_json::from_slice::<Email>(&std::fs::read(path).unwrap()).unwrap())
+            .filter(|email| email.to == login)
+            .collect()
+    }
+
+    // Polls for mail sent after the response, returning all of it
+    pub async fn wait_for_emails(&self, login: &str, count: usize) -> Vec<Email> {
+        let deadline = Instant::now() + FRAME_TIMEOUT;
+        loop {
+            let emails = self.emails_to(login);
+            if emails.len() >= count {
+                return emails;
+            }
+            assert!(Instant::now() < deadline, "expected {} emails to {}, got {}", count, login, emails.len());
+            actix_web::rt::time::sleep(Duration::from_millis(20)).await;
+        }
+    }
+
+    // Follows the link in the latest verification email
+    pub async fn verify_email(&self, user: &TestUser) {
+        let email = self.emails_to(&user.login).pop().expect("a verification email was sent");
+        let token = email.body.rsplit("token=").next().unwrap().trim();
+        let (status, _, body) = self.post("/verify_email", None, json!({"token": token})).await;
+        assert_eq!(status, StatusCode::OK, "verification failed: {}", body);
+    }
+
+    pub async fn sign_up_verified(&self, login: &str) -> TestUser {
+        let user = self.sign_up(login).await;
+        self.verify_email(&user).await;
+        user
+    }
+
+    // Sockets of the user listed by /sessions, polled until there are this many
+    pub async fn wait_for_sockets(&self, user: &TestUser, count: usize) -> Vec<Uuid> {
+        let deadline = Instant::now() + FRAME_TIMEOUT;
+        loop {
+            let (status, sessions) = self.get("/sessions", &user.cookie).await;
+            assert_eq!(status, StatusCode::OK);
+            let ws_ids: Vec<Uuid> = sessions
+                .as_array()
+                .unwrap()
+                .iter()
+                .flat_map(|session| serde_json::from_value::<Vec<Uuid>>(session["ws_ids"].clone()).unwrap())
+                .collect();
+            if ws_ids.len() == count {
+                return ws_ids;
+            }
+            assert!(Instant::now() < deadline, "expected {} sockets, still have {:?}", count, ws_ids);
+            actix_web::rt::time::sleep(Duration::from_millis(20)).await;
+        }
+    }
+
+    // Polls until storage is, or is no longer, taken for reachable
+    pub async fn wait_for_storage(&self, available: bool) {
+        let deadline = Instant::now() + FRAME_TIMEOUT;
+        while self.state.storage.is_available() != available {
+            assert!(Instant::now() < deadline, "storage availability never became {}", available);
+            actix_web::rt::time::sleep(Duration::from_millis(20)).await;
+        }
+    }
+
+    pub async fn is_online(&self, user: &TestUser) -> bool {
+        let stored = self.state.storage.get_user(&user.user_id.into()).await.unwrap().unwrap();
+        matches!(stored.status, ConnectionState::Online)
+    }
+
+    pub async fn connect(&self, user: &TestUser) -> TestSocket {
+        self.connect_to(user, &format!("/ws/?protocol_version={}", PROTOCOL_VERSION)).await
+    }
+
+    // Connects like clients from before the handshake, which speak version 1
+    pub async fn connect_legacy(&self, user: &TestUser) -> TestSocket {
+        self.connect_to(user, "/ws/").await
+    }
+
+    // Connects the way bots and token clients do, with a bearer token
+    pub async fn connect_with_token(&self, token: &str) -> TestSocket {
+        let url = self.server.url(&format!("/ws/?protocol_version={}", PROTOCOL_VERSION));
+        let (response, framed) = self.client.ws(url).bearer_auth(token).connect().await.expect("websocket handshake failed");
+        assert_eq!(response.status(), StatusCode::SWITCHING_PROTOCOLS);
+        TestSocket { framed }
+    }
+
+    async fn connect_to(&self, user: &TestUser, path: &str) -> TestSocket {
+        let url = self.server.url(path);
+        let (response, framed) = self
+            .client
+            .ws(url)
+            .cookie(user.cookie.clone())
+            .connect()
+            .await
+            .expect("websocket handshake failed");
+        assert_eq!(response.status(), StatusCode::SWITCHING_PROTOCOLS);
+        TestSocket { framed }
+    }
+
+    // Connects and reads the Initialization frame every socket starts with
+    pub async fn connect_initialized(&self, user: &TestUser) -> (TestSocket, InitMessage) {
+        let mut socket = self.connect(user).await;
+        match socket.recv().await {
+            UserMessage::Initialization(init) => (socket, init),
+            other => panic!("expected Initialization, got {:?}", other),
+        }
+    }
+}
+
+impl Drop for TestApp {
+    fn drop(&mut self) {
+        if self.owns_dir {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+}
+
+fn test_config(dir: &Path) -> Config {
+    let mut config = Config::default();
+    config.database.backend = StorageBackend::Memory;
+    config.kv.backend = KvBackend::Memory;
+    config.session.store = SessionStoreKind::Memory;
+    config.mail = MailerConfig::Outbox { dir: dir.join("outbox").to_string_lossy().into_owned() };
+    config.session_key.key_file = dir.join("session.key").to_string_lossy().into_owned();
+    config.accounts.seed_test_user = true;
+    config.accounts.admin_logins = vec![ADMIN_LOGIN.to_string()];
+    config
+}
+
+impl TestSocket {
+    pub async fn send(&mut self, message: &UserMessage) {
+        let serialized = serde_json::to_string(message).unwrap();
+        self.framed.send(ws::Message::Text(serialized.into())).await.unwrap();
+    }
+
+    async fn next_frame(&mut self, wait: Duration) -> Option<ws::Frame> {
+        loop {
+            let frame = match actix_web::rt::time::timeout(wait, self.framed.next()).await {
+                Ok(Some(frame)) => frame.unwrap(),
+                Ok(None) | Err(_) => return None,
+            };
+            match frame {
+                ws::Frame::Ping(_) | ws::Frame::Pong(_) => continue,
+                frame => return Some(frame),
+            }
+        }
+    }
+
+    pub async fn recv(&mut self) -> UserMessage {
+        match self.next_frame(FRAME_TIMEOUT).await {
+            Some(ws::Frame::Text(text)) => serde_json::from_slice(&text).unwrap(),
+            other => panic!("expected a text frame, got {:?}", other),
+        }
+    }
+
+    // Hangs up without waiting for the server to answer the close frame
+    pub async fn close(mut self) {
+        self.framed.send(ws::Message::Close(None)).await.unwrap();
+    }
+
+    pub async fn recv_close(&mut self) -> Option<ws::CloseReason> {
+        self.recv_close_within(FRAME_TIMEOUT).await
+    }
+
+    pub async fn recv_close_within(&mut self, wait: Duration) -> Option<ws::CloseReason> {
+        match self.next_frame(wait).await {
+            Some(ws::Frame::Close(reason)) => reason,
+            other => panic!("expected a close frame, got {:?}", other),
+        }
+    }
+
+    // Waits for the next ping, answering nothing
+    pub async fn recv_ping(&mut self) {
+        match actix_web::rt::time::timeout(FRAME_TIMEOUT, self.framed.next()).await {
+            Ok(Some(Ok(ws::Frame::Ping(_)))) => {}
+            other => panic!("expected a ping, got {:?}", other),
+        }
+    }
+
+    pub async fn ping(&mut self, payload: &'static [u8]) {
+        self.framed.send(ws::Message::Ping(payload.into())).await.unwrap();
+    }
+
+    // Reads past anything else until the pong for a ping arrives
+    pub async fn recv_pong(&mut self) -> Vec<u8> {
+        loop {
+            match actix_web::rt::time::timeout(FRAME_TIMEOUT, self.framed.next()).await {
+                Ok(Some(Ok(ws::Frame::Pong(payload)))) => return payload.to_vec(),
+                Ok(Some(Ok(_))) => continue,
+                other => panic!("expected a pong, got {:?}", other),
+            }
+        }
+    }
+
+    pub async fn assert_silent(&mut self) {
+        if let Some(frame) = self.next_frame(SILENCE).await {
+            panic!("expected no frame, got {:?}", frame);
+        }
+    }
+}
@@ -1,7 +1,7 @@
 use gloo_net::websocket::{futures::WebSocket, Message};
 use reqwasm::http;
 use serde::{Deserialize, Serialize};
-use surrealdb::sql::Uuid;
+use black_signal_protocol::Uuid;
 use wasm_bindgen::{JsCast, JsValue};
 use wasm_bindgen_futures::{spawn_local, JsFuture};
 use web_sys::{window, HtmlInputElement, Request, RequestInit, RequestMode, Response};
@@ -1,6 +1,5 @@
 use gloo_net::http;
 use serde::{Deserialize, Serialize};
-use surrealdb::sql::Uuid;
 use wasm_bindgen::{JsCast, JsValue};
 use wasm_bindgen_futures::{spawn_local, JsFuture};
 use web_sys::{window, HtmlInputElement, Request, RequestInit, RequestMode, Response};
@@ -0,0 +1,8 @@
+// Regenerates the published schema:
+// cargo run -p black_signal_protocol --example schema > protocol/schema/user_message.schema.json
+use black_signal_protocol::user_message_schema;
+
+fn main() {
+    let schema = user_message_schema();
+    println!("{}", serde_json::to_string_pretty(&schema).unwrap());
+}
@@ -0,0 +1,440 @@
+//! Message types shared by the BlackSignal backend and the wasm frontend.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::str::FromStr;
+use schemars::JsonSchema;
+use serde::{Serialize, Deserialize};
+
+pub use uuid::Uuid;
+
+// Version 1 is the original protocol, spoken by clients that send no handshake.
+pub const PROTOCOL_VERSION: u32 = 2;
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+// Capabilities this server is able to honour once negotiated.
+pub const SERVER_CAPABILITIES: &[Capability] = &[];
+
+// Capability Enum
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash, JsonSchema)]
+pub enum Capability {
+    Reactions,
+    Edits,
+    BinaryEncoding,
+}
+
+impl Capability {
+    // Lowest protocol version in which the capability may be negotiated
+    pub fn min_protocol_version(&self) -> u32 {
+        match self {
+            Capability::Reactions => 2,
+            Capability::Edits => 2,
+            Capability::BinaryEncoding => 2,
+        }
+    }
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Capability::Reactions => write!(f, "Reactions"),
+            Capability::Edits => write!(f, "Edits"),
+            Capability::BinaryEncoding => write!(f, "BinaryEncoding"),
+        }
+    }
+}
+
+impl FromStr for Capability {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "Reactions" => Ok(Capability::Reactions),
+            "Edits" => Ok(Capability::Edits),
+            "BinaryEncoding" => Ok(Capability::BinaryEncoding),
+            _ => Err(format!("Unknown capability: {}", s)),
+        }
+    }
+}
+
+// NegotiatedProtocol Struct
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct NegotiatedProtocol {
+    pub protocol_version: u32,
+    pub capabilities: Vec<Capability>,
+}
+
+impl NegotiatedProtocol {
+    // Settles on the highest version both sides speak and the capabilities both
+    // sides support. Capability names the server does not know are dropped so
+    // that newer clients can still connect.
+    pub fn negotiate(
+        requested_version: u32,
+        requested_capabilities: &[String],
+    ) -> Result<Self, ProtocolErrorMessage> {
+        if requested_version < MIN_PROTOCOL_VERSION {
+            return Err(ProtocolErrorMessage::new(
+                ProtocolErrorCode::UnsupportedVersion,
+                format!(
+                    "Protocol version {} is no longer supported, minimum is {}",
+                    requested_version, MIN_PROTOCOL_VERSION
+                ),
+            ));
+        }
+        let protocol_version = requested_version.min(PROTOCOL_VERSION);
+        let mut capabilities: Vec<Capability> = Vec::new();
+        for name in requested_capabilities {
+            if let Ok(capability) = name.parse::<Capability>() {
+                if SERVER_CAPABILITIES.contains(&capability)
+                    && capability.min_protocol_version() <= protocol_version
+                    && !capabilities.contains(&capability)
+                {
+                    capabilities.push(capability);
+                }
+            }
+        }
+        Ok(NegotiatedProtocol { protocol_version, capabilities })
+    }
+
+    pub fn legacy() -> Self {
+        NegotiatedProtocol { protocol_version: MIN_PROTOCOL_VERSION, capabilities: Vec::new() }
+    }
+}
+
+// UserInfo Struct
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct UserInfo {
+    pub user_id: Uuid,
+    pub ws_id: Uuid,
+    pub username: String,
+}
+
+impl UserInfo {
+    pub fn new(user_id: Uuid, ws_id: Uuid, username: String) -> Self {
+        UserInfo { user_id, ws_id, username }
+    }
+}
+
+// InitMessage Struct
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct InitMessage {
+    pub user_id: Uuid,
+    pub ws_id: Uuid,
+    pub username: String,
+    pub user_map: HashMap<Uuid, String>,
+    #[serde(default = "legacy_protocol_version")]
+    pub protocol_version: u32,
+    #[serde(default)]
+    pub capabilities: Vec<Capability>,
+    // Ids in user_map that belong to bot accounts
+    #[serde(default)]
+    pub bots: HashSet<Uuid>,
+}
+
+fn legacy_protocol_version() -> u32 {
+    MIN_PROTOCOL_VERSION
+}
+
+impl InitMessage {
+    pub fn new(
+        user_id: Uuid,
+        ws_id: Uuid,
+        username: String,
+        user_map: HashMap<Uuid, String>,
+        protocol: NegotiatedProtocol,
+    ) -> Self {
+        InitMessage {
+            user_id,
+            ws_id,
+            username,
+            user_map,
+            protocol_version: protocol.protocol_version,
+            capabilities: protocol.capabilities,
+            bots: HashSet::new(),
+        }
+    }
+
+    pub fn with_bots(mut self, bots: HashSet<Uuid>) -> Self {
+        self.bots = bots;
+        self
+    }
+}
+
+// Message Enum
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub enum UserMessage {
+    Basic(BasicMessage),
+    TSBasic(TSBasicMessage),
+    Image(ImageMessage),
+    Notification(NotificationMessage),
+    Typing(TypingMessage),
+    UserRemoval(UserRemovalMessage),
+    UserAddition(UserAdditionMessage),
+    NewUser(NewUserMessage),
+    ChangeRoom(ChangeRoomMessage),
+    UsernameChange(UsernameChangeMessage),
+    CreateRoomChange(CreateRoomChangeMessage),
+    Initialization(InitMessage),
+    Deletion(DeletionMessage),
+    ProtocolError(ProtocolErrorMessage),
+    Error(ErrorMessage),
+    ServerShutdown(ServerShutdownMessage),
+}
+
+impl UserMessage {
+    // Lowest protocol version a client must speak to understand the variant
+    pub fn min_protocol_version(&self) -> u32 {
+        match self {
+            UserMessage::ProtocolError(_) | UserMessage::Error(_) | UserMessage::ServerShutdown(_) => 2,
+            _ => MIN_PROTOCOL_VERSION,
+        }
+    }
+
+    // What clients too old for the variant are told instead, as the content
+    // of a message from the server
+    pub fn legacy_text(&self) -> Option<String> {
+        match self {
+            UserMessage::Error(error) => Some(format!("Error: {}", error.message)),
+            UserMessage::ProtocolError(error) => Some(format!("Protocol error: {}", error.message)),
+            UserMessage::ServerShutdown(shutdown) => Some(shutdown.message.clone()),
+            _ => None,
+        }
+    }
+
+    // Client chosen id echoed back in any error caused by the request
+    pub fn request_id(&self) -> Option<&str> {
+        let request_id = match self {
+            UserMessage::TSBasic(message) => &message.request_id,
+            UserMessage::Deletion(message) => &message.request_id,
+            UserMessage::UserRemoval(message) => &message.request_id,
+            UserMessage::ChangeRoom(message) => &message.request_id,
+            UserMessage::UsernameChange(message) => &message.request_id,
+            UserMessage::CreateRoomChange(message) => &message.request_id,
+            _ => return None,
+        };
+        request_id.as_deref()
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, JsonSchema)]
+pub enum ErrorCode {
+    InvalidMessage,
+    DatabaseError,
+    RateLimited,
+    PermissionDenied,
+    EmailNotVerified,
+    NotFound,
+    Internal,
+    // Storage is reconnecting, retry after a short wait
+    Unavailable,
+}
+
+// ErrorMessage Struct
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct ErrorMessage {
+    pub code: ErrorCode,
+    pub message: String,
+    #[serde(default)]
+    pub request_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_after_ms: Option<u64>,
+}
+
+impl ErrorMessage {
+    pub fn new(code: ErrorCode, message: String, request_id: Option<String>) -> Self {
+        ErrorMessage { code, message, request_id, retry_after_ms: None }
+    }
+
+    pub fn rate_limited(retry_after_ms: u64, request_id: Option<String>) -> Self {
+        ErrorMessage {
+            code: ErrorCode::RateLimited,
+            message: format!("Slow down, retry after {} ms", retry_after_ms),
+            request_id,
+            retry_after_ms: Some(retry_after_ms),
+        }
+    }
+}
+
+// ServerShutdownMessage Struct, sent just before the server closes the socket
+// because it is going away
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct ServerShutdownMessage {
+    pub message: String,
+    pub reconnect_after_ms: u64,
+}
+
+impl ServerShutdownMessage {
+    pub fn new(reconnect_after_ms: u64) -> Self {
+        ServerShutdownMessage {
+            message: format!("Server going away, reconnect in {} seconds", reconnect_after_ms.div_ceil(1000)),
+            reconnect_after_ms,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, JsonSchema)]
+pub enum ProtocolErrorCode {
+    UnsupportedVersion,
+    MalformedHandshake,
+}
+
+// ProtocolErrorMessage Struct
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct ProtocolErrorMessage {
+    pub code: ProtocolErrorCode,
+    pub message: String,
+    pub server_version: u32,
+    pub min_version: u32,
+}
+
+impl ProtocolErrorMessage {
+    pub fn new(code: ProtocolErrorCode, message: String) -> Self {
+        ProtocolErrorMessage {
+            code,
+            message,
+            server_version: PROTOCOL_VERSION,
+            min_version: MIN_PROTOCOL_VERSION,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct DeletionMessage {
+    pub sender_id: String,
+    pub message_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+// BasicMessage Struct
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct BasicMessage {
+    pub content: String,
+    pub sender_id: Uuid,
+    pub timestamp: u64,
+    pub message_id: Uuid,
+    pub room_id: Uuid,
+    pub ws_id: Uuid,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct TSBasicMessage {
+    pub content: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+// ImageMessage Struct
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct ImageMessage {
+    pub image_url: String,
+    pub sender_id: String,
+}
+
+// NotificationMessage Struct
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct NotificationMessage {
+    pub sender_id: String,
+}
+
+// TypingMessage Struct
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct TypingMessage {
+    pub sender_id: String,
+}
+
+// UserRemovalMessage Struct
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct UserRemovalMessage {
+    pub removed_user: String,
+    pub room_id: Uuid,
+    pub sender_id: Uuid,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+// UserAdditionMessage Struct
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct UserAdditionMessage {
+    pub user_id: Uuid,
+    pub username: String,
+}
+
+impl UserAdditionMessage {
+    pub fn new(user_id: Uuid, username: String) -> Self {
+        UserAdditionMessage { user_id, username }
+    }
+}
+
+// NewUserMessage Struct
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct NewUserMessage {
+    pub user_id: Uuid,
+    pub username: String,
+    #[serde(default)]
+    pub bot: bool,
+}
+
+impl NewUserMessage {
+    pub fn new(user_id: Uuid, username: String) -> Self {
+        NewUserMessage { user_id, username, bot: false }
+    }
+
+    pub fn bot(user_id: Uuid, username: String) -> Self {
+        NewUserMessage { user_id, username, bot: true }
+    }
+}
+
+// ChangeRoomMessage Struct
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct ChangeRoomMessage {
+    pub room_id: Uuid,
+    pub sender_id: Uuid,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+// UsernameChangeMessage Struct
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct UsernameChangeMessage {
+    pub new_username: String,
+    pub sender_id: Uuid,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+impl UsernameChangeMessage {
+    pub fn new(sender_id: Uuid, new_username: String) -> Self {
+        UsernameChangeMessage { sender_id, new_username, request_id: None }
+    }
+}
+
+// CreateRoomChangeMessage Struct
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct CreateRoomChangeMessage {
+    pub room_name: String,
+    pub sender_id: Uuid,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+impl CreateRoomChangeMessage {
+    pub fn new(sender_id: Uuid, room_name: String) -> Self {
+        CreateRoomChangeMessage { sender_id, room_name, request_id: None }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct LoginErrorMessage {
+    pub message: String,
+}
+
+impl LoginErrorMessage {
+    pub fn new(message: String) -> Self {
+        LoginErrorMessage { message }
+    }
+}
+// JSON Schema of every frame, published for third-party clients
+pub fn user_message_schema() -> schemars::schema::RootSchema {
+    schemars::schema_for!(UserMessage)
+}
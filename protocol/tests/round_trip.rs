@@ -0,0 +1,133 @@
+use black_signal_protocol::*;
+use std::collections::{HashMap, HashSet};
+
+fn round_trip(message: UserMessage) {
+    let serialized = serde_json::to_string(&message).unwrap();
+    let deserialized: UserMessage = serde_json::from_str(&serialized).unwrap();
+    assert_eq!(message, deserialized, "round trip changed {}", serialized);
+}
+
+#[test]
+fn every_variant_round_trips() {
+    let user_id = Uuid::new_v4();
+    let room_id = Uuid::new_v4();
+    let ws_id = Uuid::new_v4();
+    let mut user_map = HashMap::new();
+    user_map.insert(user_id, "test".to_string());
+
+    let messages = vec![
+        UserMessage::Basic(BasicMessage {
+            content: "hello".to_string(),
+            sender_id: user_id,
+            timestamp: 1_700_000_000,
+            message_id: Uuid::new_v4(),
+            room_id,
+            ws_id,
+        }),
+        UserMessage::TSBasic(TSBasicMessage {
+            content: "hello".to_string(),
+            request_id: Some("1".to_string()),
+        }),
+        UserMessage::Image(ImageMessage {
+            image_url: "https://example.com/a.png".to_string(),
+            sender_id: user_id.to_string(),
+        }),
+        UserMessage::Notification(NotificationMessage { sender_id: user_id.to_string() }),
+        UserMessage::Typing(TypingMessage { sender_id: user_id.to_string() }),
+        UserMessage::UserRemoval(UserRemovalMessage {
+            removed_user: user_id.to_string(),
+            room_id,
+            sender_id: user_id,
+            request_id: None,
+        }),
+        UserMessage::UserAddition(UserAdditionMessage::new(user_id, "test".to_string())),
+        UserMessage::NewUser(NewUserMessage::new(user_id, "test".to_string())),
+        UserMessage::NewUser(NewUserMessage::bot(user_id, "ci".to_string())),
+        UserMessage::ChangeRoom(ChangeRoomMessage { room_id, sender_id: user_id, request_id: None }),
+        UserMessage::UsernameChange(UsernameChangeMessage::new(user_id, "renamed".to_string())),
+        UserMessage::CreateRoomChange(CreateRoomChangeMessage::new(user_id, "room".to_string())),
+        UserMessage::Initialization(InitMessage::new(
+            user_id,
+            ws_id,
+            "test".to_string(),
+            user_map,
+            NegotiatedProtocol::negotiate(PROTOCOL_VERSION, &[]).unwrap(),
+        )
+        .with_bots(HashSet::from([user_id]))),
+        UserMessage::Deletion(DeletionMessage {
+            sender_id: user_id.to_string(),
+            message_id: Uuid::new_v4().to_string(),
+            request_id: Some("2".to_string()),
+        }),
+        UserMessage::ProtocolError(ProtocolErrorMessage::new(
+            ProtocolErrorCode::UnsupportedVersion,
+            "too old".to_string(),
+        )),
+        UserMessage::Error(ErrorMessage::new(
+            ErrorCode::PermissionDenied,
+            "not yours".to_string(),
+            Some("2".to_string()),
+        )),
+        UserMessage::Error(ErrorMessage::rate_limited(250, None)),
+        UserMessage::ServerShutdown(ServerShutdownMessage::new(5000)),
+    ];
+
+    for message in messages {
+        round_trip(message);
+    }
+}
+
+#[test]
+fn uuids_serialize_as_plain_strings() {
+    let room_id = Uuid::new_v4();
+    let message = UserMessage::ChangeRoom(ChangeRoomMessage { room_id, sender_id: room_id, request_id: None });
+    let value = serde_json::to_value(&message).unwrap();
+    assert_eq!(value["ChangeRoom"]["room_id"], room_id.to_string());
+}
+
+#[test]
+fn legacy_initialization_defaults_to_version_one() {
+    let legacy = format!(
+        r#"{{"Initialization":{{"user_id":"{id}","ws_id":"{id}","username":"test","user_map":{{}}}}}}"#,
+        id = Uuid::new_v4()
+    );
+    match serde_json::from_str::<UserMessage>(&legacy).unwrap() {
+        UserMessage::Initialization(init) => {
+            assert_eq!(init.protocol_version, MIN_PROTOCOL_VERSION);
+            assert!(init.capabilities.is_empty());
+            assert!(init.bots.is_empty());
+        }
+        _ => panic!("expected an Initialization frame"),
+    }
+}
+
+#[test]
+fn negotiation_downgrades_newer_clients_and_rejects_older_ones() {
+    let negotiated = NegotiatedProtocol::negotiate(PROTOCOL_VERSION + 5, &["Unknown".to_string()]).unwrap();
+    assert_eq!(negotiated.protocol_version, PROTOCOL_VERSION);
+    assert!(negotiated.capabilities.is_empty());
+
+    let rejected = NegotiatedProtocol::negotiate(MIN_PROTOCOL_VERSION - 1, &[]).unwrap_err();
+    assert_eq!(rejected.code, ProtocolErrorCode::UnsupportedVersion);
+}
+
+#[test]
+fn request_ids_are_optional_and_echoed() {
+    let without: UserMessage = serde_json::from_str(r#"{"TSBasic":{"content":"hi"}}"#).unwrap();
+    assert_eq!(without.request_id(), None);
+
+    let with: UserMessage =
+        serde_json::from_str(r#"{"TSBasic":{"content":"hi","request_id":"abc"}}"#).unwrap();
+    assert_eq!(with.request_id(), Some("abc"));
+}
+
+#[test]
+fn published_schema_is_up_to_date() {
+    let generated = serde_json::to_string_pretty(&user_message_schema()).unwrap();
+    let published = include_str!("../schema/user_message.schema.json");
+    assert_eq!(
+        generated.trim(),
+        published.trim(),
+        "regenerate with: cargo run -p black_signal_protocol --example schema > protocol/schema/user_message.schema.json"
+    );
+}